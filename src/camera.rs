@@ -14,6 +14,9 @@ const ZOOM_DEFAULT: f32 = 30.0;
 const PITCH_MIN: f32 = -0.49 * PI;
 const PITCH_MAX: f32 = 0.49 * PI;
 
+/// Distance to the near clipping plane
+pub const NEAR_PLANE: f32 = 0.5;
+
 const TRUE_UP: Vec3 = const_vec3!([0.0, 1.0, 0.0]); // Y UP
 
 pub enum Movement {
@@ -23,7 +26,7 @@ pub enum Movement {
     Right,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Camera {
     pub position: Vec3,
     pub direction: Vec3,
@@ -143,6 +146,22 @@ impl Camera {
         self.up = self.right.cross(self.direction).normalize();
     }
 
+    /// Moves the camera keeping its speed and zoom
+    pub fn look_from(&mut self, position: Vec3, direction: Vec3) {
+        let moved = Camera::new(
+            position,
+            position + direction,
+            self.screen_dimensions.x as u32,
+            self.screen_dimensions.y as u32,
+        );
+        self.position = moved.position;
+        self.direction = moved.direction;
+        self.up = moved.up;
+        self.right = moved.right;
+        self.yaw = moved.yaw;
+        self.pitch = moved.pitch;
+    }
+
     pub fn calculate_vert_fov(zoom: f32) -> f32 {
         let t = (zoom - ZOOM_MIN) / (ZOOM_MAX - ZOOM_MIN);
         (1.0 - t) * FOV_MAX + t * FOV_MIN
@@ -164,6 +183,23 @@ impl Camera {
         Ray::new(self.position, direction)
     }
 
+    /// World space corners of the part of the view frustum between two view distances
+    pub fn frustum_corners(&self, near: f32, far: f32) -> [Vec3; 8] {
+        let half_height = (self.v_fov / 2.0).tan();
+        let half_width = half_height * self.aspect_ratio;
+        let mut corners = [Vec3::ZERO; 8];
+        for (i, &distance) in [near, far].iter().enumerate() {
+            let center = self.position + self.direction * distance;
+            let up = self.up * half_height * distance;
+            let right = self.right * half_width * distance;
+            corners[i * 4] = center - right - up;
+            corners[i * 4 + 1] = center + right - up;
+            corners[i * 4 + 2] = center - right + up;
+            corners[i * 4 + 3] = center + right + up;
+        }
+        corners
+    }
+
     pub fn get_view_matrix(&self) -> Mat4 {
         // Camera never turns upside down so true up is fixed
         Mat4::look_at_rh(self.position, self.position + self.direction, TRUE_UP)
@@ -180,6 +216,6 @@ impl Camera {
     pub fn get_projection_matrix(&self) -> Mat4 {
         // Mat4::perspective_rh(self.v_fov, self.aspect_ratio, 0.5, 2000.0)
         // @explore: try setting different clip planes every frame based on z-buffer (glReadPixels)?
-        Mat4::perspective_infinite_rh(self.v_fov, self.aspect_ratio, 0.5)
+        Mat4::perspective_infinite_rh(self.v_fov, self.aspect_ratio, NEAR_PLANE)
     }
 }
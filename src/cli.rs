@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use glam::Vec3;
+use image::RgbImage;
+use thiserror::Error;
+
+use crate::camera::Camera;
+use crate::config::{Config, GraphicsSettings};
+use crate::heightfield::Heightfield;
+use crate::opengl::headless::HeadlessContext;
+use crate::project::{self, Manifest};
+use crate::renderer::{Renderer, Scene};
+use crate::{Game, GameObject, Result};
+
+const TERRAIN_USAGE: &str = "\
+Usage: game2 terrain <command> [options]
+
+Heightmaps are 16 bit images, or raw little endian floats if the extension is .r32
+
+Commands:
+    help
+    generate <out> [--size 1024] [--seed 0] [--scale 8] [--octaves 6]
+    erode <in> <out> [--iterations 50] [--talus 35]    thermal erosion, not in the editor
+    resample <in> <out> --size <texels>
+    convert <in> <out>
+    export-mesh <in> <out.obj> [--step 4]
+    bake-normals <in> <out.png>
+
+Options for commands that need the terrain dimensions:
+    --terrain-size <metres>    defaults to the editor settings
+    --max-height <metres>      defaults to the editor settings";
+
+const SCREENSHOT_USAGE: &str = "\
+Usage: game2 screenshot <project> <out.png> [options]
+
+Renders a project without a window, e.g. with Mesa's software rasteriser
+
+Options:
+    --width 1920
+    --height 1080
+    --scale 1         multiplies the width and the height
+    --bookmark 0      camera bookmark to render from, if the project has any";
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("{0}. Run 'game2 terrain help' or 'game2 screenshot help' for usage")]
+    Usage(String),
+    #[error("Invalid value '{value}' for --{name}")]
+    InvalidOption { name: String, value: String },
+}
+
+/// Positional arguments and `--name value` options
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut positional = vec![];
+        let mut options = HashMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                let value = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage(format!("Missing value for --{}", name)))?;
+                options.insert(name.to_owned(), value.clone());
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+        Ok(Args {
+            positional,
+            options,
+        })
+    }
+
+    /// Exactly `count` positional arguments
+    fn paths(&self, count: usize) -> Result<Vec<&Path>> {
+        if self.positional.len() != count {
+            return Err(CliError::Usage(format!(
+                "Expected {} file arguments, got {}",
+                count,
+                self.positional.len()
+            ))
+            .into());
+        }
+        Ok(self.positional.iter().map(Path::new).collect())
+    }
+
+    fn option<T: FromStr>(&self, name: &str, default: Option<T>) -> Result<T> {
+        match self.options.get(name) {
+            Some(value) => value.parse().map_err(|_| {
+                CliError::InvalidOption {
+                    name: name.to_owned(),
+                    value: value.clone(),
+                }
+                .into()
+            }),
+            None => default.ok_or_else(|| CliError::Usage(format!("Missing --{}", name)).into()),
+        }
+    }
+
+    /// Texels per side, which can't be 0
+    fn size(&self, default: Option<usize>) -> Result<usize> {
+        let size = self.option("size", default)?;
+        if size == 0 {
+            return Err(CliError::InvalidOption {
+                name: "size".to_owned(),
+                value: size.to_string(),
+            }
+            .into());
+        }
+        Ok(size)
+    }
+
+    /// Pixel counts and scale factors, which must be at least 1
+    fn positive(&self, name: &str, default: Option<i32>) -> Result<i32> {
+        let value = self.option(name, default)?;
+        if value < 1 {
+            return Err(CliError::InvalidOption {
+                name: name.to_owned(),
+                value: value.to_string(),
+            }
+            .into());
+        }
+        Ok(value)
+    }
+
+    /// World size and max height of the terrain in metres
+    fn dimensions(&self) -> Result<(f32, f32)> {
+        let settings = Config::load_or_default()?.terrain;
+        let terrain_size = settings.patch_size * settings.num_patches as f32;
+        Ok((
+            self.option("terrain-size", Some(terrain_size))?,
+            self.option("max-height", Some(settings.max_height))?,
+        ))
+    }
+}
+
+/// Runs `game2 terrain ...` without opening a window
+pub fn run_terrain_command(args: &[String]) -> Result<()> {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), Args::parse(args)?),
+        None => return Err(CliError::Usage("Missing command".to_owned()).into()),
+    };
+
+    match command {
+        "help" => println!("{}", TERRAIN_USAGE),
+        "generate" => {
+            let paths = args.paths(1)?;
+            let heightfield = Heightfield::generate(
+                args.size(Some(1024))?,
+                args.option("seed", Some(0))?,
+                args.option("scale", Some(8.0))?,
+                args.option("octaves", Some(6))?,
+            );
+            heightfield.save(paths[0])?;
+        }
+        "erode" => {
+            let paths = args.paths(2)?;
+            let (terrain_size, max_height) = args.dimensions()?;
+            let mut heightfield = Heightfield::load(paths[0])?;
+            heightfield.erode(
+                args.option("iterations", Some(50))?,
+                args.option("talus", Some(35.0))?,
+                terrain_size,
+                max_height,
+            );
+            heightfield.save(paths[1])?;
+        }
+        "resample" => {
+            let paths = args.paths(2)?;
+            let heightfield = Heightfield::load(paths[0])?;
+            heightfield.resample(args.size(None)?).save(paths[1])?;
+        }
+        "convert" => {
+            let paths = args.paths(2)?;
+            Heightfield::load(paths[0])?.save(paths[1])?;
+        }
+        "export-mesh" => {
+            let paths = args.paths(2)?;
+            let (terrain_size, max_height) = args.dimensions()?;
+            let heightfield = Heightfield::load(paths[0])?;
+            heightfield.save_obj(
+                paths[1],
+                terrain_size,
+                max_height,
+                args.option("step", Some(4))?,
+            )?;
+        }
+        "bake-normals" => {
+            let paths = args.paths(2)?;
+            let (terrain_size, max_height) = args.dimensions()?;
+            let heightfield = Heightfield::load(paths[0])?;
+            let size = heightfield.size as u32;
+            image::save_buffer(
+                paths[1],
+                &heightfield.normal_map(terrain_size, max_height),
+                size,
+                size,
+                image::ColorType::Rgb8,
+            )?;
+        }
+        _ => {
+            return Err(CliError::Usage(format!("Unknown command '{}'", command)).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `game2 screenshot ...` in a headless GL context
+pub fn run_screenshot_command(args: &[String]) -> Result<()> {
+    if args.first().map(String::as_str) == Some("help") {
+        println!("{}", SCREENSHOT_USAGE);
+        return Ok(());
+    }
+    let args = Args::parse(args)?;
+    let paths = args.paths(2)?;
+    let scale = args.positive("scale", Some(1))?;
+    // Too large for any GPU, which rendering reports
+    let width = args.positive("width", Some(1920))?.saturating_mul(scale);
+    let height = args.positive("height", Some(1080))?.saturating_mul(scale);
+    let bookmark: usize = args.option("bookmark", Some(0))?;
+
+    let manifest = Manifest::load(paths[0])?;
+    // Same as the editor without a saved camera
+    let (position, direction) = match manifest.camera_bookmarks.get(bookmark) {
+        Some(bookmark) => (bookmark.position, bookmark.direction),
+        None => {
+            let position = Vec3::new(520.0, 250.0, 100.0);
+            (position, -position)
+        }
+    };
+    let camera = Camera::new(position, position + direction, width as u32, height as u32);
+
+    let image = render_headless(
+        &manifest,
+        &project::heightmap_path(paths[0]),
+        false,
+        &camera,
+        width,
+        height,
+    )?;
+    image.save(paths[1])?;
+
+    Ok(())
+}
+
+/// Loads the level and draws one frame of it in a headless GL context
+pub fn render_headless(
+    manifest: &Manifest,
+    heightmap_path: &str,
+    start_flat: bool,
+    camera: &Camera,
+    width: i32,
+    height: i32,
+) -> Result<RgbImage> {
+    // Created first so that it goes away after everything that uses it
+    let _context = HeadlessContext::new()?;
+
+    let mut renderer = Renderer::offscreen(
+        width,
+        height,
+        manifest.ambient_occlusion,
+        manifest.post_process,
+        &GraphicsSettings::default(),
+    )?;
+    let (mut terrain, mut skybox, game_objects) =
+        Game::load_level(&mut renderer.gl, manifest, heightmap_path, start_flat)?;
+
+    renderer.draw_scene(Scene {
+        camera,
+        terrain: &mut terrain,
+        skybox: &mut skybox,
+        sky: &manifest.sky,
+        light: &manifest.light,
+        fog: &manifest.fog,
+        objects: game_objects.iter().map(GameObject::instance).collect(),
+    })?;
+    Ok(renderer.read_output())
+}
@@ -1,8 +1,11 @@
 use std::fs;
+use std::path::Path;
 
-use glam::Vec3;
+use glam::{Vec2, Vec3};
 use serde::{Deserialize, Serialize};
 
+use crate::layers::LayerInfo;
+use crate::terrain::TerrainSettings;
 use crate::Result;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -11,6 +14,61 @@ pub struct Config {
     pub start_with_flat_terrain: bool,
     pub camera_position: Option<Vec3>,
     pub camera_direction: Option<Vec3>,
+    #[serde(default)]
+    pub terrain: TerrainSettings,
+    #[serde(default)]
+    pub layers: Vec<LayerInfo>,
+    #[serde(default)]
+    pub recent_projects: Vec<String>,
+    #[serde(default)]
+    pub controls: ControlSettings,
+    #[serde(default)]
+    pub graphics: GraphicsSettings,
+}
+
+const MAX_RECENT_PROJECTS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ControlSettings {
+    /// Multiplies the camera sensitivity
+    pub mouse_sensitivity: f32,
+    pub invert_mouse_y: bool,
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        ControlSettings {
+            mouse_sensitivity: 1.0,
+            invert_mouse_y: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    /// Samples per pixel of the scene, 1 turns MSAA off
+    pub msaa_samples: i32,
+    /// Smooths the edges MSAA misses after tone mapping
+    pub fxaa: bool,
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        GraphicsSettings {
+            msaa_samples: 4,
+            fxaa: false,
+        }
+    }
+}
+
+impl ControlSettings {
+    /// Turns mouse movement into camera rotation deltas
+    pub fn look_delta(&self, pointer_delta: Vec2) -> Vec2 {
+        let invert = if self.invert_mouse_y { -1.0 } else { 1.0 };
+        Vec2::new(pointer_delta.x, pointer_delta.y * invert) * self.mouse_sensitivity
+    }
 }
 
 impl Config {
@@ -23,11 +81,24 @@ impl Config {
                 start_with_flat_terrain: true,
                 camera_position: None,
                 camera_direction: None,
+                terrain: TerrainSettings::default(),
+                layers: vec![],
+                recent_projects: vec![],
+                controls: ControlSettings::default(),
+                graphics: GraphicsSettings::default(),
             }
         };
         Ok(config)
     }
 
+    /// Moves the project to the top of the recent list
+    pub fn add_recent_project(&mut self, dir: &Path) {
+        let dir = dir.to_string_lossy().into_owned();
+        self.recent_projects.retain(|recent| *recent != dir);
+        self.recent_projects.insert(0, dir);
+        self.recent_projects.truncate(MAX_RECENT_PROJECTS);
+    }
+
     pub fn save(&self) {
         let string = serde_json::to_string(self).unwrap();
 
@@ -1,26 +1,79 @@
+use std::collections::HashMap;
 use std::mem::size_of;
+use std::ops::RangeInclusive;
 
-use egui::{Align2, ClippedMesh, CtxRef, LayerId, Output};
+use egui::{Align2, ClippedMesh, CtxRef, LayerId, Output, Pos2, Rect, Sense, Slider, Ui};
 use egui_gizmo::{Gizmo, GizmoMode, GizmoOrientation, GizmoVisuals};
 use egui_winit::State;
 use epaint::Color32;
 use gl::types::*;
-use glam::{Mat4, Vec2};
+use glam::{Mat4, Vec2, Vec3};
 use glutin::window::Window;
 use memoffset::offset_of;
 
+use crate::fog::Fog;
+use crate::layers::{BlendMode, LayerKind};
+use crate::light::DirectionalLight;
+use crate::postprocess::{PostProcessSettings, ToneMapper};
+use crate::project::CameraBookmark;
+use crate::renderer::{GlState, RenderState};
+use crate::shadows::SHADOW_MAP_SIZES;
+use crate::skybox::{list_skyboxes, SkyMode, SkySettings};
+use crate::splatmap::{MaterialRule, MAX_MATERIAL_RULES};
+use crate::ssao::{AmbientOcclusion, Ssao};
+use crate::terrain::{Histogram, OverlayMode, SymmetryMode, Terrain, TerrainSettings};
 use crate::{opengl::shader::Program, texture::unit_to_gl_const, utils::size_of_slice, Result};
 
+/// Windows which show how their actions went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GuiWindow {
+    Project,
+    Layers,
+    Lighting,
+    Tools,
+}
+
+/// Shown at the bottom of a window until dismissed or replaced
+struct Status {
+    text: String,
+    failed: bool,
+}
+
 /// An action to take as a result of interacting with the GUI
 pub enum Action {
     SaveTerrain,
     SaveCamera,
+    ComputeHistogram,
+    UpdateMaterials,
+    ApplyTerrainSettings,
+    AddStampLayer(String),
+    OpenProject(String),
+    SaveProject,
+    SaveProjectAs(String),
+    AddCameraBookmark(String),
+    GoToCameraBookmark(usize),
+    RemoveCameraBookmark(usize),
+    SetSkybox(String),
+    TakeScreenshot,
+    /// Without the UI, at this many times the window size
+    TakeHighResScreenshot(i32),
     Quit,
 }
 
 pub struct Gui {
     screen_size: Vec2,
 
+    // Widget state
+    stamp_path: String,
+    project_path: String,
+    bookmark_name: String,
+    screenshot_scale: i32,
+    /// Terrain settings edited but not applied yet
+    terrain_settings_changed: bool,
+    /// Found when the GUI is created
+    skyboxes: Vec<String>,
+    statuses: HashMap<GuiWindow, Status>,
+
     ctx: CtxRef,
     egui_texture: GLuint,
     egui_texture_version: Option<u64>,
@@ -119,6 +172,14 @@ impl Gui {
         Ok(Gui {
             screen_size,
 
+            stamp_path: "textures/heightmaps/valley.png".to_owned(),
+            project_path: "projects/untitled".to_owned(),
+            bookmark_name: "Bookmark".to_owned(),
+            screenshot_scale: 2,
+            terrain_settings_changed: false,
+            skyboxes: list_skyboxes(),
+            statuses: HashMap::new(),
+
             ctx: CtxRef::default(),
             egui_texture: 0, // will be created before draw
             egui_texture_version: None,
@@ -138,24 +199,131 @@ impl Gui {
         &self.ctx
     }
 
+    pub fn set_project_path(&mut self, path: &str) {
+        self.project_path = path.to_owned();
+    }
+
+    /// Shows an error in the window, or clears the last one once the action succeeds
+    pub fn show_result(&mut self, window: GuiWindow, result: Result<()>) {
+        match result {
+            Ok(()) => {
+                self.statuses.remove(&window);
+            }
+            Err(error) => {
+                let status = Status {
+                    text: error.to_string(),
+                    failed: true,
+                };
+                self.statuses.insert(window, status);
+            }
+        }
+    }
+
+    /// Shows a note in the window until it's dismissed or replaced
+    pub fn show_message(&mut self, window: GuiWindow, text: String) {
+        let status = Status {
+            text,
+            failed: false,
+        };
+        self.statuses.insert(window, status);
+    }
+
     pub fn wants_input(&self) -> bool {
         self.ctx.wants_pointer_input() || self.ctx.wants_keyboard_input()
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn layout_and_interact(
         &mut self,
         state: &mut State,
         window: &Window,
         view_matrix: &Mat4,
         projection_matrix: &Mat4,
-        model_matrix: &mut Mat4,
+        objects: &[&str],
+        selected_object: &mut Option<usize>,
+        model_matrix: Option<&mut Mat4>,
+        terrain: &mut Terrain,
+        histogram: Option<&Histogram>,
+        terrain_settings: &mut TerrainSettings,
+        light: &mut DirectionalLight,
+        sky: &mut SkySettings,
+        skybox_dir: &str,
+        fog: &mut Fog,
+        ssao: &mut Ssao,
+        post_process: &mut PostProcessSettings,
+        has_project: bool,
+        camera_bookmarks: &[CameraBookmark],
     ) -> Vec<Action> {
         let input = state.take_egui_input(window);
         self.ctx.begin_frame(input);
         let mut actions = vec![];
 
+        let overlay = &mut terrain.overlay;
+        let material_rules = &mut terrain.material_rules;
+        let symmetry = &mut terrain.symmetry;
+        let layers = &mut terrain.layers;
+        let sculpt_mask = &mut terrain.sculpt_mask;
+        let shadows = &mut terrain.shadows;
+        let stamp_path = &mut self.stamp_path;
+        let project_path = &mut self.project_path;
+        let bookmark_name = &mut self.bookmark_name;
+        let screenshot_scale = &mut self.screenshot_scale;
+        let settings_changed = &mut self.terrain_settings_changed;
+        let skyboxes = &self.skyboxes;
+        let statuses = &mut self.statuses;
+
         // ================== GUI starts ========================
 
+        egui::Window::new("Project")
+            .anchor(Align2::RIGHT_BOTTOM, egui::Vec2::new(-10.0, -10.0))
+            .resizable(false)
+            .show(&self.ctx, |ui| {
+                ui.add(egui::TextEdit::singleline(project_path).desired_width(200.0));
+                ui.horizontal(|ui| {
+                    if ui.button("Open").clicked() {
+                        actions.push(Action::OpenProject(project_path.clone()));
+                    }
+                    if ui
+                        .add_enabled(has_project, egui::Button::new("Save"))
+                        .clicked()
+                    {
+                        actions.push(Action::SaveProject);
+                    }
+                    if ui.button("Save as").clicked() {
+                        actions.push(Action::SaveProjectAs(project_path.clone()));
+                    }
+                });
+
+                ui.separator();
+                ui.label("Camera bookmarks");
+                for (i, bookmark) in camera_bookmarks.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        if ui.button(&bookmark.name).clicked() {
+                            actions.push(Action::GoToCameraBookmark(i));
+                        }
+                        if ui.small_button("Delete").clicked() {
+                            actions.push(Action::RemoveCameraBookmark(i));
+                        }
+                    });
+                }
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(bookmark_name).desired_width(120.0));
+                    if ui.button("Add").clicked() {
+                        actions.push(Action::AddCameraBookmark(bookmark_name.clone()));
+                    }
+                });
+
+                ui.separator();
+                ui.label("Objects");
+                for (i, &object) in objects.iter().enumerate() {
+                    let selected = *selected_object == Some(i);
+                    if ui.selectable_label(selected, object).clicked() {
+                        *selected_object = if selected { None } else { Some(i) };
+                    }
+                }
+                show_status(ui, statuses, GuiWindow::Project);
+            });
+
         egui::Window::new("Tools")
             .anchor(Align2::RIGHT_TOP, egui::Vec2::new(-10.0, 10.0))
             .resizable(false)
@@ -167,32 +335,476 @@ impl Gui {
                 if ui.button("Save camera position").clicked() {
                     actions.push(Action::SaveCamera);
                 }
+
+                ui.separator();
+                if ui.button("Screenshot (F12)").clicked() {
+                    actions.push(Action::TakeScreenshot);
+                }
+                ui.horizontal(|ui| {
+                    ui.add(Slider::new(screenshot_scale, 1..=4).text("×"));
+                    if ui.button("Without UI").clicked() {
+                        actions.push(Action::TakeHighResScreenshot(*screenshot_scale));
+                    }
+                });
+
+                ui.separator();
+                egui::ComboBox::from_label("Symmetry")
+                    .selected_text(symmetry.mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in SymmetryMode::ALL {
+                            ui.selectable_value(&mut symmetry.mode, mode, mode.name());
+                        }
+                    });
+                if symmetry.mode == SymmetryMode::Radial {
+                    ui.add(Slider::new(&mut symmetry.radial_folds, 2..=16).text("Folds"));
+                }
+
+                ui.separator();
+                ui.checkbox(&mut sculpt_mask.painting, "Paint sculpt mask");
+                ui.checkbox(&mut sculpt_mask.show, "Show protected areas");
+                ui.horizontal(|ui| {
+                    if ui.button("Invert").clicked() {
+                        sculpt_mask.invert();
+                    }
+                    if ui.button("Clear").clicked() {
+                        sculpt_mask.clear();
+                    }
+                    if ui.button("Fill").clicked() {
+                        sculpt_mask.fill();
+                    }
+                });
+                show_status(ui, statuses, GuiWindow::Tools);
+            });
+
+        egui::Window::new("Analysis")
+            .anchor(Align2::LEFT_TOP, egui::Vec2::new(10.0, 10.0))
+            .resizable(false)
+            .show(&self.ctx, |ui| {
+                egui::ComboBox::from_label("Overlay")
+                    .selected_text(overlay.mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in OverlayMode::ALL {
+                            ui.selectable_value(&mut overlay.mode, mode, mode.name());
+                        }
+                    });
+
+                match overlay.mode {
+                    OverlayMode::None => {}
+                    OverlayMode::Slope => {
+                        ui.add(
+                            Slider::new(&mut overlay.slope_gentle, 0.0..=90.0).text("Gentle, °"),
+                        );
+                        ui.add(Slider::new(&mut overlay.slope_steep, 0.0..=90.0).text("Steep, °"));
+                        overlay.slope_steep = overlay.slope_steep.max(overlay.slope_gentle);
+                    }
+                    OverlayMode::Curvature => {
+                        ui.add(
+                            Slider::new(&mut overlay.curvature_scale, 1.0..=200.0)
+                                .logarithmic(true)
+                                .text("Scale"),
+                        );
+                    }
+                    OverlayMode::HeightBands => {
+                        ui.add(Slider::new(&mut overlay.height_band, 1.0..=100.0).text("Band, m"));
+                    }
+                    OverlayMode::Contours => {
+                        ui.add(
+                            Slider::new(&mut overlay.contour_interval, 1.0..=100.0)
+                                .text("Interval, m"),
+                        );
+                    }
+                }
+
+                ui.separator();
+                if ui.button("Compute histogram").clicked() {
+                    actions.push(Action::ComputeHistogram);
+                }
+                if let Some(histogram) = histogram {
+                    ui.label(format!("Heights, 0-{} m", histogram.max_height));
+                    draw_histogram(ui, &histogram.heights);
+                    ui.label("Slopes, 0-90°");
+                    draw_histogram(ui, &histogram.slopes);
+                }
             });
 
-        egui::Area::new("Viewport")
-            .fixed_pos((0.0, 0.0))
+        egui::Window::new("Materials")
+            .default_pos((10.0, 320.0))
+            .resizable(false)
             .show(&self.ctx, |ui| {
-                ui.with_layer_id(LayerId::background(), |ui| {
-                    let visuals = GizmoVisuals {
-                        gizmo_size: 100.0,
-                        ..Default::default()
-                    };
-                    let gizmo = Gizmo::new("gizmo")
-                        .view_matrix(view_matrix.to_cols_array_2d())
-                        .projection_matrix(projection_matrix.to_cols_array_2d())
-                        .model_matrix(model_matrix.to_cols_array_2d())
-                        .mode(GizmoMode::Translate)
-                        .orientation(GizmoOrientation::Global)
-                        .visuals(visuals);
-
-                    if let Some(gizmo_result) = gizmo.interact(ui) {
-                        *model_matrix = Mat4::from_cols_array_2d(&gizmo_result.transform);
+                let mut changed = false;
+                let mut removed = None;
+
+                for (i, rule) in material_rules.iter_mut().enumerate() {
+                    egui::CollapsingHeader::new(rule.name.clone())
+                        .id_source(i)
+                        .show(ui, |ui| {
+                            ui.horizontal(|ui| {
+                                ui.text_edit_singleline(&mut rule.name);
+                                let mut color = rule.color.to_array();
+                                if ui.color_edit_button_rgb(&mut color).changed() {
+                                    rule.color = Vec3::from(color);
+                                    changed = true;
+                                }
+                            });
+                            if i == 0 {
+                                ui.label("Base material, covers everything else");
+                                return;
+                            }
+
+                            let mut slider =
+                                |value: &mut f32, range: RangeInclusive<f32>, text: &str| {
+                                    changed |=
+                                        ui.add(Slider::new(value, range).text(text)).changed();
+                                };
+                            slider(&mut rule.min_height, 0.0..=1000.0, "Min height, m");
+                            slider(&mut rule.max_height, 0.0..=1000.0, "Max height, m");
+                            slider(&mut rule.min_slope, 0.0..=90.0, "Min slope, °");
+                            slider(&mut rule.max_slope, 0.0..=90.0, "Max slope, °");
+                            slider(&mut rule.blend, 0.0..=0.5, "Blend");
+                            slider(&mut rule.noise, 0.0..=1.0, "Noise");
+
+                            if ui.button("Remove").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                }
+
+                if let Some(i) = removed {
+                    material_rules.remove(i);
+                    changed = true;
+                }
+
+                ui.horizontal(|ui| {
+                    if material_rules.len() < MAX_MATERIAL_RULES && ui.button("Add rule").clicked()
+                    {
+                        material_rules.push(MaterialRule::new("New material", Vec3::splat(0.5)));
+                        changed = true;
+                    }
+                    if ui.button("Refresh").clicked() {
+                        changed = true;
                     }
                 });
+
+                if changed {
+                    actions.push(Action::UpdateMaterials);
+                }
             });
 
+        egui::Window::new("Terrain settings")
+            .default_pos((10.0, 600.0))
+            .resizable(false)
+            .show(&self.ctx, |ui| {
+                let settings = terrain_settings;
+                let mut changed = false;
+                let mut dragging = false;
+
+                let mut slider = |ui: &mut Ui, slider: Slider| {
+                    let response = ui.add(slider);
+                    *settings_changed |= response.changed();
+                    dragging |= response.dragged();
+                };
+                slider(
+                    ui,
+                    Slider::new(&mut settings.max_height, 10.0..=1000.0).text("Max height, m"),
+                );
+                slider(
+                    ui,
+                    Slider::new(&mut settings.num_patches, 8..=256).text("Patches per side"),
+                );
+                slider(
+                    ui,
+                    Slider::new(&mut settings.patch_size, 1.0..=64.0).text("Patch size, m"),
+                );
+                slider(
+                    ui,
+                    Slider::new(&mut settings.tess_level, 1.0..=64.0).text("Tessellation"),
+                );
+
+                egui::ComboBox::from_label("Shadow map")
+                    .selected_text(format!("{0}x{0}", settings.shadow_map_size))
+                    .show_ui(ui, |ui| {
+                        for size in SHADOW_MAP_SIZES {
+                            changed |= ui
+                                .selectable_value(
+                                    &mut settings.shadow_map_size,
+                                    size,
+                                    format!("{0}x{0}", size),
+                                )
+                                .changed();
+                        }
+                    });
+
+                if ui.button("Reset to defaults").clicked() {
+                    *settings = TerrainSettings::default();
+                    changed = true;
+                }
+
+                // Rebuilding is too slow to follow a slider while it's dragged
+                *settings_changed |= changed;
+                if *settings_changed && !dragging {
+                    *settings_changed = false;
+                    actions.push(Action::ApplyTerrainSettings);
+                }
+            });
+
+        egui::Window::new("Lighting")
+            .default_pos((300.0, 600.0))
+            .resizable(false)
+            .show(&self.ctx, |ui| {
+                ui.label("Sun");
+                let sun = &mut light.sun;
+                let mut sun_moved = false;
+                sun_moved |= ui
+                    .add(Slider::new(&mut sun.time_of_day, 0.0..=24.0).text("Time of day, h"))
+                    .changed();
+                sun_moved |= ui
+                    .add(
+                        Slider::new(&mut sun.sunrise_azimuth, 0.0..=360.0)
+                            .text("Sunrise azimuth, °"),
+                    )
+                    .changed();
+                sun_moved |= ui
+                    .add(Slider::new(&mut sun.noon_elevation, 5.0..=90.0).text("Noon elevation, °"))
+                    .changed();
+                if sun_moved {
+                    light.follow_sun();
+                }
+                let mut color = light.color.to_array();
+                ui.horizontal(|ui| {
+                    if ui.color_edit_button_rgb(&mut color).changed() {
+                        light.color = Vec3::from(color);
+                    }
+                    ui.label("Colour");
+                });
+                ui.add(Slider::new(&mut light.intensity, 0.0..=4.0).text("Intensity"));
+                ui.add(Slider::new(&mut light.ambient, 0.0..=1.0).text("Ambient"));
+
+                ui.separator();
+                egui::ComboBox::from_label("Sky")
+                    .selected_text(sky.mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in SkyMode::ALL {
+                            ui.selectable_value(&mut sky.mode, mode, mode.name());
+                        }
+                    });
+                if sky.mode == SkyMode::Cubemap {
+                    egui::ComboBox::from_label("Skybox")
+                        .selected_text(skybox_dir)
+                        .show_ui(ui, |ui| {
+                            for skybox in skyboxes {
+                                if ui.selectable_label(skybox == skybox_dir, skybox).clicked()
+                                    && skybox != skybox_dir
+                                {
+                                    actions.push(Action::SetSkybox(skybox.clone()));
+                                }
+                            }
+                        });
+                }
+                if sky.mode == SkyMode::Atmosphere {
+                    ui.add(
+                        Slider::new(&mut sky.haze, 0.1..=10.0)
+                            .logarithmic(true)
+                            .text("Haze"),
+                    );
+                }
+                ui.checkbox(&mut sky.bake_ambient, "Ambient light from the sky");
+
+                ui.separator();
+                ui.checkbox(&mut fog.enabled, "Fog");
+                ui.add_enabled_ui(fog.enabled, |ui| {
+                    let mut color = fog.color.to_array();
+                    ui.horizontal(|ui| {
+                        if ui.color_edit_button_rgb(&mut color).changed() {
+                            fog.color = Vec3::from(color);
+                        }
+                        ui.label("Colour");
+                    });
+                    ui.add(
+                        Slider::new(&mut fog.density, 0.0..=0.05)
+                            .logarithmic(true)
+                            .text("Density"),
+                    );
+                    ui.add(Slider::new(&mut fog.start, 0.0..=1000.0).text("Start, m"));
+                    ui.add(
+                        Slider::new(&mut fog.height_falloff, 0.0..=0.5)
+                            .logarithmic(true)
+                            .text("Height falloff"),
+                    );
+                    ui.add(
+                        Slider::new(&mut fog.base_height, -100.0..=1000.0).text("Base height, m"),
+                    );
+                    if ui.button("Reset to defaults").clicked() {
+                        *fog = Fog::default();
+                    }
+                });
+
+                ui.separator();
+                ui.label("Shadows");
+                ui.add(Slider::new(&mut shadows.distance, 50.0..=2000.0).text("Distance, m"));
+                ui.add(Slider::new(&mut shadows.blend, 0.0..=0.5).text("Cascade blend"));
+                ui.checkbox(&mut shadows.show_cascades, "Show cascades");
+
+                ui.separator();
+                let ao = &mut ssao.settings;
+                ui.checkbox(&mut ao.enabled, "Ambient occlusion");
+                ui.add_enabled_ui(ao.enabled, |ui| {
+                    ui.add(Slider::new(&mut ao.radius, 0.1..=10.0).text("Radius, m"));
+                    ui.add(Slider::new(&mut ao.intensity, 0.1..=5.0).text("Intensity"));
+                    if ui.button("Reset to defaults").clicked() {
+                        *ao = AmbientOcclusion::default();
+                    }
+                });
+                ui.checkbox(&mut ssao.show, "Show occlusion");
+
+                ui.separator();
+                ui.add(Slider::new(&mut post_process.exposure, -5.0..=5.0).text("Exposure, EV"));
+                egui::ComboBox::from_label("Tone mapping")
+                    .selected_text(post_process.tone_mapper.name())
+                    .show_ui(ui, |ui| {
+                        for tone_mapper in ToneMapper::ALL {
+                            ui.selectable_value(
+                                &mut post_process.tone_mapper,
+                                tone_mapper,
+                                tone_mapper.name(),
+                            );
+                        }
+                    });
+                ui.checkbox(&mut post_process.bloom, "Bloom");
+                ui.add_enabled_ui(post_process.bloom, |ui| {
+                    ui.add(
+                        Slider::new(&mut post_process.bloom_threshold, 0.1..=10.0)
+                            .logarithmic(true)
+                            .text("Threshold"),
+                    );
+                    ui.add(
+                        Slider::new(&mut post_process.bloom_intensity, 0.0..=0.5).text("Intensity"),
+                    );
+                });
+                show_status(ui, statuses, GuiWindow::Lighting);
+            });
+
+        egui::Window::new("Layers")
+            .default_pos((10.0, 800.0))
+            .resizable(false)
+            .show(&self.ctx, |ui| {
+                let mut changed = false;
+                let mut active = layers.active;
+                let mut removed = None;
+                let mut moved_up = None;
+
+                // Top layer first
+                let num_layers = layers.layers.len();
+                for (i, layer) in layers.layers.iter_mut().enumerate().rev() {
+                    let info = &mut layer.info;
+                    ui.horizontal(|ui| {
+                        if ui.radio(active == i, "").clicked() {
+                            active = i;
+                        }
+                        changed |= ui.checkbox(&mut info.visible, "").changed();
+                        ui.add(egui::TextEdit::singleline(&mut info.name).desired_width(100.0));
+                        ui.label(info.kind.name());
+                    });
+                    ui.horizontal(|ui| {
+                        egui::ComboBox::from_id_source(("blend_mode", i))
+                            .selected_text(info.blend_mode.name())
+                            .show_ui(ui, |ui| {
+                                for mode in BlendMode::ALL {
+                                    changed |= ui
+                                        .selectable_value(&mut info.blend_mode, mode, mode.name())
+                                        .changed();
+                                }
+                            });
+                        changed |= ui
+                            .add(Slider::new(&mut info.opacity, 0.0..=1.0).text("Opacity"))
+                            .changed();
+                        if i + 1 < num_layers && ui.small_button("Up").clicked() {
+                            moved_up = Some(i);
+                        }
+                        if num_layers > 1 && ui.small_button("Delete").clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                    ui.separator();
+                }
+
+                layers.active = active;
+                if let Some(i) = moved_up {
+                    layers.move_up(i);
+                }
+                if let Some(i) = removed {
+                    layers.remove_layer(i);
+                }
+                if changed {
+                    layers.mark_dirty();
+                }
+
+                ui.checkbox(&mut layers.edit_mask, "Paint layer mask");
+
+                if layers.can_add_layer() {
+                    ui.horizontal(|ui| {
+                        if ui.button("Add sculpt").clicked() {
+                            layers.add_layer(LayerKind::Sculpt);
+                        }
+                        if ui.button("Add procedural").clicked() {
+                            layers.add_layer(LayerKind::Procedural);
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::TextEdit::singleline(stamp_path).desired_width(150.0));
+                        if ui.button("Add stamp").clicked() {
+                            actions.push(Action::AddStampLayer(stamp_path.clone()));
+                        }
+                    });
+                }
+                show_status(ui, statuses, GuiWindow::Layers);
+            });
+
+        if let Some(model_matrix) = model_matrix {
+            egui::Area::new("Viewport")
+                .fixed_pos((0.0, 0.0))
+                .show(&self.ctx, |ui| {
+                    ui.with_layer_id(LayerId::background(), |ui| {
+                        let visuals = GizmoVisuals {
+                            gizmo_size: 100.0,
+                            ..Default::default()
+                        };
+                        let gizmo = Gizmo::new("gizmo")
+                            .view_matrix(view_matrix.to_cols_array_2d())
+                            .projection_matrix(projection_matrix.to_cols_array_2d())
+                            .model_matrix(model_matrix.to_cols_array_2d())
+                            .mode(GizmoMode::Translate)
+                            .orientation(GizmoOrientation::Global)
+                            .visuals(visuals);
+
+                        if let Some(gizmo_result) = gizmo.interact(ui) {
+                            *model_matrix = Mat4::from_cols_array_2d(&gizmo_result.transform);
+                        }
+                    });
+                });
+        }
+
         // ================== GUI ends ===========================
 
+        self.end_frame(state, window);
+
+        actions
+    }
+
+    /// Lays out a frame with `build_ui` instead of the editor windows
+    pub fn layout_with<T>(
+        &mut self,
+        state: &mut State,
+        window: &Window,
+        build_ui: impl FnOnce(&CtxRef) -> T,
+    ) -> T {
+        let input = state.take_egui_input(window);
+        self.ctx.begin_frame(input);
+        let result = build_ui(&self.ctx);
+        self.end_frame(state, window);
+        result
+    }
+
+    fn end_frame(&mut self, state: &mut State, window: &Window) {
         let (output, shapes) = self.ctx.end_frame();
 
         state.handle_output(window, &self.ctx, output);
@@ -270,11 +882,10 @@ impl Gui {
                 )
             }
         }
-
-        actions
     }
 
-    pub fn draw(&mut self) {
+    /// Draws over whatever is in the window
+    pub fn draw(&mut self, gl: &mut GlState) {
         let pixels_per_point = self.ctx.pixels_per_point();
         let screen_size_in_points = self.screen_size / pixels_per_point;
 
@@ -282,31 +893,19 @@ impl Gui {
         self.shader
             .set_vec2("u_screen_size", &screen_size_in_points)
             .unwrap();
+        gl.set(RenderState::UI);
+        gl.bind_output();
         unsafe {
             gl::ActiveTexture(unit_to_gl_const(0));
             gl::BindTexture(gl::TEXTURE_2D, self.egui_texture);
 
             gl::BindVertexArray(self.vao);
-            gl::Disable(gl::DEPTH_TEST);
-            gl::Disable(gl::CULL_FACE);
-            gl::Enable(gl::BLEND);
-            gl::BlendFuncSeparate(
-                gl::ONE,
-                gl::ONE_MINUS_SRC_ALPHA,
-                gl::ONE_MINUS_DST_ALPHA,
-                gl::ONE,
-            );
-
             gl::DrawElements(
                 gl::TRIANGLES,
                 self.index_count,
                 gl::UNSIGNED_INT,
                 std::ptr::null(),
             );
-
-            gl::Disable(gl::BLEND);
-            gl::Enable(gl::DEPTH_TEST);
-            gl::Enable(gl::CULL_FACE);
         }
     }
 
@@ -369,6 +968,45 @@ impl Drop for Gui {
     }
 }
 
+fn show_status(ui: &mut Ui, statuses: &mut HashMap<GuiWindow, Status>, window: GuiWindow) {
+    let status = match statuses.get(&window) {
+        Some(status) => status,
+        None => return,
+    };
+    ui.separator();
+    let dismissed = ui
+        .horizontal(|ui| {
+            if status.failed {
+                ui.colored_label(Color32::RED, &status.text);
+            } else {
+                ui.label(&status.text);
+            }
+            ui.small_button("OK").clicked()
+        })
+        .inner;
+    if dismissed {
+        statuses.remove(&window);
+    }
+}
+
+fn draw_histogram(ui: &mut Ui, bins: &[u32]) {
+    let (response, painter) = ui.allocate_painter(egui::Vec2::new(200.0, 60.0), Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0.0, Color32::from_black_alpha(100));
+
+    let max_count = bins.iter().copied().max().unwrap_or(0).max(1) as f32;
+    let bar_width = rect.width() / bins.len() as f32;
+    for (i, &count) in bins.iter().enumerate() {
+        let left = rect.left() + i as f32 * bar_width;
+        let top = rect.bottom() - rect.height() * count as f32 / max_count;
+        let bar = Rect::from_min_max(
+            Pos2::new(left, top),
+            Pos2::new(left + bar_width, rect.bottom()),
+        );
+        painter.rect_filled(bar, 0.0, Color32::LIGHT_BLUE);
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 struct Vertex {
@@ -0,0 +1,97 @@
+use gl::types::*;
+use glam::{Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+
+/// Uniform buffer binding shared by all shaders that apply fog
+const FOG_BINDING: GLuint = 2;
+
+/// UFog and fog_amount(), for `with_includes`
+pub const FOG_GLSL: &str = include_str!("shaders/common/fog.glsl");
+
+/// Exponential fog which gets thinner with height
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Fog {
+    pub enabled: bool,
+    pub color: Vec3,
+    pub density: f32,
+    /// Metres from the camera where fog begins
+    pub start: f32,
+    /// How quickly fog thins out with height, 0 for uniform fog
+    pub height_falloff: f32,
+    /// Height where the fog has its full density
+    pub base_height: f32,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Fog {
+            enabled: true,
+            color: Vec3::new(0.66, 0.73, 0.8),
+            density: 0.002,
+            start: 50.0,
+            height_falloff: 0.01,
+            base_height: 0.0,
+        }
+    }
+}
+
+/// Must match UFog in fog.glsl
+#[repr(C)]
+struct FogUniforms {
+    color: Vec4,
+    camera_position: Vec4,
+    density: f32,
+    start: f32,
+    height_falloff: f32,
+    base_height: f32,
+}
+
+pub struct FogBuffer {
+    ubo: GLuint,
+}
+
+impl FogBuffer {
+    pub fn new() -> Self {
+        let mut ubo: GLuint = 0;
+        unsafe {
+            gl::CreateBuffers(1, &mut ubo);
+            gl::NamedBufferStorage(
+                ubo,
+                std::mem::size_of::<FogUniforms>() as isize,
+                std::ptr::null(),
+                gl::DYNAMIC_STORAGE_BIT,
+            );
+            gl::BindBufferBase(gl::UNIFORM_BUFFER, FOG_BINDING, ubo);
+        }
+        FogBuffer { ubo }
+    }
+
+    pub fn update(&self, fog: &Fog, camera_position: Vec3) {
+        let uniforms = FogUniforms {
+            color: fog.color.extend(1.0),
+            camera_position: camera_position.extend(1.0),
+            // Zero density turns the fog off in the shaders
+            density: if fog.enabled { fog.density } else { 0.0 },
+            start: fog.start,
+            height_falloff: fog.height_falloff,
+            base_height: fog.base_height,
+        };
+        unsafe {
+            gl::NamedBufferSubData(
+                self.ubo,
+                0,
+                std::mem::size_of::<FogUniforms>() as isize,
+                &uniforms as *const FogUniforms as *const _,
+            );
+        }
+    }
+}
+
+impl Drop for FogBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.ubo);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Renders fixed scenes without a window and compares them with the reference images in
+//! tests/golden. Set UPDATE_GOLDEN=1 to record new references after an intended visual change.
+//! Needs a GL 4.5 context, from surfaceless EGL on an X display or else OSMesa.
+//! Ignored until the references are committed, record them with
+//! `UPDATE_GOLDEN=1 cargo test golden -- --ignored`.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use glam::{Quat, Vec3};
+use image::{Rgb, RgbImage};
+
+use crate::camera::Camera;
+use crate::cli::render_headless;
+use crate::project::{Manifest, ObjectInfo};
+
+const WIDTH: i32 = 320;
+const HEIGHT: i32 = 180;
+
+const REFERENCE_DIR: &str = "tests/golden";
+/// Where the actual and the diff images of failed comparisons go
+const FAILURE_DIR: &str = "target/golden";
+
+/// Largest difference in a colour channel that still counts as the same pixel
+const CHANNEL_TOLERANCE: i32 = 8;
+/// Share of pixels allowed to differ, rasterisers don't agree on every edge
+const MAX_DIFFERENT_PIXELS: f32 = 0.002;
+
+/// The GL function pointers are global, so contexts must not be current on two threads
+static GL: Mutex<()> = Mutex::new(());
+
+struct GoldenScene {
+    name: &'static str,
+    manifest: Manifest,
+    /// None for flat terrain
+    heightmap: Option<&'static str>,
+    camera_position: Vec3,
+    camera_target: Vec3,
+}
+
+impl GoldenScene {
+    fn new(name: &'static str, camera_position: Vec3, camera_target: Vec3) -> Self {
+        GoldenScene {
+            name,
+            manifest: Manifest {
+                objects: vec![],
+                ..Manifest::default()
+            },
+            heightmap: None,
+            camera_position,
+            camera_target,
+        }
+    }
+
+    fn render(&self) -> RgbImage {
+        // Still usable after another test has panicked while holding it
+        let _lock = GL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let camera = Camera::new(
+            self.camera_position,
+            self.camera_target,
+            WIDTH as u32,
+            HEIGHT as u32,
+        );
+        render_headless(
+            &self.manifest,
+            self.heightmap.unwrap_or(""),
+            self.heightmap.is_none(),
+            &camera,
+            WIDTH,
+            HEIGHT,
+        )
+        .unwrap_or_else(|error| panic!("Couldn't render {}: {}", self.name, error))
+    }
+
+    fn check(&self) {
+        compare_with_reference(self.name, &self.render());
+    }
+}
+
+fn compare_with_reference(name: &str, actual: &RgbImage) {
+    let reference_path = Path::new(REFERENCE_DIR).join(format!("{}.png", name));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(REFERENCE_DIR).unwrap();
+        actual.save(&reference_path).unwrap();
+        eprintln!("Recorded {}", reference_path.display());
+        return;
+    }
+    assert!(
+        reference_path.exists(),
+        "{} has no reference, record it with UPDATE_GOLDEN=1",
+        reference_path.display(),
+    );
+
+    let reference = image::open(&reference_path).unwrap().into_rgb8();
+    assert_eq!(
+        reference.dimensions(),
+        actual.dimensions(),
+        "{} has a different size than its reference",
+        name,
+    );
+
+    // Differences in red over a faded reference
+    let (width, height) = actual.dimensions();
+    let mut diff = RgbImage::new(width, height);
+    let mut different = 0;
+    for ((actual, expected), diff) in actual
+        .pixels()
+        .zip(reference.pixels())
+        .zip(diff.pixels_mut())
+    {
+        let error = actual
+            .0
+            .iter()
+            .zip(expected.0.iter())
+            .map(|(&a, &b)| (a as i32 - b as i32).abs())
+            .max()
+            .unwrap_or(0);
+        *diff = if error > CHANNEL_TOLERANCE {
+            different += 1;
+            Rgb([255, 0, 0])
+        } else {
+            let faded = |c: u8| c / 4 + 96;
+            Rgb([faded(expected[0]), faded(expected[1]), faded(expected[2])])
+        };
+    }
+
+    let total = width * height;
+    if different as f32 > MAX_DIFFERENT_PIXELS * total as f32 {
+        fs::create_dir_all(FAILURE_DIR).unwrap();
+        let actual_path = Path::new(FAILURE_DIR).join(format!("{}.actual.png", name));
+        let diff_path = Path::new(FAILURE_DIR).join(format!("{}.diff.png", name));
+        actual.save(&actual_path).unwrap();
+        diff.save(&diff_path).unwrap();
+        panic!(
+            "{}: {} of {} pixels differ from {}, see {} and {}",
+            name,
+            different,
+            total,
+            reference_path.display(),
+            actual_path.display(),
+            diff_path.display(),
+        );
+    }
+}
+
+#[test]
+#[ignore = "no reference images yet"]
+fn flat_terrain() {
+    GoldenScene::new("flat_terrain", Vec3::new(300.0, 120.0, 300.0), Vec3::ZERO).check();
+}
+
+#[test]
+#[ignore = "no reference images yet"]
+fn sample_heightmap() {
+    GoldenScene {
+        heightmap: Some("textures/heightmaps/valley.png"),
+        ..GoldenScene::new(
+            "sample_heightmap",
+            Vec3::new(520.0, 250.0, 100.0),
+            Vec3::ZERO,
+        )
+    }
+    .check();
+}
+
+#[test]
+#[ignore = "no reference images yet"]
+fn box_model() {
+    let mut scene = GoldenScene::new(
+        "box_model",
+        Vec3::new(3.0, 2.5, 4.0),
+        Vec3::new(0.0, 1.0, 0.0),
+    );
+    scene.manifest.objects.push(ObjectInfo {
+        model_path: "models/box/box.gltf".to_owned(),
+        position: Vec3::ZERO,
+        orientation: Quat::from_rotation_y(0.5),
+    });
+    scene.check();
+}
+
+#[test]
+#[ignore = "no reference images yet"]
+fn skybox() {
+    // Mostly sky with a strip of terrain at the bottom
+    GoldenScene::new(
+        "skybox",
+        Vec3::new(0.0, 50.0, 0.0),
+        Vec3::new(100.0, 90.0, 40.0),
+    )
+    .check();
+}
@@ -0,0 +1,362 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use glam::Vec3;
+
+use crate::layers::{fractal_noise, read_floats, write_floats};
+use crate::Result;
+
+/// Square grid of normalised [0:1] heights kept on the CPU.
+/// Samples heights and normals like the editor does on the GPU, for tools that have no GL context.
+/// Erosion is the exception, the editor has no erosion tool.
+pub struct Heightfield {
+    pub size: usize,
+    pub heights: Vec<f32>,
+}
+
+impl Heightfield {
+    pub fn new(size: usize, heights: Vec<f32>) -> Self {
+        assert!(size > 0, "Heightfields have at least one texel");
+        assert_eq!(heights.len(), size * size);
+        Heightfield { size, heights }
+    }
+
+    pub fn generate(size: usize, seed: u32, scale: f32, octaves: u32) -> Self {
+        Heightfield::new(size, fractal_noise(size, seed, scale, octaves))
+    }
+
+    /// Reads 16 bit images, or raw little endian floats if the extension is .r32
+    pub fn load(path: &Path) -> Result<Self> {
+        let heights = if is_raw(path) {
+            read_floats(path)?
+        } else {
+            let img = image::open(path)?.into_luma16();
+            if img.width() != img.height() {
+                return Err(format!("{:?} is not square", path).into());
+            }
+            img.into_raw()
+                .into_iter()
+                .map(|value| value as f32 / u16::MAX as f32)
+                .collect()
+        };
+
+        if heights.is_empty() {
+            return Err(format!("{:?} has no heights", path).into());
+        }
+        let size = (heights.len() as f32).sqrt() as usize;
+        if size * size != heights.len() {
+            return Err(format!("{:?} is not square", path).into());
+        }
+        Ok(Heightfield::new(size, heights))
+    }
+
+    /// Writes 16 bit images, or raw little endian floats if the extension is .r32
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if is_raw(path) {
+            return write_floats(path, &self.heights);
+        }
+
+        let pixels: Vec<u16> = self
+            .heights
+            .iter()
+            .map(|height| (height.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16)
+            .collect();
+        let img = image::ImageBuffer::<image::Luma<u16>, _>::from_raw(
+            self.size as u32,
+            self.size as u32,
+            pixels,
+        )
+        .expect("Pixel count matches the size");
+        img.save(path)?;
+        Ok(())
+    }
+
+    /// Clamps to the edges like the heightmap textures
+    pub fn at(&self, x: isize, y: isize) -> f32 {
+        let max = self.size as isize - 1;
+        self.heights[(y.clamp(0, max) * self.size as isize + x.clamp(0, max)) as usize]
+    }
+
+    /// Same as calc_normal in terrain.te.glsl
+    pub fn normal(&self, x: usize, y: usize, terrain_size: f32, max_height: f32) -> Vec3 {
+        let (x, y) = (x as isize, y as isize);
+        let left = self.at(x - 1, y) * max_height;
+        let right = self.at(x + 1, y) * max_height;
+        let top = self.at(x, y - 1) * max_height;
+        let bottom = self.at(x, y + 1) * max_height;
+
+        let texel_size_world = terrain_size / self.size as f32;
+        let horizontal = Vec3::new(2.0 * texel_size_world, right - left, 0.0);
+        let vertical = Vec3::new(0.0, bottom - top, 2.0 * texel_size_world);
+
+        vertical.cross(horizontal).normalize()
+    }
+
+    /// Degrees
+    pub fn slope(&self, x: usize, y: usize, terrain_size: f32, max_height: f32) -> f32 {
+        let normal = self.normal(x, y, terrain_size, max_height);
+        normal.y.clamp(-1.0, 1.0).acos().to_degrees()
+    }
+
+    /// Bilinear filtering like a texture lookup, `u` and `v` are [0:1]
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        // Align texel centers
+        let x = u * self.size as f32 - 0.5;
+        let y = v * self.size as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = lerp(self.at(x0, y0), self.at(x0 + 1, y0), tx);
+        let bottom = lerp(self.at(x0, y0 + 1), self.at(x0 + 1, y0 + 1), tx);
+        lerp(top, bottom, ty)
+    }
+
+    /// Bilinear resampling to a new resolution
+    pub fn resample(&self, new_size: usize) -> Heightfield {
+        let mut heights = Vec::with_capacity(new_size * new_size);
+        for y in 0..new_size {
+            for x in 0..new_size {
+                let u = (x as f32 + 0.5) / new_size as f32;
+                let v = (y as f32 + 0.5) / new_size as f32;
+                heights.push(self.sample(u, v));
+            }
+        }
+        Heightfield::new(new_size, heights)
+    }
+
+    /// Thermal erosion: material slides down wherever the slope is steeper than `talus_angle`.
+    /// Only available here, not in the editor.
+    pub fn erode(&mut self, iterations: u32, talus_angle: f32, terrain_size: f32, max_height: f32) {
+        let texel_size_world = terrain_size / self.size as f32;
+        let talus = talus_angle.to_radians().tan() * texel_size_world / max_height;
+        let size = self.size as isize;
+        let neighbours = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+        let mut deltas = vec![0.0; self.heights.len()];
+        for _ in 0..iterations {
+            deltas.iter_mut().for_each(|delta| *delta = 0.0);
+            for y in 0..size {
+                for x in 0..size {
+                    let height = self.at(x, y);
+                    for (dx, dy) in neighbours {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if nx < 0 || ny < 0 || nx >= size || ny >= size {
+                            continue;
+                        }
+                        let difference = height - self.at(nx, ny);
+                        if difference > talus {
+                            // Each neighbour takes a share so that the cell can't go below them
+                            let amount = 0.5 * (difference - talus) / neighbours.len() as f32;
+                            deltas[(y * size + x) as usize] -= amount;
+                            deltas[(ny * size + nx) as usize] += amount;
+                        }
+                    }
+                }
+            }
+            for (height, delta) in self.heights.iter_mut().zip(&deltas) {
+                *height += delta;
+            }
+        }
+    }
+
+    /// World space normals, Y up, packed into RGB
+    pub fn normal_map(&self, terrain_size: f32, max_height: f32) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(self.size * self.size * 3);
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let normal = self.normal(x, y, terrain_size, max_height);
+                for value in [normal.x, normal.y, normal.z] {
+                    pixels.push(((value * 0.5 + 0.5) * 255.0).round() as u8);
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Wavefront OBJ with a vertex every `step` texels, centered like the terrain in the editor
+    pub fn to_obj(&self, terrain_size: f32, max_height: f32, step: usize) -> String {
+        let step = step.max(1);
+        let count = (self.size - 1) / step + 1;
+        let texel_size_world = terrain_size / self.size as f32;
+        // The middle texel lands on the terrain center
+        let half = (self.size - 1) as f32 / 2.0;
+
+        let mut obj = String::new();
+        for y in (0..self.size).step_by(step).take(count) {
+            for x in (0..self.size).step_by(step).take(count) {
+                let height = self.at(x as isize, y as isize) * max_height;
+                let px = (x as f32 - half) * texel_size_world;
+                let pz = (y as f32 - half) * texel_size_world;
+                let normal = self.normal(x, y, terrain_size, max_height);
+                let (u, v) = (x as f32 / self.size as f32, y as f32 / self.size as f32);
+                writeln!(obj, "v {} {} {}", px, height, pz).unwrap();
+                writeln!(obj, "vt {} {}", u, v).unwrap();
+                writeln!(obj, "vn {} {} {}", normal.x, normal.y, normal.z).unwrap();
+            }
+        }
+
+        // OBJ indices start at 1
+        let index = |x: usize, y: usize| y * count + x + 1;
+        for y in 0..count - 1 {
+            for x in 0..count - 1 {
+                let (a, b) = (index(x, y), index(x + 1, y));
+                let (c, d) = (index(x, y + 1), index(x + 1, y + 1));
+                writeln!(obj, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", a, c, b).unwrap();
+                writeln!(obj, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", b, c, d).unwrap();
+            }
+        }
+        obj
+    }
+
+    pub fn save_obj(
+        &self,
+        path: &Path,
+        terrain_size: f32,
+        max_height: f32,
+        step: usize,
+    ) -> Result<()> {
+        fs::write(path, self.to_obj(terrain_size, max_height, step))?;
+        Ok(())
+    }
+}
+
+fn is_raw(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "r32")
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TERRAIN_SIZE: f32 = 1024.0;
+    const MAX_HEIGHT: f32 = 200.0;
+
+    /// Rises along x from 0 to 1
+    fn ramp(size: usize) -> Heightfield {
+        let heights = (0..size * size)
+            .map(|i| (i % size) as f32 / (size - 1) as f32)
+            .collect();
+        Heightfield::new(size, heights)
+    }
+
+    #[test]
+    fn resample_keeps_flat_heights() {
+        let heightfield = Heightfield::new(8, vec![0.25; 64]);
+        let resampled = heightfield.resample(13);
+        assert_eq!(resampled.size, 13);
+        assert!(resampled.heights.iter().all(|&h| (h - 0.25).abs() < 1e-6));
+    }
+
+    #[test]
+    fn resample_keeps_the_shape() {
+        let resampled = ramp(64).resample(32);
+        for y in 0..32 {
+            for x in 1..32 {
+                assert!(resampled.at(x, y) > resampled.at(x - 1, y));
+            }
+        }
+        assert!(resampled.at(0, 0) < 0.05);
+        assert!(resampled.at(31, 0) > 0.95);
+    }
+
+    #[test]
+    fn erosion_flattens_steep_slopes_and_keeps_the_material() {
+        let mut heightfield = Heightfield::new(16, vec![0.0; 256]);
+        heightfield.heights[8 * 16 + 8] = 1.0;
+        let total_before: f32 = heightfield.heights.iter().sum();
+        // Next to the peak, its own normal points straight up
+        let slope_before = heightfield.slope(9, 8, TERRAIN_SIZE, MAX_HEIGHT);
+
+        heightfield.erode(100, 35.0, TERRAIN_SIZE, MAX_HEIGHT);
+
+        let total_after: f32 = heightfield.heights.iter().sum();
+        assert!((total_before - total_after).abs() < 1e-4);
+        assert!(heightfield.at(8, 8) < 1.0);
+        assert!(heightfield.slope(9, 8, TERRAIN_SIZE, MAX_HEIGHT) < slope_before);
+    }
+
+    #[test]
+    fn erosion_leaves_gentle_slopes_alone() {
+        let mut heightfield = ramp(16);
+        let before = heightfield.heights.clone();
+        // 1/15 of 200 m over 64 m texels is about 12 degrees
+        heightfield.erode(10, 35.0, TERRAIN_SIZE, MAX_HEIGHT);
+        assert_eq!(heightfield.heights, before);
+    }
+
+    #[test]
+    fn flat_normal_map_points_up() {
+        let heightfield = Heightfield::new(4, vec![0.5; 16]);
+        let pixels = heightfield.normal_map(TERRAIN_SIZE, MAX_HEIGHT);
+        assert_eq!(pixels.len(), 4 * 4 * 3);
+        for normal in pixels.chunks_exact(3) {
+            assert_eq!(normal, [128, 255, 128]);
+        }
+    }
+
+    #[test]
+    fn normals_lean_away_from_the_slope() {
+        let heightfield = ramp(8);
+        let normal = heightfield.normal(4, 4, TERRAIN_SIZE, MAX_HEIGHT);
+        assert!(normal.x < 0.0);
+        assert!(normal.y > 0.0);
+        assert!(normal.z.abs() < 1e-6);
+    }
+
+    #[test]
+    fn obj_has_a_vertex_every_step() {
+        let obj = Heightfield::new(9, vec![0.0; 81]).to_obj(TERRAIN_SIZE, MAX_HEIGHT, 4);
+        let count = |prefix: &str| obj.lines().filter(|l| l.starts_with(prefix)).count();
+        // Texels 0, 4 and 8 on each side
+        assert_eq!(count("v "), 9);
+        assert_eq!(count("vt "), 9);
+        assert_eq!(count("vn "), 9);
+        assert_eq!(count("f "), 2 * 2 * 2);
+    }
+
+    #[test]
+    fn obj_is_centered() {
+        let obj = Heightfield::new(3, vec![0.0; 9]).to_obj(TERRAIN_SIZE, MAX_HEIGHT, 1);
+        let xs: Vec<f32> = obj
+            .lines()
+            .filter(|l| l.starts_with("v "))
+            .map(|l| l.split(' ').nth(1).unwrap().parse().unwrap())
+            .collect();
+        let texel_size_world = TERRAIN_SIZE / 3.0;
+        assert_eq!(xs[0], -texel_size_world);
+        assert_eq!(xs[1], 0.0);
+        assert_eq!(xs[2], texel_size_world);
+    }
+
+    #[test]
+    fn single_texel_obj_has_no_faces() {
+        let obj = Heightfield::new(1, vec![0.5]).to_obj(TERRAIN_SIZE, MAX_HEIGHT, 1);
+        assert_eq!(obj.lines().filter(|l| l.starts_with("v ")).count(), 1);
+        assert!(!obj.lines().any(|l| l.starts_with("f ")));
+    }
+
+    #[test]
+    fn raw_heights_round_trip() {
+        let path = std::env::temp_dir().join("game2_heightfield_round_trip.r32");
+        let heightfield = ramp(8);
+        heightfield.save(&path).unwrap();
+        let loaded = Heightfield::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.size, 8);
+        assert_eq!(loaded.heights, heightfield.heights);
+    }
+
+    #[test]
+    fn empty_file_is_an_error() {
+        let path = std::env::temp_dir().join("game2_heightfield_empty.r32");
+        fs::write(&path, b"").unwrap();
+        let result = Heightfield::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -15,6 +15,10 @@ pub struct Input {
     pub back: bool,
     pub left: bool,
     pub right: bool,
+    pub jump: bool,
+    pub toggle_game_mode: bool,
+    pub toggle_menu: bool,
+    pub take_screenshot: bool,
     pub time: f32,
 
     // Processed
@@ -33,6 +37,7 @@ impl Input {
             back: self.back,
             left: self.left,
             right: self.right,
+            jump: self.jump,
             modifiers: self.modifiers,
             should_exit: self.should_exit,
             ..Default::default()
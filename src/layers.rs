@@ -0,0 +1,410 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::opengl::shader::Program;
+use crate::renderer::{GlState, RenderState};
+use crate::terrain::Heightmap;
+use crate::texture::unit_to_gl_const;
+use crate::Result;
+
+/// Each layer takes two texture units when compositing
+pub const MAX_LAYERS: usize = 6;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    /// Imported heightmap
+    Base,
+    Procedural,
+    Sculpt,
+    Stamp,
+}
+
+impl LayerKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LayerKind::Base => "Base",
+            LayerKind::Procedural => "Procedural",
+            LayerKind::Sculpt => "Sculpt",
+            LayerKind::Stamp => "Stamp",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Add,
+    Max,
+    Min,
+    Replace,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 4] = [
+        BlendMode::Add,
+        BlendMode::Max,
+        BlendMode::Min,
+        BlendMode::Replace,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlendMode::Add => "Add",
+            BlendMode::Max => "Max",
+            BlendMode::Min => "Min",
+            BlendMode::Replace => "Replace",
+        }
+    }
+
+    /// Must match the BLEND_* constants in composite.frag.glsl
+    fn to_gl(self) -> i32 {
+        match self {
+            BlendMode::Add => 0,
+            BlendMode::Max => 1,
+            BlendMode::Min => 2,
+            BlendMode::Replace => 3,
+        }
+    }
+}
+
+/// Everything about a layer except its pixels, which are saved separately
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LayerInfo {
+    pub name: String,
+    pub kind: LayerKind,
+    pub blend_mode: BlendMode,
+    pub opacity: f32,
+    pub visible: bool,
+}
+
+pub struct Layer {
+    pub info: LayerInfo,
+    /// Always float so that sculpt layers can hold negative offsets
+    pub(crate) heights: Heightmap,
+    /// Where the layer is applied, [0:1]
+    pub(crate) mask: Heightmap,
+}
+
+impl Layer {
+    fn new(info: LayerInfo, heights: Heightmap) -> Self {
+        let mask = Heightmap::flat(heights.texture_size);
+        mask.fill(1.0);
+        Layer {
+            info,
+            heights,
+            mask,
+        }
+    }
+}
+
+/// Heightmap layers which are composited on the GPU into the terrain heightmap
+pub struct LayerStack {
+    pub layers: Vec<Layer>,
+    /// The layer brushes draw on
+    pub active: usize,
+    /// Draw on the active layer's mask instead of its heights
+    pub edit_mask: bool,
+
+    texture_size: usize,
+    shader: Program,
+    dirty: bool,
+}
+
+impl LayerStack {
+    fn new(texture_size: usize, layers: Vec<Layer>) -> Result<Self> {
+        let shader = Program::new()
+            .vertex_shader(include_str!("shaders/editor/terrain/heightmap.vert"))?
+            .fragment_shader(include_str!("shaders/editor/terrain/composite.frag.glsl"))?
+            .link()?;
+
+        Ok(LayerStack {
+            active: layers.len().saturating_sub(1),
+            layers,
+            edit_mask: false,
+
+            texture_size,
+            shader,
+            dirty: true,
+        })
+    }
+
+    /// A base layer with an empty sculpt layer on top
+    pub fn from_base(base: Heightmap) -> Result<Self> {
+        let texture_size = base.texture_size;
+        let base = Layer::new(
+            LayerInfo {
+                name: "Base".to_owned(),
+                kind: LayerKind::Base,
+                blend_mode: BlendMode::Replace,
+                opacity: 1.0,
+                visible: true,
+            },
+            base,
+        );
+        let mut stack = LayerStack::new(texture_size, vec![base])?;
+        stack.add_layer(LayerKind::Sculpt);
+        Ok(stack)
+    }
+
+    /// Loads the layers saved by `save`
+    pub fn load(dir: &Path, infos: &[LayerInfo]) -> Result<Self> {
+        if infos.is_empty() {
+            return Err(format!("No layers to load from {:?}", dir).into());
+        }
+        let mut layers = vec![];
+        let mut texture_size = 0;
+        for (i, info) in infos.iter().enumerate() {
+            let heights_path = dir.join(format!("layer{}.heights.r32", i));
+            let mask_path = dir.join(format!("layer{}.mask.r32", i));
+            let heights = read_floats(&heights_path)?;
+            let mask = read_floats(&mask_path)?;
+
+            let size = (heights.len() as f64).sqrt() as usize;
+            if size == 0 || size * size != heights.len() {
+                return Err(format!("Layer {:?} isn't a square heightmap", heights_path).into());
+            }
+            if i > 0 && size != texture_size {
+                return Err(format!(
+                    "Layer {:?} is {}x{}, but the layers below are {}x{}",
+                    heights_path, size, size, texture_size, texture_size
+                )
+                .into());
+            }
+            if mask.len() != heights.len() {
+                return Err(format!("Layer mask {:?} doesn't match its layer", mask_path).into());
+            }
+            texture_size = size;
+
+            layers.push(Layer {
+                info: info.clone(),
+                heights: Heightmap::from_heights(texture_size, &heights),
+                mask: Heightmap::from_heights(texture_size, &mask),
+            });
+        }
+        LayerStack::new(texture_size, layers)
+    }
+
+    /// Writes the raw layer pixels into `dir` and returns what needs to be stored elsewhere
+    pub fn save(&self, dir: &Path) -> Result<Vec<LayerInfo>> {
+        fs::create_dir_all(dir)?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            write_floats(
+                &dir.join(format!("layer{}.heights.r32", i)),
+                &layer.heights.read_heights(),
+            )?;
+            write_floats(
+                &dir.join(format!("layer{}.mask.r32", i)),
+                &layer.mask.read_heights(),
+            )?;
+        }
+        Ok(self.layers.iter().map(|layer| layer.info.clone()).collect())
+    }
+
+    pub fn texture_size(&self) -> usize {
+        self.texture_size
+    }
+
+    pub fn can_add_layer(&self) -> bool {
+        self.layers.len() < MAX_LAYERS
+    }
+
+    /// Adds a layer above the active one
+    pub fn add_layer(&mut self, kind: LayerKind) {
+        let empty = || vec![0.0; self.texture_size * self.texture_size];
+        let (heights, blend_mode, opacity) = match kind {
+            LayerKind::Procedural => (
+                fractal_noise(self.texture_size, self.layers.len() as u32, 8.0, 6),
+                BlendMode::Add,
+                0.25,
+            ),
+            LayerKind::Stamp => (empty(), BlendMode::Max, 1.0),
+            LayerKind::Base | LayerKind::Sculpt => (empty(), BlendMode::Add, 1.0),
+        };
+        let heights = Heightmap::from_heights(self.texture_size, &heights);
+        self.insert_layer(kind, blend_mode, opacity, heights);
+    }
+
+    /// Adds a stamp layer from a heightmap image of the same size as the layers
+    pub fn add_stamp(&mut self, path: &str) -> Result<()> {
+        let heights = Heightmap::from_image(path)?;
+        if heights.texture_size != self.texture_size {
+            return Err(format!(
+                "Stamp is {0}x{0}, but the terrain layers are {1}x{1}",
+                heights.texture_size, self.texture_size
+            )
+            .into());
+        }
+        self.insert_layer(LayerKind::Stamp, BlendMode::Max, 1.0, heights);
+        Ok(())
+    }
+
+    fn insert_layer(
+        &mut self,
+        kind: LayerKind,
+        blend_mode: BlendMode,
+        opacity: f32,
+        heights: Heightmap,
+    ) {
+        assert!(self.can_add_layer());
+        let info = LayerInfo {
+            name: format!("{} {}", kind.name(), self.layers.len()),
+            kind,
+            blend_mode,
+            opacity,
+            visible: true,
+        };
+        let index = (self.active + 1).min(self.layers.len());
+        self.layers.insert(index, Layer::new(info, heights));
+        self.active = index;
+        self.dirty = true;
+    }
+
+    pub fn remove_layer(&mut self, index: usize) {
+        if self.layers.len() <= 1 {
+            return; // always keep something to draw on
+        }
+        self.layers.remove(index);
+        self.active = self.active.min(self.layers.len() - 1);
+        self.dirty = true;
+    }
+
+    /// Swaps a layer with the one above it
+    pub fn move_up(&mut self, index: usize) {
+        if index + 1 < self.layers.len() {
+            self.layers.swap(index, index + 1);
+            if self.active == index {
+                self.active += 1;
+            } else if self.active == index + 1 {
+                self.active -= 1;
+            }
+            self.dirty = true;
+        }
+    }
+
+    /// What the brush should draw on
+    pub fn active_target(&self) -> &Heightmap {
+        let layer = &self.layers[self.active];
+        if self.edit_mask {
+            &layer.mask
+        } else {
+            &layer.heights
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Blends all visible layers into `target` if anything has changed.
+    /// Returns whether it did.
+    pub fn composite(&mut self, gl: &mut GlState, target: &Heightmap) -> Result<bool> {
+        if !self.dirty {
+            return Ok(false);
+        }
+        self.dirty = false;
+
+        let visible = self
+            .layers
+            .iter()
+            .filter(|layer| layer.info.visible)
+            .collect::<Vec<_>>();
+
+        self.shader.set_used();
+        self.shader.set_i32("num_layers", visible.len() as i32)?;
+        for (i, layer) in visible.iter().enumerate() {
+            self.shader.set_i32(
+                &format!("blend_modes[{}]", i),
+                layer.info.blend_mode.to_gl(),
+            )?;
+            self.shader
+                .set_f32(&format!("opacities[{}]", i), layer.info.opacity)?;
+        }
+
+        unsafe {
+            for (i, layer) in visible.iter().enumerate() {
+                gl::ActiveTexture(unit_to_gl_const(i as i32));
+                gl::BindTexture(gl::TEXTURE_2D, layer.heights.texture);
+                gl::ActiveTexture(unit_to_gl_const((MAX_LAYERS + i) as i32));
+                gl::BindTexture(gl::TEXTURE_2D, layer.mask.texture);
+            }
+        }
+
+        gl.set(RenderState::DATA);
+        let size = target.texture_size as i32;
+        gl.bind_target(target.fbo(), size, size);
+        unsafe {
+            gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+        }
+
+        Ok(true)
+    }
+}
+
+/// Where the layer pixels, the sculpt mask and the material rules of a terrain are saved
+pub fn layers_dir(heightmap_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.layers", heightmap_path))
+}
+
+/// Value noise summed over several octaves, normalised to [0:1].
+/// `scale` is the number of noise cells across the map for the first octave.
+pub fn fractal_noise(size: usize, seed: u32, scale: f32, octaves: u32) -> Vec<f32> {
+    let hash = |x: i32, y: i32| {
+        let mut h = (x as u32)
+            .wrapping_mul(374_761_393)
+            .wrapping_add((y as u32).wrapping_mul(668_265_263))
+            .wrapping_add(seed.wrapping_mul(2_246_822_519));
+        h = (h ^ (h >> 13)).wrapping_mul(1_274_126_177);
+        (h ^ (h >> 16)) as f32 / u32::MAX as f32
+    };
+    let value_noise = |x: f32, y: f32| {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (sx, sy) = (tx * tx * (3.0 - 2.0 * tx), ty * ty * (3.0 - 2.0 * ty));
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let top = hash(x0, y0) + (hash(x0 + 1, y0) - hash(x0, y0)) * sx;
+        let bottom = hash(x0, y0 + 1) + (hash(x0 + 1, y0 + 1) - hash(x0, y0 + 1)) * sx;
+        top + (bottom - top) * sy
+    };
+
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    for _ in 0..octaves {
+        total_amplitude += amplitude;
+        amplitude *= 0.5;
+    }
+
+    let mut heights = Vec::with_capacity(size * size);
+    for y in 0..size {
+        for x in 0..size {
+            let (u, v) = (x as f32 / size as f32, y as f32 / size as f32);
+            let mut value = 0.0;
+            let mut frequency = scale;
+            let mut amplitude = 1.0;
+            for _ in 0..octaves {
+                value += amplitude * value_noise(u * frequency, v * frequency);
+                frequency *= 2.0;
+                amplitude *= 0.5;
+            }
+            heights.push(value / total_amplitude);
+        }
+    }
+    heights
+}
+
+pub(crate) fn read_floats(path: &Path) -> Result<Vec<f32>> {
+    let bytes = fs::read(path)?;
+    if bytes.len() % 4 != 0 {
+        return Err(format!("{:?} is truncated", path).into());
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+pub(crate) fn write_floats(path: &Path, values: &[f32]) -> Result<()> {
+    let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    fs::write(path, bytes)?;
+    Ok(())
+}
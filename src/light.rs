@@ -0,0 +1,143 @@
+use std::f32::consts::PI;
+
+use gl::types::*;
+use glam::{Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+
+/// Uniform buffer binding shared by all lit shaders
+const LIGHT_BINDING: GLuint = 4;
+
+/// Where the sun is over the day
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct SunPath {
+    /// Hours, the sun rises at 6 and sets at 18
+    pub time_of_day: f32,
+    /// Degrees clockwise from -Z where the sun rises
+    pub sunrise_azimuth: f32,
+    /// Degrees above the horizon at noon
+    pub noon_elevation: f32,
+}
+
+impl Default for SunPath {
+    fn default() -> Self {
+        SunPath {
+            time_of_day: 9.0,
+            sunrise_azimuth: 90.0,
+            noon_elevation: 50.0,
+        }
+    }
+}
+
+impl SunPath {
+    /// Unit vector pointing at the sun
+    pub fn sun_position(&self) -> Vec3 {
+        let azimuth = self.sunrise_azimuth.to_radians();
+        let sunrise = Vec3::new(azimuth.sin(), 0.0, -azimuth.cos());
+        let elevation = self.noon_elevation.to_radians();
+        // Noon is to the right of sunrise and tilted up
+        let noon =
+            Vec3::new(-sunrise.z, 0.0, sunrise.x) * elevation.cos() + Vec3::Y * elevation.sin();
+        // 0 at sunrise, PI at sunset
+        let angle = (self.time_of_day - 6.0) / 12.0 * PI;
+        (sunrise * angle.cos() + noon * angle.sin()).normalize()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct DirectionalLight {
+    pub color: Vec3,
+    /// Where the light travels, away from the sun
+    pub direction: Vec3,
+    pub intensity: f32,
+    /// Fraction of the light that reaches surfaces in shadow
+    pub ambient: f32,
+    pub sun: SunPath,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        let sun = SunPath::default();
+        DirectionalLight {
+            color: Vec3::new(1.0, 1.0, 1.0),
+            direction: -sun.sun_position(),
+            intensity: 1.0,
+            ambient: 0.35,
+            sun,
+        }
+    }
+}
+
+impl DirectionalLight {
+    /// Points the light from where the sun is at the current time of day
+    pub fn follow_sun(&mut self) {
+        self.direction = -self.sun.sun_position();
+    }
+
+    /// Fades out as the sun goes below the horizon
+    fn daylight(&self) -> f32 {
+        let elevation = -self.direction.y;
+        ((elevation + 0.05) / 0.15).clamp(0.0, 1.0)
+    }
+}
+
+/// Must match ULight in the shaders
+#[repr(C)]
+struct LightUniforms {
+    /// Towards the light
+    direction: Vec4,
+    /// Premultiplied by the intensity
+    color: Vec4,
+    ambient: f32,
+    use_sky_ambient: i32,
+    _padding: [f32; 2],
+}
+
+pub struct LightBuffer {
+    ubo: GLuint,
+}
+
+impl LightBuffer {
+    pub fn new() -> Self {
+        let mut ubo: GLuint = 0;
+        unsafe {
+            gl::CreateBuffers(1, &mut ubo);
+            gl::NamedBufferStorage(
+                ubo,
+                std::mem::size_of::<LightUniforms>() as isize,
+                std::ptr::null(),
+                gl::DYNAMIC_STORAGE_BIT,
+            );
+            gl::BindBufferBase(gl::UNIFORM_BUFFER, LIGHT_BINDING, ubo);
+        }
+        LightBuffer { ubo }
+    }
+
+    /// `sky_ambient` tells the shaders to take ambient light from the baked sky
+    pub fn update(&self, light: &DirectionalLight, sky_ambient: bool) {
+        let uniforms = LightUniforms {
+            direction: (-light.direction).normalize().extend(0.0),
+            color: (light.color * light.intensity * light.daylight()).extend(1.0),
+            ambient: light.ambient,
+            use_sky_ambient: sky_ambient as i32,
+            _padding: [0.0; 2],
+        };
+        unsafe {
+            gl::NamedBufferSubData(
+                self.ubo,
+                0,
+                std::mem::size_of::<LightUniforms>() as isize,
+                &uniforms as *const LightUniforms as *const _,
+            );
+        }
+    }
+}
+
+impl Drop for LightBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.ubo);
+        }
+    }
+}
@@ -2,24 +2,40 @@
 // #![allow(unused)]
 
 mod camera;
+mod cli;
 mod config;
 mod editor;
+mod fog;
+#[cfg(test)]
+mod golden;
+mod heightfield;
 mod input;
+mod layers;
+mod light;
+mod menu;
 mod model;
 mod opengl;
+mod player;
+mod postprocess;
+mod project;
 mod ray;
+mod renderer;
+mod shadows;
 mod skybox;
+mod splatmap;
+mod ssao;
 mod terrain;
 mod texture;
 mod utils;
 
 use std::error::Error;
-use std::time::Instant;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use egui::{Event as GuiEvent, Pos2, RawInput as EguiInput, Rect};
 use egui_winit::State as EguiState;
-use gl::types::GLuint;
-use glam::{Mat4, Quat, Vec2, Vec3};
+use glam::{Mat4, Quat, Vec2, Vec3, Vec3Swizzles};
 use glutin::event::{
     DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode,
     WindowEvent,
@@ -28,25 +44,53 @@ use glutin::event_loop::{ControlFlow, EventLoop};
 use glutin::window::WindowBuilder;
 use glutin::{Api, GlProfile, GlRequest};
 use glutin::{PossiblyCurrent, WindowedContext};
+use image::RgbImage;
 
 use camera::Camera;
 use config::Config;
-use editor::gui::{Action, Gui};
+use editor::gui::{Action, Gui, GuiWindow};
+use fog::Fog;
 use input::{vec2_to_egui_pos2, vec2_to_egui_vec2, vkeycode_to_egui_key, Input, Modifiers};
+use layers::layers_dir;
+use light::DirectionalLight;
+use menu::{Menu, MenuAction, MenuKind};
 use model::Model;
-use skybox::Skybox;
-use terrain::Terrain;
-
-use crate::opengl::shader::Program;
-use crate::texture::unit_to_gl_const;
+use player::{Ground, Player};
+use project::{CameraBookmark, Manifest, ObjectInfo};
+use ray::AABB;
+use renderer::{GlState, ModelInstance, Renderer, Scene};
+use skybox::{SkySettings, Skybox};
+use splatmap::save_material_rules;
+use terrain::{Histogram, Terrain, TerrainSettings};
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 // ==================================== Main loop =================================================
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("terrain") {
+        // Headless, no window or GL context
+        if let Err(error) = cli::run_terrain_command(&args[1..]) {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("screenshot") {
+        // Offscreen, no window
+        if let Err(error) = cli::run_screenshot_command(&args[1..]) {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Optional project directory to open
+    let project_dir = args.first().map(PathBuf::from);
+
     let event_loop = EventLoop::new();
-    let mut game = Game::new(&event_loop).unwrap_or_else(|error| {
+    let mut game = Game::new(&event_loop, project_dir).unwrap_or_else(|error| {
         eprintln!("{}", error);
         std::process::exit(1);
     });
@@ -61,14 +105,6 @@ fn main() {
 
 // ==================================== Game ======================================================
 
-static mut WINDOW_WIDTH: usize = 0;
-static mut WINDOW_HEIGHT: usize = 0;
-
-struct DirectionalLight {
-    color: Vec3,
-    direction: Vec3,
-}
-
 enum GameMode {
     Game,
     Editor,
@@ -82,6 +118,14 @@ enum EditorMode {
 
 struct EditorState {}
 
+/// Only exists while walking around
+struct GameState {
+    player: Player,
+    ground: Ground,
+    /// Restored when going back to the editor
+    editor_camera: Camera,
+}
+
 enum TerrainTool {
     Sculpt,
     PaintTextures,
@@ -89,24 +133,32 @@ enum TerrainTool {
     PaintVegetation,
 }
 
-// NOTE: no need to worry about std140 because Mat4's are aligned properly and with no gaps
-#[repr(C)]
-pub struct CameraTransforms {
-    mvp: Mat4,
-    proj: Mat4,
-    view: Mat4,
-    model: Mat4, // still unsure whether it belongs here
-    sun_vp: Mat4,
-}
-
 // Intentionally dumb
 struct GameObject {
     pos: Vec3,
     orientation: Quat,
     model: Model,
+    model_path: String,
 }
 
 impl GameObject {
+    fn load(info: &ObjectInfo) -> Result<Self> {
+        Ok(GameObject {
+            pos: info.position,
+            orientation: info.orientation,
+            model: Model::load(&info.model_path)?,
+            model_path: info.model_path.clone(),
+        })
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo {
+            model_path: self.model_path.clone(),
+            position: self.pos,
+            orientation: self.orientation,
+        }
+    }
+
     pub fn get_model_matrix(&self) -> Mat4 {
         Mat4::from_rotation_translation(self.orientation, self.pos)
     }
@@ -116,10 +168,19 @@ impl GameObject {
         self.pos = pos;
         self.orientation = orientation;
     }
+
+    fn instance(&self) -> ModelInstance<'_> {
+        ModelInstance {
+            model: &self.model,
+            transform: self.get_model_matrix(),
+        }
+    }
 }
 
 struct Game {
     config: Config,
+    /// None until a project is opened or saved
+    project_dir: Option<PathBuf>,
 
     windowed_context: WindowedContext<PossiblyCurrent>,
     in_focus: bool,
@@ -138,25 +199,37 @@ struct Game {
     camera: Camera,
 
     terrain: Terrain,
+    /// Of the open level, saved with the project when there is one instead of the config
+    terrain_settings: TerrainSettings,
+    terrain_histogram: Option<Histogram>,
     skybox: Skybox,
+    skybox_dir: String,
+    sky: SkySettings,
+    light: DirectionalLight,
+    fog: Fog,
+    camera_bookmarks: Vec<CameraBookmark>,
 
     mode: GameMode,
 
     editor_state: EditorState,
     editor_mode: EditorMode,
+    game_state: Option<GameState>,
+    menu: Menu,
 
-    // tmp
-    camera_transforms_ubo: GLuint,
-    camera_transforms: CameraTransforms,
-
-    model_shader: Program,
+    renderer: Renderer,
     game_objects: Vec<GameObject>,
+    /// Moved with the gizmo
+    selected_object: Option<usize>,
 }
 
 impl Game {
     /// Creates a window and inits a new game
-    fn new(event_loop: &EventLoop<()>) -> Result<Self> {
+    fn new(event_loop: &EventLoop<()>, project_dir: Option<PathBuf>) -> Result<Self> {
         let config = Config::load_or_default()?;
+        let manifest = match &project_dir {
+            Some(dir) => Manifest::load(dir)?,
+            None => Manifest::from_config(&config)?,
+        };
 
         // Create window
         #[cfg(all(windows))]
@@ -208,19 +281,18 @@ impl Game {
         // window.set_cursor_visible(false);
         let window_size = window.inner_size();
         unsafe {
-            // Remember window dimensions for further viewport adjustments
-            WINDOW_WIDTH = window_size.width as usize;
-            WINDOW_HEIGHT = window_size.height as usize;
-
-            gl::Viewport(0, 0, window_size.width as i32, window_size.height as i32);
             gl::ClearColor(0.05, 0.05, 0.05, 1.0);
-            gl::Enable(gl::DEPTH_TEST);
-            gl::Enable(gl::FRAMEBUFFER_SRGB);
-            gl::Enable(gl::CULL_FACE);
 
             gl::Enable(gl::DEBUG_OUTPUT);
             gl::DebugMessageCallback(Some(opengl::debug_callback), std::ptr::null());
         }
+        let mut renderer = Renderer::new(
+            window_size.width as i32,
+            window_size.height as i32,
+            manifest.ambient_occlusion,
+            manifest.post_process,
+            &config.graphics,
+        )?;
 
         // // Directional light
         // let light_color = Vec3::new(1.0, 0.7, 0.7);
@@ -233,86 +305,39 @@ impl Game {
         // shader.set_float("material.shininess", 10.0)?;
 
         // Set up camera
-        let position = config
-            .camera_position
+        let bookmark = manifest.camera_bookmarks.first();
+        let position = bookmark
+            .map(|bookmark| bookmark.position)
+            .or(config.camera_position)
             .unwrap_or_else(|| Vec3::new(520.0, 250.0, 100.0));
-        let target = position + config.camera_direction.unwrap_or(-position);
+        let direction = bookmark
+            .map(|bookmark| bookmark.direction)
+            .or(config.camera_direction);
+        let target = position + direction.unwrap_or(-position);
         let camera = Camera::new(position, target, window_size.width, window_size.height);
 
-        // Set up camera transforms uniform buffer
-        let mut transforms_ubo: GLuint = 0;
-        unsafe {
-            gl::CreateBuffers(1, &mut transforms_ubo);
-            gl::NamedBufferStorage(
-                transforms_ubo,
-                std::mem::size_of::<CameraTransforms>() as isize,
-                std::ptr::null(),
-                gl::DYNAMIC_STORAGE_BIT,
-            );
-            gl::BindBufferBase(gl::UNIFORM_BUFFER, 1, transforms_ubo);
-        }
-        let transforms_data = {
-            let proj = camera.get_projection_matrix();
-            let view = camera.get_view_matrix();
-            let model = Mat4::IDENTITY;
-            let sun_proj = Mat4::orthographic_rh_gl(-600.0, 600.0, -600.0, 600.0, 1.0, 1200.0);
-            let sun_view = Mat4::look_at_rh(
-                Vec3::new(0.0, 200.0, 500.0),
-                Vec3::new(0.0, 0.0, 0.0),
-                Vec3::new(0.0, 1.0, 0.0),
-            );
-
-            CameraTransforms {
-                mvp: proj * view * model,
-                proj,
-                view,
-                model,
-                sun_vp: sun_proj * sun_view,
-            }
+        let (terrain, skybox, game_objects) = match &project_dir {
+            Some(dir) => Game::load_level(
+                &mut renderer.gl,
+                &manifest,
+                &project::heightmap_path(dir),
+                false,
+            )?,
+            None => Game::load_level(
+                &mut renderer.gl,
+                &manifest,
+                &config.heightmap_path,
+                config.start_with_flat_terrain,
+            )?,
         };
 
-        let terrain = Terrain::new(
-            Vec2::new(0.0, 0.0),
-            config.start_with_flat_terrain,
-            &config.heightmap_path,
-        )?;
-
-        let skybox = Skybox::from([
-            "textures/skybox/default/right.png",
-            "textures/skybox/default/left.png",
-            "textures/skybox/default/top.png",
-            "textures/skybox/default/bottom.png",
-            "textures/skybox/default/front.png",
-            "textures/skybox/default/back.png",
-        ])?;
-
-        let game_objects = vec![
-            GameObject {
-                pos: Vec3::new(0.0, 0.0, 0.0),
-                orientation: Quat::default(),
-                model: Model::load("models/viking_room/scene.gltf")?,
-            },
-            GameObject {
-                pos: Vec3::new(100.0, 100.0, 0.0),
-                orientation: Quat::default(),
-                model: Model::load("models/box/box.gltf")?,
-            },
-            GameObject {
-                pos: Vec3::new(-100.0, 100.0, 0.0),
-                orientation: Quat::default(),
-                model: Model::load("models/box/box.gltf")?,
-            },
-        ];
-
-        let model_shader = Program::new()
-            .vertex_shader(include_str!("shaders/simple/simple.vert"))?
-            .fragment_shader(include_str!("shaders/simple/simple.frag"))?
-            .link()?;
-
         let screen_size_physical = Vec2::new(window_size.width as f32, window_size.height as f32);
 
         // Gui and its initial input
-        let gui = Gui::new(screen_size_physical)?;
+        let mut gui = Gui::new(screen_size_physical)?;
+        if let Some(dir) = &project_dir {
+            gui.set_project_path(&dir.to_string_lossy());
+        }
         let gui_state = EguiState::new(window);
 
         let now = Instant::now();
@@ -321,8 +346,16 @@ impl Game {
             ..Default::default()
         };
 
+        // Without a project there's nothing to edit yet
+        let mode = if project_dir.is_some() {
+            GameMode::Editor
+        } else {
+            GameMode::Menu
+        };
+
         Ok(Game {
             config,
+            project_dir,
 
             scale_factor: window.scale_factor() as f32,
             windowed_context,
@@ -340,22 +373,159 @@ impl Game {
             in_focus: true,
 
             terrain,
+            terrain_settings: manifest.terrain,
+            terrain_histogram: None,
             skybox,
+            skybox_dir: manifest.skybox,
+            sky: manifest.sky,
+            light: manifest.light,
+            fog: manifest.fog,
+            camera_bookmarks: manifest.camera_bookmarks,
 
-            mode: GameMode::Editor,
+            mode,
             editor_state: EditorState {},
+            game_state: None,
+            menu: Menu::default(),
             editor_mode: EditorMode::Terrain {
                 tool: TerrainTool::Sculpt,
             },
 
-            camera_transforms_ubo: transforms_ubo,
-            camera_transforms: transforms_data,
-
+            renderer,
             game_objects,
-            model_shader,
+            selected_object: None,
         })
     }
 
+    /// Everything in the level apart from the camera
+    fn load_level(
+        gl: &mut GlState,
+        manifest: &Manifest,
+        heightmap_path: &str,
+        start_flat: bool,
+    ) -> Result<(Terrain, Skybox, Vec<GameObject>)> {
+        let terrain = Terrain::new(
+            gl,
+            Vec2::new(0.0, 0.0),
+            &manifest.terrain,
+            start_flat,
+            heightmap_path,
+            manifest.material_rules.clone(),
+            &manifest.layers,
+        )?;
+        let skybox = Skybox::load(&manifest.skybox)?;
+        let game_objects = manifest
+            .objects
+            .iter()
+            .map(GameObject::load)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((terrain, skybox, game_objects))
+    }
+
+    fn open_project(&mut self, dir: &Path) -> Result<()> {
+        let manifest = Manifest::load(dir)?;
+        self.set_level(manifest, dir, false)?;
+        self.config.add_recent_project(dir);
+        self.config.save();
+        Ok(())
+    }
+
+    /// Flat terrain and the default objects, saved right away
+    fn new_project(&mut self, dir: &Path) -> Result<()> {
+        self.set_level(Manifest::default(), dir, true)?;
+        self.save_project(dir)
+    }
+
+    /// Replaces everything in the level with what the manifest describes
+    fn set_level(&mut self, manifest: Manifest, dir: &Path, start_flat: bool) -> Result<()> {
+        let (terrain, skybox, game_objects) = Game::load_level(
+            &mut self.renderer.gl,
+            &manifest,
+            &project::heightmap_path(dir),
+            start_flat,
+        )?;
+
+        self.terrain = terrain;
+        self.terrain_histogram = None;
+        self.skybox = skybox;
+        self.game_objects = game_objects;
+        self.selected_object = None;
+        self.terrain_settings = manifest.terrain;
+        self.skybox_dir = manifest.skybox;
+        self.sky = manifest.sky;
+        self.light = manifest.light;
+        self.fog = manifest.fog;
+        self.renderer.ssao.settings = manifest.ambient_occlusion;
+        self.renderer.post_process.settings = manifest.post_process;
+        self.camera_bookmarks = manifest.camera_bookmarks;
+        if let Some(bookmark) = self.camera_bookmarks.first() {
+            self.camera.look_from(bookmark.position, bookmark.direction);
+        }
+        self.input.camera_moved = true;
+        self.project_dir = Some(dir.to_owned());
+
+        Ok(())
+    }
+
+    /// Into the manifest of the open project, or into the config without one
+    fn save_terrain_settings(&mut self) -> Result<()> {
+        match &self.project_dir {
+            Some(dir) => {
+                let mut manifest = Manifest::load(dir)?;
+                manifest.terrain = self.terrain_settings;
+                manifest.save(dir)
+            }
+            None => {
+                self.config.terrain = self.terrain_settings;
+                self.config.save();
+                Ok(())
+            }
+        }
+    }
+
+    fn save_project(&mut self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let heightmap_path = project::heightmap_path(dir);
+        self.save_heightmap(&heightmap_path)?;
+        let layers_dir = layers_dir(&heightmap_path);
+        let layers = self.terrain.layers.save(&layers_dir)?;
+        self.terrain.sculpt_mask.save(&layers_dir)?;
+
+        let manifest = Manifest {
+            version: project::PROJECT_VERSION,
+            terrain: self.terrain.settings(),
+            material_rules: self.terrain.material_rules.clone(),
+            layers,
+            objects: self.game_objects.iter().map(GameObject::info).collect(),
+            skybox: self.skybox_dir.clone(),
+            sky: self.sky,
+            light: self.light,
+            fog: self.fog,
+            ambient_occlusion: self.renderer.ssao.settings,
+            post_process: self.renderer.post_process.settings,
+            camera_bookmarks: self.camera_bookmarks.clone(),
+        };
+        manifest.save(dir)?;
+        self.project_dir = Some(dir.to_owned());
+        self.config.add_recent_project(dir);
+        self.config.save();
+
+        Ok(())
+    }
+
+    /// Exports the composited heightmap as a 16 bit image
+    fn save_heightmap(&self, path: &str) -> Result<()> {
+        let (pixels, size) = self.terrain.get_heightmap_pixels();
+        image::save_buffer(
+            path,
+            &pixels,
+            size as u32,
+            size as u32,
+            image::ColorType::L16,
+        )?;
+        Ok(())
+    }
+
     fn process_event(&mut self, event: Event<()>, control_flow: &mut ControlFlow) -> Result<()> {
         match event {
             Event::WindowEvent { event, .. } => {
@@ -419,6 +589,10 @@ impl Game {
                             VirtualKeyCode::A => self.input.left = pressed,
                             VirtualKeyCode::S => self.input.back = pressed,
                             VirtualKeyCode::D => self.input.right = pressed,
+                            VirtualKeyCode::Space => self.input.jump = pressed,
+                            VirtualKeyCode::F5 if pressed => self.input.toggle_game_mode = true,
+                            VirtualKeyCode::Escape if pressed => self.input.toggle_menu = true,
+                            VirtualKeyCode::F12 if pressed => self.input.take_screenshot = true,
                             _ => {}
                         }
                     }
@@ -461,8 +635,8 @@ impl Game {
         self.input.time = time as f32;
 
         let new_mode = match self.mode {
-            GameMode::Menu => unimplemented!("Menu is not implemented"),
-            GameMode::Game => unimplemented!("Game mode is not implemented"),
+            GameMode::Menu => self.draw_menu()?,
+            GameMode::Game => self.draw_game(delta_time)?,
             GameMode::Editor => self.draw_editor(delta_time)?,
         };
 
@@ -472,17 +646,38 @@ impl Game {
     }
 
     fn draw_editor(&mut self, delta_time: f32) -> Result<GameMode> {
-        let active_game_object = 1;
-        let mut model_matrix = self.game_objects[active_game_object].get_model_matrix();
+        let object_names: Vec<&str> = self
+            .game_objects
+            .iter()
+            .map(|object| object.model_path.as_str())
+            .collect();
+        let selected_object = self.selected_object;
+        let mut model_matrix =
+            selected_object.map(|index| self.game_objects[index].get_model_matrix());
 
         let actions = self.gui.layout_and_interact(
             &mut self.gui_state,
             self.windowed_context.window(),
-            &self.camera_transforms.view,
-            &self.camera_transforms.proj,
-            &mut model_matrix,
+            &self.camera.get_view_matrix(),
+            &self.camera.get_projection_matrix(),
+            &object_names,
+            &mut self.selected_object,
+            model_matrix.as_mut(),
+            &mut self.terrain,
+            self.terrain_histogram.as_ref(),
+            &mut self.terrain_settings,
+            &mut self.light,
+            &mut self.sky,
+            &self.skybox_dir,
+            &mut self.fog,
+            &mut self.renderer.ssao,
+            &mut self.renderer.post_process.settings,
+            self.project_dir.is_some(),
+            &self.camera_bookmarks,
         );
-        self.game_objects[active_game_object].set_model_matrix(&model_matrix);
+        if let (Some(index), Some(model_matrix)) = (selected_object, model_matrix) {
+            self.game_objects[index].set_model_matrix(&model_matrix);
+        }
         self.process_gui_actions(actions)?;
 
         if self.gui.wants_input() {
@@ -515,30 +710,12 @@ impl Game {
 
                 // Rotate camera
                 if self.input.pointer_moved {
-                    let delta = self.input.pointer_delta;
+                    let delta = self.config.controls.look_delta(self.input.pointer_delta);
                     self.camera.rotate(delta.x, delta.y);
                     self.input.camera_moved = true;
                 }
             }
 
-            if self.input.camera_moved {
-                // Update camera tranforms uniform buffer
-                self.camera_transforms.view = self.camera.get_view_matrix();
-                self.camera_transforms.proj = self.camera.get_projection_matrix();
-                self.camera_transforms.mvp = self.camera_transforms.proj
-                    * self.camera_transforms.view
-                    * self.camera_transforms.model;
-                let data = &self.camera_transforms as *const CameraTransforms;
-                unsafe {
-                    gl::NamedBufferSubData(
-                        self.camera_transforms_ubo,
-                        0,
-                        std::mem::size_of::<CameraTransforms>() as isize,
-                        data as *const _,
-                    )
-                }
-            }
-
             if self.input.pointer_moved || self.input.camera_moved {
                 let ray = self.camera.get_ray_through_pixel(self.input.pointer);
                 let cursor_active = self.terrain.move_cursor(&ray);
@@ -554,70 +731,294 @@ impl Game {
             }
 
             if self.input.mouse_buttons.primary && self.terrain.cursor.is_finite() {
-                self.terrain
-                    .shape_terrain(delta_time, !self.input.modifiers.ctrl);
+                self.terrain.shape_terrain(
+                    &mut self.renderer.gl,
+                    delta_time,
+                    !self.input.modifiers.ctrl,
+                );
             }
         }
+        if !self.input.mouse_buttons.primary {
+            self.terrain.end_stroke();
+        }
 
-        // Draw
-        unsafe {
-            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        self.draw_scene()?;
+
+        self.gui.draw(&mut self.renderer.gl);
+
+        self.present()?;
+
+        let new_mode = if self.input.toggle_menu {
+            self.menu.open(MenuKind::Main);
+            GameMode::Menu
+        } else if self.input.toggle_game_mode {
+            self.enter_game_mode()?;
+            GameMode::Game
+        } else {
+            GameMode::Editor
+        };
+
+        // Clear old input
+        self.old_input = self.input.renew();
+
+        Ok(new_mode)
+    }
+
+    fn draw_game(&mut self, delta_time: f32) -> Result<GameMode> {
+        // Don't fall through the ground after a hitch
+        let delta_time = delta_time.min(0.05);
+
+        // Mouse look
+        if self.input.pointer_moved {
+            let delta = self.config.controls.look_delta(self.input.pointer_delta);
+            self.camera.rotate(delta.x, delta.y);
         }
-        self.terrain.draw(self.input.time)?;
-
-        // Draw objects
-        self.model_shader.set_used();
-        for obj in &self.game_objects {
-            let transform = obj.get_model_matrix();
-            unsafe {
-                gl::BindVertexArray(obj.model.vao);
-            }
-            for node in &obj.model.drawable_nodes {
-                let transform = transform * node.transform;
-                self.model_shader.set_mat4("model", &transform)?;
-
-                for primitive in &node.primitives {
-                    let material = &obj.model.materials[primitive.material_index];
-                    unsafe {
-                        gl::ActiveTexture(unit_to_gl_const(0));
-                        gl::BindTexture(gl::TEXTURE_2D, material.base_color_texture);
-
-                        gl::DrawElements(
-                            gl::TRIANGLES,
-                            primitive.index_count as i32,
-                            gl::UNSIGNED_INT,
-                            primitive.first_index as *const _,
-                        );
+
+        // Walk relative to where the camera looks
+        let forward = self.camera.direction.xz().normalize_or_zero();
+        let right = Vec2::new(-forward.y, forward.x);
+        let mut direction = Vec2::ZERO;
+        if self.input.forward {
+            direction += forward;
+        }
+        if self.input.back {
+            direction -= forward;
+        }
+        if self.input.right {
+            direction += right;
+        }
+        if self.input.left {
+            direction -= right;
+        }
+
+        let obstacles: Vec<AABB> = self
+            .game_objects
+            .iter()
+            .map(|obj| obj.model.bounds.transformed(&obj.get_model_matrix()))
+            .collect();
+        let state = self
+            .game_state
+            .as_mut()
+            .expect("Game state is created when entering the game mode");
+        state.player.update(
+            direction,
+            self.input.modifiers.shift,
+            self.input.jump,
+            delta_time,
+            &state.ground,
+            &obstacles,
+        );
+        self.camera.position = state.player.eye();
+
+        self.draw_scene()?;
+
+        self.present()?;
+
+        let new_mode = if self.input.toggle_menu {
+            self.menu.open(MenuKind::Pause);
+            self.grab_cursor(false)?;
+            GameMode::Menu
+        } else if self.input.toggle_game_mode {
+            self.leave_game_mode()?;
+            GameMode::Editor
+        } else {
+            GameMode::Game
+        };
+
+        // Clear old input
+        self.old_input = self.input.renew();
+
+        Ok(new_mode)
+    }
+
+    /// Puts the player on the ground below the editor camera
+    fn enter_game_mode(&mut self) -> Result<()> {
+        let ground = self.terrain.ground();
+        let start = self
+            .camera
+            .position
+            .xz()
+            .clamp(self.terrain.aabb.min.xz(), self.terrain.aabb.max.xz());
+        let player = Player::new(Vec3::new(start.x, ground.height_at(start), start.y));
+        self.game_state = Some(GameState {
+            player,
+            ground,
+            editor_camera: self.camera.clone(),
+        });
+
+        self.terrain.hide_cursor();
+        self.grab_cursor(true)
+    }
+
+    fn leave_game_mode(&mut self) -> Result<()> {
+        if let Some(state) = self.game_state.take() {
+            self.camera = state.editor_camera;
+        }
+        self.input.camera_moved = true;
+        self.grab_cursor(false)
+    }
+
+    /// Mouse look needs the cursor locked and hidden
+    fn grab_cursor(&self, grab: bool) -> Result<()> {
+        let window = self.windowed_context.window();
+        window.set_cursor_grab(grab)?;
+        window.set_cursor_visible(!grab);
+        Ok(())
+    }
+
+    fn draw_menu(&mut self) -> Result<GameMode> {
+        let menu = &mut self.menu;
+        let config = &mut self.config;
+        let terrain_settings = &mut self.terrain_settings;
+        let actions =
+            self.gui
+                .layout_with(&mut self.gui_state, self.windowed_context.window(), |ctx| {
+                    menu.show(
+                        ctx,
+                        &config.recent_projects,
+                        terrain_settings,
+                        &mut config.graphics,
+                        &mut config.controls,
+                    )
+                });
+
+        let mut new_mode = GameMode::Menu;
+        if self.input.toggle_menu {
+            new_mode = self.resume()?;
+        }
+        for action in actions {
+            match action {
+                MenuAction::Resume => new_mode = self.resume()?,
+                MenuAction::BackToEditor => {
+                    self.leave_game_mode()?;
+                    new_mode = GameMode::Editor;
+                }
+                MenuAction::NewProject(path) => {
+                    self.leave_game_mode()?;
+                    let result = self.new_project(Path::new(&path));
+                    new_mode = self.project_loaded(result);
+                }
+                MenuAction::OpenProject(path) => {
+                    self.leave_game_mode()?;
+                    let result = self.open_project(Path::new(&path));
+                    new_mode = self.project_loaded(result);
+                }
+                MenuAction::ApplyGraphicsSettings => {
+                    self.terrain
+                        .apply_settings(&mut self.renderer.gl, &self.terrain_settings)?;
+                    self.renderer.apply_graphics_settings(&self.config.graphics);
+                }
+                MenuAction::SaveSettings => {
+                    self.config.save();
+                    if let Err(error) = self.save_terrain_settings() {
+                        self.menu.show_error(error.to_string());
                     }
                 }
+                MenuAction::Quit => self.input.should_exit = true,
             }
         }
 
-        self.skybox.draw();
-
-        self.gui.draw();
+        // The level stays visible behind the menu
+        self.draw_scene()?;
+        self.gui.draw(&mut self.renderer.gl);
 
-        self.windowed_context.swap_buffers()?;
+        self.present()?;
 
         // Clear old input
         self.old_input = self.input.renew();
 
-        Ok(GameMode::Editor)
+        Ok(new_mode)
+    }
+
+    /// Goes to the editor, or back to the main menu with the error.
+    /// The game mode has been left either way.
+    fn project_loaded(&mut self, result: Result<()>) -> GameMode {
+        match result {
+            Ok(()) => GameMode::Editor,
+            Err(error) => {
+                self.menu.open(MenuKind::Main);
+                self.menu.show_error(error.to_string());
+                GameMode::Menu
+            }
+        }
+    }
+
+    /// Closes the menu
+    fn resume(&mut self) -> Result<GameMode> {
+        Ok(match self.menu.kind {
+            MenuKind::Main => GameMode::Editor,
+            MenuKind::Pause => {
+                self.grab_cursor(true)?;
+                GameMode::Game
+            }
+        })
+    }
+
+    /// Shows the frame, saving it first if a screenshot was asked for
+    fn present(&mut self) -> Result<()> {
+        if self.input.take_screenshot {
+            let image = self.renderer.read_output();
+            let result = self.save_screenshot(&image);
+            self.screenshot_saved(result);
+        }
+        self.windowed_context.swap_buffers()?;
+        Ok(())
+    }
+
+    /// Into the project's screenshots directory, or the working directory without a project
+    fn save_screenshot(&self, image: &RgbImage) -> Result<PathBuf> {
+        let dir = self
+            .project_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new("."))
+            .join("screenshots");
+        fs::create_dir_all(&dir)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let path = dir.join(format!("{}.png", timestamp));
+        image.save(&path)?;
+        Ok(path)
+    }
+
+    /// Tells in the Tools window where the screenshot went, or why it didn't
+    fn screenshot_saved(&mut self, result: Result<PathBuf>) {
+        match result {
+            Ok(path) => self
+                .gui
+                .show_message(GuiWindow::Tools, format!("Saved {}", path.display())),
+            Err(error) => self.gui.show_result(GuiWindow::Tools, Err(error)),
+        }
+    }
+
+    /// The renderer and what it draws, borrowed separately
+    fn renderer_and_scene(&mut self) -> (&mut Renderer, Scene<'_>) {
+        let scene = Scene {
+            camera: &self.camera,
+            terrain: &mut self.terrain,
+            skybox: &mut self.skybox,
+            sky: &self.sky,
+            light: &self.light,
+            fog: &self.fog,
+            objects: self.game_objects.iter().map(GameObject::instance).collect(),
+        };
+        (&mut self.renderer, scene)
+    }
+
+    /// Terrain, objects and sky
+    fn draw_scene(&mut self) -> Result<()> {
+        let (renderer, scene) = self.renderer_and_scene();
+        renderer.draw_scene(scene)
     }
 
     fn process_gui_actions(&mut self, actions: Vec<Action>) -> Result<()> {
         for action in actions {
             match action {
                 Action::SaveTerrain => {
-                    let (pixels, size) = self.terrain.get_heightmap_pixels();
-                    image::save_buffer(
-                        self.config.heightmap_path.clone(),
-                        &pixels,
-                        size as u32,
-                        size as u32,
-                        image::ColorType::L16,
-                    )?;
+                    self.save_heightmap(&self.config.heightmap_path)?;
                     self.config.start_with_flat_terrain = false;
+                    let layers_dir = layers_dir(&self.config.heightmap_path);
+                    self.config.layers = self.terrain.layers.save(&layers_dir)?;
+                    self.terrain.sculpt_mask.save(&layers_dir)?;
+                    save_material_rules(&layers_dir, &self.terrain.material_rules)?;
                     self.config.save();
                 }
                 Action::SaveCamera => {
@@ -625,6 +1026,67 @@ impl Game {
                     self.config.camera_direction = Some(self.camera.direction);
                     self.config.save();
                 }
+                Action::OpenProject(path) => {
+                    let result = self.open_project(Path::new(&path));
+                    self.gui.show_result(GuiWindow::Project, result);
+                }
+                Action::SaveProject => {
+                    if let Some(dir) = self.project_dir.clone() {
+                        let result = self.save_project(&dir);
+                        self.gui.show_result(GuiWindow::Project, result);
+                    }
+                }
+                Action::SaveProjectAs(path) => {
+                    let result = self.save_project(Path::new(&path));
+                    self.gui.show_result(GuiWindow::Project, result);
+                }
+                Action::AddCameraBookmark(name) => {
+                    self.camera_bookmarks.push(CameraBookmark {
+                        name,
+                        position: self.camera.position,
+                        direction: self.camera.direction,
+                    });
+                }
+                Action::GoToCameraBookmark(index) => {
+                    let bookmark = &self.camera_bookmarks[index];
+                    self.camera.look_from(bookmark.position, bookmark.direction);
+                    self.input.camera_moved = true;
+                }
+                Action::RemoveCameraBookmark(index) => {
+                    self.camera_bookmarks.remove(index);
+                }
+                Action::UpdateMaterials => {
+                    self.terrain.update_splat_map(&mut self.renderer.gl)?;
+                }
+                Action::ApplyTerrainSettings => {
+                    self.terrain
+                        .apply_settings(&mut self.renderer.gl, &self.terrain_settings)?;
+                    let result = self.save_terrain_settings();
+                    self.gui.show_result(GuiWindow::Project, result);
+                }
+                Action::AddStampLayer(path) => {
+                    let result = self.terrain.layers.add_stamp(&path);
+                    self.gui.show_result(GuiWindow::Layers, result);
+                }
+                Action::SetSkybox(path) => {
+                    // Keeps the current one on failure
+                    let result = Skybox::load(&path).map(|skybox| {
+                        self.skybox = skybox;
+                        self.skybox_dir = path;
+                    });
+                    self.gui
+                        .show_result(GuiWindow::Lighting, result.map_err(|error| error.into()));
+                }
+                Action::TakeScreenshot => self.input.take_screenshot = true,
+                Action::TakeHighResScreenshot(scale) => {
+                    let (renderer, scene) = self.renderer_and_scene();
+                    let image = renderer.capture(scene, scale);
+                    let result = image.and_then(|image| self.save_screenshot(&image));
+                    self.screenshot_saved(result);
+                }
+                Action::ComputeHistogram => {
+                    self.terrain_histogram = Some(self.terrain.compute_histogram(64));
+                }
                 Action::Quit => {
                     self.input.should_exit = true;
                 }
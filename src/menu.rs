@@ -0,0 +1,187 @@
+use egui::{Align2, Color32, CtxRef, Slider, Ui};
+
+use crate::config::{ControlSettings, GraphicsSettings};
+use crate::shadows::SHADOW_MAP_SIZES;
+use crate::terrain::TerrainSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuKind {
+    /// Opened from the editor
+    Main,
+    /// Opened while walking around
+    Pause,
+}
+
+/// An action to take as a result of interacting with the menu
+pub enum MenuAction {
+    /// Close the menu and go back to where it was opened from
+    Resume,
+    BackToEditor,
+    NewProject(String),
+    OpenProject(String),
+    /// Graphics settings were changed
+    ApplyGraphicsSettings,
+    /// Left the settings screen
+    SaveSettings,
+    Quit,
+}
+
+pub struct Menu {
+    pub kind: MenuKind,
+    settings_open: bool,
+    project_path: String,
+    /// Why the last project couldn't be opened or created
+    error: Option<String>,
+}
+
+impl Default for Menu {
+    fn default() -> Self {
+        Menu {
+            kind: MenuKind::Main,
+            settings_open: false,
+            project_path: "projects/untitled".to_owned(),
+            error: None,
+        }
+    }
+}
+
+impl Menu {
+    pub fn open(&mut self, kind: MenuKind) {
+        self.kind = kind;
+        self.settings_open = false;
+        self.error = None;
+    }
+
+    pub fn show_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    pub fn show(
+        &mut self,
+        ctx: &CtxRef,
+        recent_projects: &[String],
+        terrain_settings: &mut TerrainSettings,
+        graphics: &mut GraphicsSettings,
+        controls: &mut ControlSettings,
+    ) -> Vec<MenuAction> {
+        let mut actions = vec![];
+        let title = match self.kind {
+            MenuKind::Main => "Main menu",
+            MenuKind::Pause => "Paused",
+        };
+
+        egui::Window::new(title)
+            .anchor(Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                if self.settings_open {
+                    show_settings(ui, terrain_settings, graphics, controls, &mut actions);
+                    ui.separator();
+                    if ui.button("Back").clicked() {
+                        self.settings_open = false;
+                        actions.push(MenuAction::SaveSettings);
+                    }
+                    return;
+                }
+
+                let resume_label = match self.kind {
+                    MenuKind::Main => "Back to editor",
+                    MenuKind::Pause => "Resume",
+                };
+                if ui.button(resume_label).clicked() {
+                    actions.push(MenuAction::Resume);
+                }
+                if self.kind == MenuKind::Pause && ui.button("Back to editor").clicked() {
+                    actions.push(MenuAction::BackToEditor);
+                }
+
+                ui.separator();
+                ui.text_edit_singleline(&mut self.project_path);
+                ui.horizontal(|ui| {
+                    if ui.button("New project").clicked() {
+                        actions.push(MenuAction::NewProject(self.project_path.clone()));
+                    }
+                    if ui.button("Open project").clicked() {
+                        actions.push(MenuAction::OpenProject(self.project_path.clone()));
+                    }
+                });
+                if let Some(error) = &self.error {
+                    ui.colored_label(Color32::RED, error);
+                }
+                if !recent_projects.is_empty() {
+                    ui.label("Recent projects");
+                    for path in recent_projects {
+                        if ui.button(path).clicked() {
+                            actions.push(MenuAction::OpenProject(path.clone()));
+                        }
+                    }
+                }
+
+                ui.separator();
+                if ui.button("Settings").clicked() {
+                    self.settings_open = true;
+                }
+                if ui.button("Quit").clicked() {
+                    actions.push(MenuAction::Quit);
+                }
+            });
+
+        actions
+    }
+}
+
+fn show_settings(
+    ui: &mut Ui,
+    terrain_settings: &mut TerrainSettings,
+    graphics: &mut GraphicsSettings,
+    controls: &mut ControlSettings,
+    actions: &mut Vec<MenuAction>,
+) {
+    ui.heading("Graphics");
+    let mut changed = false;
+    changed |= ui
+        .add(Slider::new(&mut terrain_settings.tess_level, 1.0..=16.0).text("Terrain detail"))
+        .changed();
+    egui::ComboBox::from_label("Shadow quality")
+        .selected_text(terrain_settings.shadow_map_size.to_string())
+        .show_ui(ui, |ui| {
+            for size in SHADOW_MAP_SIZES {
+                changed |= ui
+                    .selectable_value(
+                        &mut terrain_settings.shadow_map_size,
+                        size,
+                        size.to_string(),
+                    )
+                    .changed();
+            }
+        });
+    let msaa_name = |samples: i32| {
+        if samples > 1 {
+            format!("{}x", samples)
+        } else {
+            "Off".to_owned()
+        }
+    };
+    egui::ComboBox::from_label("MSAA")
+        .selected_text(msaa_name(graphics.msaa_samples))
+        .show_ui(ui, |ui| {
+            for samples in [1, 2, 4, 8] {
+                changed |= ui
+                    .selectable_value(&mut graphics.msaa_samples, samples, msaa_name(samples))
+                    .changed();
+            }
+        });
+    changed |= ui.checkbox(&mut graphics.fxaa, "FXAA").changed();
+    if changed {
+        actions.push(MenuAction::ApplyGraphicsSettings);
+    }
+
+    ui.heading("Controls");
+    ui.add(
+        Slider::new(&mut controls.mouse_sensitivity, 0.1..=5.0)
+            .logarithmic(true)
+            .text("Mouse sensitivity"),
+    );
+    ui.checkbox(&mut controls.invert_mouse_y, "Invert mouse Y");
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::mem::size_of;
 
 use gl::types::*;
@@ -5,10 +6,13 @@ use glam::{Mat4, Vec2, Vec3, Vec4};
 use gltf::accessor::DataType;
 use gltf::accessor::Dimensions;
 use gltf::image::Format;
+use gltf::material::AlphaMode;
 use gltf::Document;
 use memoffset::offset_of;
 
-use crate::texture::calculate_mip_levels;
+use crate::opengl::shader::Program;
+use crate::ray::AABB;
+use crate::texture::{calculate_mip_levels, unit_to_gl_const};
 use crate::utils::size_of_slice;
 use crate::Result;
 
@@ -21,6 +25,8 @@ pub struct Model {
 
     pub drawable_nodes: Vec<DrawableNode>,
     pub materials: Vec<Material>,
+    /// In model space, with the node transforms applied
+    pub bounds: AABB,
 }
 
 impl Model {
@@ -32,6 +38,7 @@ impl Model {
         let mut drawable_nodes = vec![];
         let mut vertices = vec![];
         let mut indices = vec![];
+        let mut bounds = AABB::empty();
         for (node, transform) in
             NodesWithTransforms::from(&gltf).filter(|(node, _)| node.mesh().is_some())
         {
@@ -39,12 +46,14 @@ impl Model {
             for primitive in node.mesh().unwrap().primitives() {
                 let first_index = indices.len();
                 let vertex_start = vertices.len();
+                let has_tangents;
 
                 // Add vertices to our buffer
                 {
                     // Gather all attributes in separate vecs (yes, inefficient)
                     let mut positions = vec![];
                     let mut normals = vec![];
+                    let mut tangents = vec![];
                     let mut uvs = vec![];
 
                     for (attr, accessor) in primitive.attributes() {
@@ -87,6 +96,15 @@ impl Model {
                                         cursor = cursor.add(stride);
                                     }
                                 }
+                                Tangents => {
+                                    debug_assert_eq!(accessor.dimensions(), Dimensions::Vec4);
+                                    for _ in 0..count {
+                                        // Vec4 is 16-byte aligned, glTF only guarantees 4
+                                        let tangent = cursor as *const [f32; 4];
+                                        tangents.push(Vec4::from(tangent.read_unaligned()));
+                                        cursor = cursor.add(stride);
+                                    }
+                                }
                                 TexCoords(0) => {
                                     debug_assert_eq!(accessor.dimensions(), Dimensions::Vec2);
                                     for _ in 0..count {
@@ -102,11 +120,20 @@ impl Model {
                     }
                     assert_eq!(positions.len(), normals.len());
                     assert_eq!(positions.len(), uvs.len());
+                    has_tangents = tangents.len() == positions.len();
+                    if !has_tangents {
+                        // Generated below once the indices are known
+                        tangents = vec![Vec4::ZERO; positions.len()];
+                    }
 
+                    for &position in &positions {
+                        bounds.extend(transform.transform_point3(position));
+                    }
                     for i in 0..positions.len() {
                         vertices.push(Vertex {
                             pos: positions[i],
                             normal: normals[i],
+                            tangent: tangents[i],
                             uv: uvs[i],
                         })
                     }
@@ -143,6 +170,16 @@ impl Model {
                 }
                 assert_eq!(indices.len(), first_index + accessor.count());
 
+                if !has_tangents {
+                    let mut geometry = TangentGeometry {
+                        vertices: &mut vertices[vertex_start..],
+                        indices: &indices[first_index..],
+                        vertex_start: vertex_start as u32,
+                    };
+                    // On failure tangents stay zero and the shader ignores the normal map
+                    mikktspace::generate_tangents(&mut geometry);
+                }
+
                 primitives.push(Primitive {
                     first_index,
                     index_count: accessor.count(),
@@ -199,13 +236,25 @@ impl Model {
                 offset_of!(Vertex, uv) as u32,
             );
 
+            // Tangent, w is the handedness of the bitangent
+            gl::VertexArrayAttribFormat(
+                vao,
+                3,
+                4,
+                gl::FLOAT,
+                gl::FALSE,
+                offset_of!(Vertex, tangent) as u32,
+            );
+
             gl::EnableVertexArrayAttrib(vao, 0);
             gl::EnableVertexArrayAttrib(vao, 1);
             gl::EnableVertexArrayAttrib(vao, 2);
+            gl::EnableVertexArrayAttrib(vao, 3);
 
             gl::VertexArrayAttribBinding(vao, 0, 0);
             gl::VertexArrayAttribBinding(vao, 1, 0);
             gl::VertexArrayAttribBinding(vao, 2, 0);
+            gl::VertexArrayAttribBinding(vao, 3, 0);
 
             // Vertex data
             gl::NamedBufferStorage(
@@ -224,6 +273,18 @@ impl Model {
             );
         }
 
+        // Colours are sRGB, everything else (normals, roughness, ...) is linear
+        let mut srgb_images = HashSet::new();
+        for material in gltf.materials() {
+            let colors = [
+                material.pbr_metallic_roughness().base_color_texture(),
+                material.emissive_texture(),
+            ];
+            for info in colors.iter().flatten() {
+                srgb_images.insert(info.texture().source().index());
+            }
+        }
+
         // Load textures
         let num_textures = images.len();
         let mut texture_ids = Vec::with_capacity(num_textures);
@@ -246,13 +307,18 @@ impl Model {
                 gl::TextureParameteri(texture, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
             }
         }
-        for (image, texture_id) in images.into_iter().zip(&texture_ids) {
+        for (i, (image, texture_id)) in images.into_iter().zip(&texture_ids).enumerate() {
             let texture = *texture_id;
+            let internal_format = if srgb_images.contains(&i) {
+                gl::SRGB8
+            } else {
+                gl::RGB8
+            };
             unsafe {
                 gl::TextureStorage2D(
                     texture,
                     calculate_mip_levels(image.width as usize, image.height as usize),
-                    gl::SRGB8,
+                    internal_format,
                     image.width as i32,
                     image.height as i32,
                 );
@@ -290,15 +356,55 @@ impl Model {
             .map(|texture| texture_ids[texture.source().index()])
             .collect::<Vec<_>>();
 
+        // Stand-ins for the textures a material doesn't have
+        let white = create_pixel_texture([255, 255, 255]);
+        let flat_normal = create_pixel_texture([128, 128, 255]);
+        texture_ids.push(white);
+        texture_ids.push(flat_normal);
+
         // Load materials
         let materials = gltf
             .materials()
             .map(|material| {
                 let pbr = material.pbr_metallic_roughness();
-                let texture_index = pbr.base_color_texture().unwrap().texture().index();
+                let texture_or = |index: Option<usize>, default: GLuint| {
+                    index.map_or(default, |index| textures[index])
+                };
                 Material {
                     base_color_factor: Vec4::from(pbr.base_color_factor()),
-                    base_color_texture: textures[texture_index],
+                    base_color_texture: texture_or(
+                        pbr.base_color_texture().map(|info| info.texture().index()),
+                        white,
+                    ),
+                    metallic_factor: pbr.metallic_factor(),
+                    roughness_factor: pbr.roughness_factor(),
+                    metallic_roughness_texture: texture_or(
+                        pbr.metallic_roughness_texture()
+                            .map(|info| info.texture().index()),
+                        white,
+                    ),
+                    normal_texture: texture_or(
+                        material.normal_texture().map(|info| info.texture().index()),
+                        flat_normal,
+                    ),
+                    normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+                    occlusion_texture: texture_or(
+                        material
+                            .occlusion_texture()
+                            .map(|info| info.texture().index()),
+                        white,
+                    ),
+                    occlusion_strength: material
+                        .occlusion_texture()
+                        .map_or(1.0, |info| info.strength()),
+                    emissive_factor: Vec3::from(material.emissive_factor()),
+                    emissive_texture: texture_or(
+                        material
+                            .emissive_texture()
+                            .map(|info| info.texture().index()),
+                        white,
+                    ),
+                    blend: material.alpha_mode() == AlphaMode::Blend,
                 }
             })
             .collect::<Vec<_>>();
@@ -311,8 +417,17 @@ impl Model {
 
             drawable_nodes,
             materials,
+            bounds,
         })
     }
+
+    /// Whether any primitive is drawn in the transparent pass if `blend`, or the opaque one if not
+    pub fn has_primitives(&self, blend: bool) -> bool {
+        self.drawable_nodes
+            .iter()
+            .flat_map(|node| &node.primitives)
+            .any(|primitive| self.materials[primitive.material_index].blend == blend)
+    }
 }
 
 impl Drop for Model {
@@ -331,13 +446,120 @@ impl Drop for Model {
 pub struct Vertex {
     pos: Vec3,
     normal: Vec3,
+    tangent: Vec4,
     uv: Vec2,
 }
 
+/// Triangles of one primitive, for generating the tangents it doesn't have
+struct TangentGeometry<'a> {
+    vertices: &'a mut [Vertex],
+    indices: &'a [u32],
+    /// Subtracted from the indices to index `vertices`
+    vertex_start: u32,
+}
+
+impl TangentGeometry<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> usize {
+        (self.indices[face * 3 + vert] - self.vertex_start) as usize
+    }
+}
+
+impl mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertices[self.vertex(face, vert)].pos.to_array()
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertices[self.vertex(face, vert)].normal.to_array()
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.vertices[self.vertex(face, vert)].uv.to_array()
+    }
+
+    // Vertices shared between faces keep the last tangent, which is fine for smooth meshes
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        let vertex = self.vertex(face, vert);
+        self.vertices[vertex].tangent = Vec4::from(tangent);
+    }
+}
+
+/// glTF metallic-roughness material. Missing textures are replaced with neutral ones.
 #[derive(Debug)]
 pub struct Material {
     pub base_color_factor: Vec4,
     pub base_color_texture: GLuint,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    /// Roughness in G, metalness in B
+    pub metallic_roughness_texture: GLuint,
+    pub normal_texture: GLuint,
+    pub normal_scale: f32,
+    /// Only the R channel is used
+    pub occlusion_texture: GLuint,
+    pub occlusion_strength: f32,
+    pub emissive_factor: Vec3,
+    pub emissive_texture: GLuint,
+    /// Blended by the base colour alpha in the transparent pass, opaque otherwise
+    pub blend: bool,
+}
+
+impl Material {
+    /// Binds the textures to units 0-4 and sets the factors in the model shader
+    pub fn bind(&self, shader: &Program) -> Result<()> {
+        let textures = [
+            self.base_color_texture,
+            self.metallic_roughness_texture,
+            self.normal_texture,
+            self.occlusion_texture,
+            self.emissive_texture,
+        ];
+        unsafe {
+            for (unit, &texture) in textures.iter().enumerate() {
+                gl::ActiveTexture(unit_to_gl_const(unit as i32));
+                gl::BindTexture(gl::TEXTURE_2D, texture);
+            }
+        }
+        shader.set_vec4("base_color_factor", &self.base_color_factor)?;
+        shader.set_f32("metallic_factor", self.metallic_factor)?;
+        shader.set_f32("roughness_factor", self.roughness_factor)?;
+        shader.set_f32("normal_scale", self.normal_scale)?;
+        shader.set_f32("occlusion_strength", self.occlusion_strength)?;
+        shader.set_vec3("emissive_factor", &self.emissive_factor)?;
+        shader.set_i32("alpha_blend", self.blend as i32)?;
+        Ok(())
+    }
+}
+
+/// 1x1 linear RGB texture
+fn create_pixel_texture(color: [u8; 3]) -> GLuint {
+    let mut texture: GLuint = 0;
+    unsafe {
+        gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture);
+        gl::TextureStorage2D(texture, 1, gl::RGB8, 1, 1);
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+        gl::TextureSubImage2D(
+            texture,
+            0,
+            0,
+            0,
+            1,
+            1,
+            gl::RGB,
+            gl::UNSIGNED_BYTE,
+            color.as_ptr() as *const _,
+        );
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+    }
+    texture
 }
 
 #[derive(Debug)]
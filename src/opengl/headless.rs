@@ -0,0 +1,70 @@
+use glutin::dpi::PhysicalSize;
+use glutin::{Api, Context, ContextBuilder, GlProfile, GlRequest, NotCurrent, PossiblyCurrent};
+
+use crate::Result;
+
+/// A current GL 4.5 context without a window, everything is drawn into offscreen targets
+pub struct HeadlessContext {
+    _context: Context<PossiblyCurrent>,
+    _keep_alive: KeepAlive,
+}
+
+impl HeadlessContext {
+    pub fn new() -> Result<Self> {
+        let builder = ContextBuilder::new()
+            .with_gl(GlRequest::Specific(Api::OpenGl, (4, 5)))
+            .with_gl_profile(GlProfile::Core);
+        let (context, keep_alive) = build(builder)?;
+
+        let context = unsafe { context.make_current().map_err(|(_, error)| error)? };
+        gl::load_with(|s| context.get_proc_address(s) as *const _);
+        unsafe {
+            gl::ClearColor(0.05, 0.05, 0.05, 1.0);
+
+            gl::Enable(gl::DEBUG_OUTPUT);
+            gl::DebugMessageCallback(Some(super::debug_callback), std::ptr::null());
+        }
+
+        Ok(HeadlessContext {
+            _context: context,
+            _keep_alive: keep_alive,
+        })
+    }
+}
+
+/// The context's own framebuffer is never drawn into
+const SIZE: PhysicalSize<u32> = PhysicalSize {
+    width: 1,
+    height: 1,
+};
+
+/// The event loop of a surfaceless context, OSMesa doesn't have one
+#[cfg(target_os = "linux")]
+type KeepAlive = Option<glutin::event_loop::EventLoop<()>>;
+
+/// Surfaceless EGL renders on the GPU, but glutin only opens it through an X11 connection.
+/// Without an X display, e.g. on CI, OSMesa renders on the CPU with Mesa's llvmpipe instead.
+#[cfg(target_os = "linux")]
+fn build(builder: ContextBuilder<NotCurrent>) -> Result<(Context<NotCurrent>, KeepAlive)> {
+    use glutin::event_loop::EventLoop;
+    use glutin::platform::unix::{EventLoopExtUnix, HeadlessContextExt};
+
+    // Tests create contexts on their own threads
+    if let Ok(event_loop) = EventLoop::new_x11_any_thread() {
+        if let Ok(context) = builder.clone().build_surfaceless(&event_loop) {
+            return Ok((context, Some(event_loop)));
+        }
+    }
+    Ok((builder.build_osmesa(SIZE)?, None))
+}
+
+/// Elsewhere headless contexts still come from the event loop
+#[cfg(not(target_os = "linux"))]
+type KeepAlive = glutin::event_loop::EventLoop<()>;
+
+#[cfg(not(target_os = "linux"))]
+fn build(builder: ContextBuilder<NotCurrent>) -> Result<(Context<NotCurrent>, KeepAlive)> {
+    let event_loop = glutin::event_loop::EventLoop::new();
+    let context = builder.build_headless(&event_loop, SIZE)?;
+    Ok((context, event_loop))
+}
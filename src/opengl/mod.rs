@@ -5,6 +5,7 @@ use std::ffi::CStr;
 
 use gl::types::*;
 
+pub mod headless;
 pub mod shader;
 
 pub fn gl_check_error(file: &str, line: u32) {
@@ -4,7 +4,7 @@ use std::io;
 
 use gl::types::*;
 use glam::Vec2;
-use glam::{Mat4, Vec3};
+use glam::{Mat2, Mat4, Vec3, Vec4};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -21,6 +21,20 @@ pub enum ShaderError {
 
 pub type Result<T> = std::result::Result<T, ShaderError>;
 
+/// Puts shared GLSL right after the #version line of `code`, like an #include would
+pub fn with_includes(code: &str, includes: &[&str]) -> String {
+    let (version, rest) = code.split_once('\n').unwrap_or((code, ""));
+    let mut source = format!("{}\n", version);
+    for include in includes {
+        source.push_str(include);
+        source.push('\n');
+    }
+    // Errors in the rest of the shader keep their line numbers
+    source.push_str("#line 2\n");
+    source.push_str(rest);
+    source
+}
+
 pub struct Program {
     id: GLuint,
 }
@@ -146,6 +160,14 @@ impl Program {
         Ok(())
     }
 
+    pub fn set_vec4(&self, name: &str, vec: &Vec4) -> Result<()> {
+        let location = self.get_uniform_location(name)?;
+        unsafe {
+            gl::Uniform4fv(location, 1, vec.to_array().as_ptr());
+        }
+        Ok(())
+    }
+
     pub fn set_float3(&self, name: &str, vec: &[f32]) -> Result<()> {
         let location = self.get_uniform_location(name)?;
         unsafe {
@@ -154,6 +176,14 @@ impl Program {
         Ok(())
     }
 
+    pub fn set_mat2(&self, name: &str, mat: &Mat2) -> Result<()> {
+        let location = self.get_uniform_location(name)?;
+        unsafe {
+            gl::UniformMatrix2fv(location, 1, gl::FALSE, mat.to_cols_array().as_ptr());
+        }
+        Ok(())
+    }
+
     pub fn set_mat4(&self, name: &str, mat: &Mat4) -> Result<()> {
         let location = self.get_uniform_location(name)?;
         unsafe {
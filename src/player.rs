@@ -0,0 +1,187 @@
+use glam::{Vec2, Vec3, Vec3Swizzles};
+
+use crate::heightfield::Heightfield;
+use crate::ray::AABB;
+
+const HEIGHT: f32 = 1.8;
+const EYE_HEIGHT: f32 = 1.7;
+const RADIUS: f32 = 0.4;
+/// Obstacles lower than this are stepped onto rather than blocking
+const STEP_HEIGHT: f32 = 0.3;
+
+const WALK_SPEED: f32 = 4.0;
+const RUN_SPEED: f32 = 8.0;
+const JUMP_SPEED: f32 = 5.0;
+const GRAVITY: f32 = 9.81;
+/// Degrees. Steeper slopes can't be walked up and make the player slide down.
+const MAX_SLOPE: f32 = 40.0;
+const SLIDE_SPEED: f32 = 6.0;
+
+/// CPU copy of the terrain heights to walk on
+pub struct Ground {
+    heightfield: Heightfield,
+    min: Vec2,
+    size: f32,
+    max_height: f32,
+}
+
+impl Ground {
+    /// `min` is the corner of the terrain with the lowest x and z
+    pub fn new(heightfield: Heightfield, min: Vec2, size: f32, max_height: f32) -> Self {
+        Ground {
+            heightfield,
+            min,
+            size,
+            max_height,
+        }
+    }
+
+    fn uv(&self, position: Vec2) -> Vec2 {
+        ((position - self.min) / self.size).clamp(Vec2::ZERO, Vec2::ONE)
+    }
+
+    pub fn height_at(&self, position: Vec2) -> f32 {
+        let uv = self.uv(position);
+        self.heightfield.sample(uv.x, uv.y) * self.max_height
+    }
+
+    pub fn normal_at(&self, position: Vec2) -> Vec3 {
+        let texel = self.uv(position) * (self.heightfield.size - 1) as f32;
+        self.heightfield.normal(
+            texel.x.round() as usize,
+            texel.y.round() as usize,
+            self.size,
+            self.max_height,
+        )
+    }
+
+    fn is_too_steep(&self, position: Vec2) -> bool {
+        self.normal_at(position).y < MAX_SLOPE.to_radians().cos()
+    }
+}
+
+/// A capsule walking on the terrain
+pub struct Player {
+    /// Bottom of the capsule
+    pub position: Vec3,
+    velocity: Vec3,
+    on_ground: bool,
+}
+
+impl Player {
+    pub fn new(position: Vec3) -> Self {
+        Player {
+            position,
+            velocity: Vec3::ZERO,
+            on_ground: false,
+        }
+    }
+
+    pub fn eye(&self) -> Vec3 {
+        self.position + Vec3::new(0.0, EYE_HEIGHT, 0.0)
+    }
+
+    /// `direction` is where the player wants to go in the xz plane, not normalised
+    pub fn update(
+        &mut self,
+        direction: Vec2,
+        run: bool,
+        jump: bool,
+        delta_time: f32,
+        ground: &Ground,
+        obstacles: &[AABB],
+    ) {
+        // Steer on the ground, keep momentum in the air
+        if self.on_ground {
+            let speed = if run { RUN_SPEED } else { WALK_SPEED };
+            let horizontal = direction.normalize_or_zero() * speed;
+            self.velocity.x = horizontal.x;
+            self.velocity.z = horizontal.y;
+
+            let position = self.position.xz();
+            if ground.is_too_steep(position) {
+                let downhill = ground.normal_at(position).xz().normalize_or_zero();
+                self.velocity.x += downhill.x * SLIDE_SPEED;
+                self.velocity.z += downhill.y * SLIDE_SPEED;
+            } else if jump {
+                self.velocity.y = JUMP_SPEED;
+                self.on_ground = false;
+            }
+        }
+        self.velocity.y -= GRAVITY * delta_time;
+
+        // Horizontal movement, refusing to walk up slopes that are too steep
+        let old_position = self.position;
+        let step = self.velocity.xz() * delta_time;
+        for step in [step, Vec2::new(step.x, 0.0), Vec2::new(0.0, step.y)] {
+            let target = self.position.xz() + step;
+            let uphill = ground.height_at(target) > ground.height_at(self.position.xz());
+            if !(self.on_ground && uphill && ground.is_too_steep(target)) {
+                self.position.x = target.x;
+                self.position.z = target.y;
+                break;
+            }
+        }
+        self.push_out_of(obstacles, old_position.y);
+
+        // Vertical movement
+        self.position.y += self.velocity.y * delta_time;
+        let floor = self.floor_height(ground, obstacles, old_position.y);
+        let snap = if self.on_ground { STEP_HEIGHT } else { 0.0 };
+        if self.velocity.y <= 0.0 && self.position.y <= floor + snap {
+            self.position.y = floor;
+            self.velocity.y = 0.0;
+            self.on_ground = true;
+        } else {
+            self.on_ground = false;
+        }
+    }
+
+    /// Terrain or the top of an obstacle the player is standing on
+    fn floor_height(&self, ground: &Ground, obstacles: &[AABB], feet: f32) -> f32 {
+        obstacles
+            .iter()
+            .filter(|aabb| self.overlaps_xz(aabb) && aabb.max.y <= feet + STEP_HEIGHT)
+            .map(|aabb| aabb.max.y)
+            .fold(ground.height_at(self.position.xz()), f32::max)
+    }
+
+    fn overlaps_xz(&self, aabb: &AABB) -> bool {
+        let position = self.position.xz();
+        let closest = position.clamp(aabb.min.xz(), aabb.max.xz());
+        position.distance(closest) < RADIUS
+    }
+
+    /// Moves the capsule sideways out of the obstacles it can't step onto
+    fn push_out_of(&mut self, obstacles: &[AABB], feet: f32) {
+        for aabb in obstacles {
+            let blocks = aabb.max.y > feet + STEP_HEIGHT && aabb.min.y < feet + HEIGHT;
+            if !blocks || !self.overlaps_xz(aabb) {
+                continue;
+            }
+
+            let position = self.position.xz();
+            let closest = position.clamp(aabb.min.xz(), aabb.max.xz());
+            let offset = position - closest;
+            let pushed = if offset.length() > 0.0 {
+                closest + offset.normalize() * RADIUS
+            } else {
+                // Center is inside the box, leave through the nearest side
+                let to_min = position - aabb.min.xz();
+                let to_max = aabb.max.xz() - position;
+                let nearest = to_min.min(to_max).min_element();
+                if nearest == to_min.x {
+                    Vec2::new(aabb.min.x - RADIUS, position.y)
+                } else if nearest == to_max.x {
+                    Vec2::new(aabb.max.x + RADIUS, position.y)
+                } else if nearest == to_min.y {
+                    Vec2::new(position.x, aabb.min.z - RADIUS)
+                } else {
+                    Vec2::new(position.x, aabb.max.z + RADIUS)
+                }
+            };
+            self.position.x = pushed.x;
+            self.position.z = pushed.y;
+        }
+    }
+}
@@ -0,0 +1,440 @@
+use gl::types::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::GraphicsSettings;
+use crate::opengl::shader::Program;
+use crate::renderer::{Blend, GlState, RenderState};
+use crate::texture::unit_to_gl_const;
+use crate::Result;
+
+/// Number of bloom mips, the first one is half the screen size
+const BLOOM_LEVELS: usize = 6;
+
+/// How HDR colours are brought into the displayable range
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapper {
+    Reinhard,
+    Aces,
+    Agx,
+}
+
+impl ToneMapper {
+    pub const ALL: [ToneMapper; 3] = [ToneMapper::Reinhard, ToneMapper::Aces, ToneMapper::Agx];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMapper::Reinhard => "Reinhard",
+            ToneMapper::Aces => "ACES",
+            ToneMapper::Agx => "AgX",
+        }
+    }
+
+    /// Must match the TONE_MAPPER_* constants in resolve.frag
+    fn to_gl(self) -> i32 {
+        match self {
+            ToneMapper::Reinhard => 0,
+            ToneMapper::Aces => 1,
+            ToneMapper::Agx => 2,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct PostProcessSettings {
+    /// Stops, every one doubles the brightness
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    pub bloom: bool,
+    /// Brightness above which colours start to glow
+    pub bloom_threshold: f32,
+    /// How much of the glow is added to the scene
+    pub bloom_intensity: f32,
+}
+
+impl Default for PostProcessSettings {
+    fn default() -> Self {
+        PostProcessSettings {
+            exposure: 0.0,
+            tone_mapper: ToneMapper::Aces,
+            bloom: true,
+            bloom_threshold: 1.0,
+            bloom_intensity: 0.05,
+        }
+    }
+}
+
+/// Offscreen HDR target the scene is drawn into, then resolved to the window
+pub struct PostProcess {
+    pub settings: PostProcessSettings,
+
+    width: i32,
+    height: i32,
+
+    scene_fbo: GLuint,
+    scene_texture: GLuint,
+    depth_renderbuffer: GLuint,
+    /// Drawn into instead of the scene framebuffer and resolved into it when MSAA is on
+    msaa: Option<Multisampled>,
+
+    /// Tone mapped colours for FXAA
+    ldr_fbo: GLuint,
+    ldr_texture: GLuint,
+    fxaa: bool,
+
+    /// Each level is half the size of the previous one
+    bloom_textures: [GLuint; BLOOM_LEVELS],
+    bloom_fbo: GLuint,
+
+    /// Fullscreen triangles are generated from gl_VertexID but a VAO must still be bound
+    vao: GLuint,
+    downsample_shader: Program,
+    upsample_shader: Program,
+    resolve_shader: Program,
+    fxaa_shader: Program,
+}
+
+/// Multisampled colour and depth
+struct Multisampled {
+    samples: i32,
+    fbo: GLuint,
+    color_renderbuffer: GLuint,
+    depth_renderbuffer: GLuint,
+}
+
+impl Multisampled {
+    fn new(samples: i32, width: i32, height: i32) -> Self {
+        let mut fbo: GLuint = 0;
+        let mut renderbuffers = [0; 2];
+        unsafe {
+            gl::CreateRenderbuffers(2, renderbuffers.as_mut_ptr());
+            let [color_renderbuffer, depth_renderbuffer] = renderbuffers;
+            gl::NamedRenderbufferStorageMultisample(
+                color_renderbuffer,
+                samples,
+                gl::RGBA16F,
+                width,
+                height,
+            );
+            gl::NamedRenderbufferStorageMultisample(
+                depth_renderbuffer,
+                samples,
+                gl::DEPTH_COMPONENT32F,
+                width,
+                height,
+            );
+
+            gl::CreateFramebuffers(1, &mut fbo);
+            gl::NamedFramebufferRenderbuffer(
+                fbo,
+                gl::COLOR_ATTACHMENT0,
+                gl::RENDERBUFFER,
+                color_renderbuffer,
+            );
+            gl::NamedFramebufferRenderbuffer(
+                fbo,
+                gl::DEPTH_ATTACHMENT,
+                gl::RENDERBUFFER,
+                depth_renderbuffer,
+            );
+            assert_eq!(
+                gl::CheckNamedFramebufferStatus(fbo, gl::FRAMEBUFFER),
+                gl::FRAMEBUFFER_COMPLETE,
+                "Multisampled scene framebuffer is incomplete",
+            );
+        }
+        Multisampled {
+            samples,
+            fbo,
+            color_renderbuffer: renderbuffers[0],
+            depth_renderbuffer: renderbuffers[1],
+        }
+    }
+}
+
+impl Drop for Multisampled {
+    fn drop(&mut self) {
+        let renderbuffers = [self.color_renderbuffer, self.depth_renderbuffer];
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.fbo);
+            gl::DeleteRenderbuffers(2, renderbuffers.as_ptr());
+        }
+    }
+}
+
+impl PostProcess {
+    pub fn new(
+        settings: PostProcessSettings,
+        graphics: &GraphicsSettings,
+        width: i32,
+        height: i32,
+    ) -> Result<Self> {
+        let downsample_shader = Program::new()
+            .vertex_shader(include_str!("shaders/fullscreen.vert"))?
+            .fragment_shader(include_str!("shaders/post/downsample.frag"))?
+            .link()?;
+        let upsample_shader = Program::new()
+            .vertex_shader(include_str!("shaders/fullscreen.vert"))?
+            .fragment_shader(include_str!("shaders/post/upsample.frag"))?
+            .link()?;
+        let resolve_shader = Program::new()
+            .vertex_shader(include_str!("shaders/fullscreen.vert"))?
+            .fragment_shader(include_str!("shaders/post/resolve.frag"))?
+            .link()?;
+        let fxaa_shader = Program::new()
+            .vertex_shader(include_str!("shaders/fullscreen.vert"))?
+            .fragment_shader(include_str!("shaders/post/fxaa.frag"))?
+            .link()?;
+
+        let scene_texture = create_hdr_texture(width, height);
+        let mut bloom_textures = [0; BLOOM_LEVELS];
+        for (level, texture) in bloom_textures.iter_mut().enumerate() {
+            let (w, h) = mip_size(width, height, level + 1);
+            *texture = create_hdr_texture(w, h);
+        }
+
+        let mut scene_fbo: GLuint = 0;
+        let mut depth_renderbuffer: GLuint = 0;
+        let mut bloom_fbo: GLuint = 0;
+        let mut ldr_fbo: GLuint = 0;
+        let mut ldr_texture: GLuint = 0;
+        let mut vao: GLuint = 0;
+        unsafe {
+            gl::CreateRenderbuffers(1, &mut depth_renderbuffer);
+            gl::NamedRenderbufferStorage(depth_renderbuffer, gl::DEPTH_COMPONENT32F, width, height);
+
+            gl::CreateFramebuffers(1, &mut scene_fbo);
+            gl::NamedFramebufferTexture(scene_fbo, gl::COLOR_ATTACHMENT0, scene_texture, 0);
+            gl::NamedFramebufferRenderbuffer(
+                scene_fbo,
+                gl::DEPTH_ATTACHMENT,
+                gl::RENDERBUFFER,
+                depth_renderbuffer,
+            );
+            assert_eq!(
+                gl::CheckNamedFramebufferStatus(scene_fbo, gl::FRAMEBUFFER),
+                gl::FRAMEBUFFER_COMPLETE,
+                "HDR scene framebuffer is incomplete",
+            );
+
+            gl::CreateTextures(gl::TEXTURE_2D, 1, &mut ldr_texture);
+            gl::TextureParameteri(ldr_texture, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+            gl::TextureParameteri(ldr_texture, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            gl::TextureParameteri(ldr_texture, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+            gl::TextureParameteri(ldr_texture, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+            // sRGB keeps the precision in the darks, FXAA reads it back as linear
+            gl::TextureStorage2D(ldr_texture, 1, gl::SRGB8_ALPHA8, width, height);
+            gl::CreateFramebuffers(1, &mut ldr_fbo);
+            gl::NamedFramebufferTexture(ldr_fbo, gl::COLOR_ATTACHMENT0, ldr_texture, 0);
+
+            // Gets a bloom level attached before drawing into it
+            gl::CreateFramebuffers(1, &mut bloom_fbo);
+
+            gl::CreateVertexArrays(1, &mut vao);
+        }
+
+        let mut post_process = PostProcess {
+            settings,
+
+            width,
+            height,
+
+            scene_fbo,
+            scene_texture,
+            depth_renderbuffer,
+            msaa: None,
+
+            ldr_fbo,
+            ldr_texture,
+            fxaa: false,
+
+            bloom_textures,
+            bloom_fbo,
+
+            vao,
+            downsample_shader,
+            upsample_shader,
+            resolve_shader,
+            fxaa_shader,
+        };
+        post_process.apply_graphics_settings(graphics);
+
+        Ok(post_process)
+    }
+
+    /// Recreates the multisampled target if the number of samples has changed
+    pub fn apply_graphics_settings(&mut self, graphics: &GraphicsSettings) {
+        self.fxaa = graphics.fxaa;
+
+        let mut max_samples = 1;
+        unsafe {
+            gl::GetIntegerv(gl::MAX_SAMPLES, &mut max_samples);
+        }
+        let samples = graphics.msaa_samples.clamp(1, max_samples);
+        let current = self.msaa.as_ref().map_or(1, |msaa| msaa.samples);
+        if samples == current {
+            return;
+        }
+        self.msaa = if samples > 1 {
+            Some(Multisampled::new(samples, self.width, self.height))
+        } else {
+            None
+        };
+    }
+
+    /// Binds and clears the HDR target, everything drawn until `resolve` goes into it
+    pub fn begin(&self, gl: &mut GlState) {
+        let fbo = self.msaa.as_ref().map_or(self.scene_fbo, |msaa| msaa.fbo);
+        gl.bind_target(fbo, self.width, self.height);
+        unsafe {
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+    }
+
+    /// Applies bloom and tone mapping and writes the result to the window
+    pub fn resolve(&self, gl: &mut GlState) -> Result<()> {
+        if let Some(msaa) = &self.msaa {
+            unsafe {
+                gl::BlitNamedFramebuffer(
+                    msaa.fbo,
+                    self.scene_fbo,
+                    0,
+                    0,
+                    self.width,
+                    self.height,
+                    0,
+                    0,
+                    self.width,
+                    self.height,
+                    gl::COLOR_BUFFER_BIT,
+                    gl::NEAREST,
+                );
+            }
+        }
+        gl.set(RenderState::FULLSCREEN);
+        unsafe {
+            gl::BindVertexArray(self.vao);
+        }
+        if self.settings.bloom {
+            self.draw_bloom(gl)?;
+        }
+
+        self.resolve_shader.set_used();
+        self.resolve_shader
+            .set_f32("exposure", self.settings.exposure.exp2())?;
+        self.resolve_shader
+            .set_i32("tone_mapper", self.settings.tone_mapper.to_gl())?;
+        // Zero intensity skips the bloom texture
+        let bloom_intensity = if self.settings.bloom {
+            self.settings.bloom_intensity
+        } else {
+            0.0
+        };
+        self.resolve_shader
+            .set_f32("bloom_intensity", bloom_intensity)?;
+        // FXAA needs the tone mapped image as a texture
+        if self.fxaa {
+            gl.bind_target(self.ldr_fbo, self.width, self.height);
+        } else {
+            gl.bind_output();
+        }
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(0));
+            gl::BindTexture(gl::TEXTURE_2D, self.scene_texture);
+            gl::ActiveTexture(unit_to_gl_const(1));
+            gl::BindTexture(gl::TEXTURE_2D, self.bloom_textures[0]);
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+        }
+
+        if self.fxaa {
+            self.fxaa_shader.set_used();
+            gl.bind_output();
+            unsafe {
+                gl::ActiveTexture(unit_to_gl_const(0));
+                gl::BindTexture(gl::TEXTURE_2D, self.ldr_texture);
+                gl::DrawArrays(gl::TRIANGLES, 0, 3);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Downsamples the bright parts of the scene into the bloom levels, then blurs them back up
+    fn draw_bloom(&self, gl: &mut GlState) -> Result<()> {
+        self.downsample_shader.set_used();
+        self.downsample_shader
+            .set_f32("threshold", self.settings.bloom_threshold)?;
+        let mut source = self.scene_texture;
+        for (level, &target) in self.bloom_textures.iter().enumerate() {
+            // Only the first pass cuts off the dim colours
+            self.downsample_shader
+                .set_i32("prefilter", (level == 0) as i32)?;
+            self.draw_into_bloom_level(gl, level, source);
+            source = target;
+        }
+
+        self.upsample_shader.set_used();
+        // Each level adds its blurred version of the smaller one on top of itself
+        gl.set(RenderState {
+            blend: Blend::Add,
+            ..RenderState::FULLSCREEN
+        });
+        for level in (0..BLOOM_LEVELS - 1).rev() {
+            self.draw_into_bloom_level(gl, level, self.bloom_textures[level + 1]);
+        }
+        gl.set(RenderState::FULLSCREEN);
+
+        Ok(())
+    }
+
+    fn draw_into_bloom_level(&self, gl: &mut GlState, level: usize, source: GLuint) {
+        let (width, height) = mip_size(self.width, self.height, level + 1);
+        unsafe {
+            gl::NamedFramebufferTexture(
+                self.bloom_fbo,
+                gl::COLOR_ATTACHMENT0,
+                self.bloom_textures[level],
+                0,
+            );
+        }
+        gl.bind_target(self.bloom_fbo, width, height);
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(0));
+            gl::BindTexture(gl::TEXTURE_2D, source);
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+        }
+    }
+}
+
+impl Drop for PostProcess {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.scene_fbo);
+            gl::DeleteFramebuffers(1, &self.bloom_fbo);
+            gl::DeleteFramebuffers(1, &self.ldr_fbo);
+            gl::DeleteTextures(1, &self.scene_texture);
+            gl::DeleteTextures(1, &self.ldr_texture);
+            gl::DeleteTextures(BLOOM_LEVELS as i32, self.bloom_textures.as_ptr());
+            gl::DeleteRenderbuffers(1, &self.depth_renderbuffer);
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
+
+fn mip_size(width: i32, height: i32, level: usize) -> (i32, i32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// Filtered so that the bloom passes can sample between texels
+fn create_hdr_texture(width: i32, height: i32) -> GLuint {
+    let mut texture: GLuint = 0;
+    unsafe {
+        gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture);
+        gl::TextureParameteri(texture, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        gl::TextureParameteri(texture, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TextureParameteri(texture, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+        gl::TextureParameteri(texture, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+        gl::TextureStorage2D(texture, 1, gl::RGBA16F, width, height);
+    }
+    texture
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::fog::Fog;
+use crate::layers::{layers_dir, LayerInfo};
+use crate::light::DirectionalLight;
+use crate::postprocess::PostProcessSettings;
+use crate::skybox::SkySettings;
+use crate::splatmap::{default_material_rules, load_material_rules, MaterialRule};
+use crate::ssao::AmbientOcclusion;
+use crate::terrain::TerrainSettings;
+use crate::Result;
+
+/// Bump when the manifest changes in a way older versions can't read
+pub const PROJECT_VERSION: u32 = 1;
+
+const MANIFEST_FILE_NAME: &str = "project.json";
+const HEIGHTMAP_FILE_NAME: &str = "heightmap.png";
+
+#[derive(Debug, Error)]
+pub enum ProjectError {
+    #[error("{0:?} is not a project directory (no project.json)")]
+    NotAProject(PathBuf),
+    #[error("Project version {0} is newer than this editor supports")]
+    UnsupportedVersion(u32),
+}
+
+/// A game object placed in the level
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ObjectInfo {
+    pub model_path: String,
+    pub position: Vec3,
+    pub orientation: Quat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CameraBookmark {
+    pub name: String,
+    pub position: Vec3,
+    pub direction: Vec3,
+}
+
+/// Describes a project directory.
+/// The terrain maps are stored next to it, see `heightmap_path`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub version: u32,
+    #[serde(default)]
+    pub terrain: TerrainSettings,
+    #[serde(default = "default_material_rules")]
+    pub material_rules: Vec<MaterialRule>,
+    #[serde(default)]
+    pub layers: Vec<LayerInfo>,
+    #[serde(default = "default_objects")]
+    pub objects: Vec<ObjectInfo>,
+    /// Directory with six cube map faces, or an equirectangular image
+    pub skybox: String,
+    #[serde(default)]
+    pub sky: SkySettings,
+    pub light: DirectionalLight,
+    #[serde(default)]
+    pub fog: Fog,
+    #[serde(default)]
+    pub ambient_occlusion: AmbientOcclusion,
+    #[serde(default)]
+    pub post_process: PostProcessSettings,
+    #[serde(default)]
+    pub camera_bookmarks: Vec<CameraBookmark>,
+}
+
+/// A new project with flat terrain
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: PROJECT_VERSION,
+            terrain: TerrainSettings::default(),
+            material_rules: default_material_rules(),
+            layers: vec![],
+            objects: default_objects(),
+            skybox: "textures/skybox/default".to_owned(),
+            sky: SkySettings::default(),
+            light: DirectionalLight::default(),
+            fog: Fog::default(),
+            ambient_occlusion: AmbientOcclusion::default(),
+            post_process: PostProcessSettings::default(),
+            camera_bookmarks: vec![],
+        }
+    }
+}
+
+impl Manifest {
+    /// What the editor shows when no project is open
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Manifest {
+            terrain: config.terrain,
+            material_rules: load_material_rules(&layers_dir(&config.heightmap_path))?,
+            layers: config.layers.clone(),
+            ..Manifest::default()
+        })
+    }
+
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE_NAME);
+        if !path.exists() {
+            return Err(ProjectError::NotAProject(dir.to_owned()).into());
+        }
+        let manifest: Manifest = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if manifest.version > PROJECT_VERSION {
+            return Err(ProjectError::UnsupportedVersion(manifest.version).into());
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let string = serde_json::to_string_pretty(self)?;
+        fs::write(dir.join(MANIFEST_FILE_NAME), string)?;
+        Ok(())
+    }
+}
+
+/// Where the project keeps its terrain heightmap.
+/// Layers and the sculpt mask are saved in `layers::layers_dir` of this path.
+pub fn heightmap_path(dir: &Path) -> String {
+    dir.join(HEIGHTMAP_FILE_NAME).to_string_lossy().into_owned()
+}
+
+fn default_objects() -> Vec<ObjectInfo> {
+    let object = |model_path: &str, position: Vec3| ObjectInfo {
+        model_path: model_path.to_owned(),
+        position,
+        orientation: Quat::default(),
+    };
+    vec![
+        object("models/viking_room/scene.gltf", Vec3::new(0.0, 0.0, 0.0)),
+        object("models/box/box.gltf", Vec3::new(100.0, 100.0, 0.0)),
+        object("models/box/box.gltf", Vec3::new(-100.0, 100.0, 0.0)),
+    ]
+}
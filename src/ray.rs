@@ -1,6 +1,6 @@
 use std::ops::Index;
 
-use glam::Vec3;
+use glam::{Mat4, Vec3};
 
 const EPSILON: f32 = 0.00001;
 
@@ -139,6 +139,22 @@ impl AABB {
         }
     }
 
+    /// Grows the box to include `p`
+    pub fn extend(&mut self, p: Vec3) {
+        self.min = self.min.min(p);
+        self.max = self.max.max(p);
+    }
+
+    /// Bounding box of this box after a transform
+    pub fn transformed(&self, transform: &Mat4) -> AABB {
+        let mut aabb = AABB::empty();
+        for i in 0..8 {
+            let corner = Vec3::new(self[i & 1].x, self[(i >> 1) & 1].y, self[(i >> 2) & 1].z);
+            aabb.extend(transform.transform_point3(corner));
+        }
+        aabb
+    }
+
     pub fn contains(&self, p: &Vec3) -> bool {
         p.x >= self.min.x
             && p.x <= self.max.x
@@ -0,0 +1,774 @@
+use std::cmp::Ordering;
+use std::mem::{self, size_of};
+
+use gl::types::*;
+use glam::Mat4;
+use image::RgbImage;
+use thiserror::Error;
+
+use crate::camera::Camera;
+use crate::config::GraphicsSettings;
+use crate::fog::{Fog, FogBuffer, FOG_GLSL};
+use crate::light::{DirectionalLight, LightBuffer};
+use crate::model::Model;
+use crate::opengl::shader::{with_includes, Program};
+use crate::postprocess::{PostProcess, PostProcessSettings};
+use crate::shadows::{NUM_CASCADES, SHADOWS_GLSL, SHADOW_BLOCK_GLSL, SHADOW_MAP_UNIT};
+use crate::skybox::{SkySettings, Skybox};
+use crate::ssao::{AmbientOcclusion, Ssao};
+use crate::terrain::Terrain;
+use crate::texture::unit_to_gl_const;
+use crate::Result;
+
+/// Parts of a frame, in the order they are drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pass {
+    /// Depth from the sun, every shadow cascade binds its own target
+    Shadow,
+    /// Depth and normals for ambient occlusion, only drawn into when it's enabled
+    Prepass,
+    /// Lit opaque geometry, into the HDR scene target
+    Opaque,
+    /// Drawn at the far plane where nothing else was
+    Sky,
+    /// Blended over the opaque scene without writing depth
+    Transparent,
+    /// The scene is resolved to the window and the GUI is drawn on top
+    Ui,
+}
+
+/// Something to draw, submitted by whoever owns it to each pass it appears in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrawItem {
+    Terrain,
+    /// Index into `Scene::objects`
+    Object(usize),
+    Sky,
+}
+
+/// What each pass draws this frame
+#[derive(Default)]
+pub struct DrawList {
+    items: Vec<(Pass, DrawItem)>,
+}
+
+impl DrawList {
+    /// Items of a pass are drawn in the order they are submitted
+    pub fn submit(&mut self, pass: Pass, item: DrawItem) {
+        self.items.push((pass, item));
+    }
+
+    fn items(&self, pass: Pass) -> impl Iterator<Item = DrawItem> + '_ {
+        self.items
+            .iter()
+            .filter(move |(item_pass, _)| *item_pass == pass)
+            .map(|(_, item)| *item)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blend {
+    Off,
+    Add,
+    /// Subtracts the source from the destination
+    ReverseSubtract,
+    Alpha,
+    /// What egui produces
+    PremultipliedAlpha,
+}
+
+/// Fixed function state a draw depends on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderState {
+    pub depth_test: bool,
+    /// Also passes at equal depth, for the sky at the far plane
+    pub depth_less_equal: bool,
+    pub depth_write: bool,
+    pub cull_face: bool,
+    pub blend: Blend,
+    /// Encode linear colours when writing into sRGB targets
+    pub srgb: bool,
+}
+
+impl RenderState {
+    pub const OPAQUE: RenderState = RenderState {
+        depth_test: true,
+        depth_less_equal: false,
+        depth_write: true,
+        cull_face: true,
+        blend: Blend::Off,
+        srgb: true,
+    };
+
+    pub const SKY: RenderState = RenderState {
+        depth_less_equal: true,
+        ..RenderState::OPAQUE
+    };
+
+    pub const TRANSPARENT: RenderState = RenderState {
+        depth_write: false,
+        blend: Blend::Alpha,
+        ..RenderState::OPAQUE
+    };
+
+    pub const UI: RenderState = RenderState {
+        depth_test: false,
+        depth_write: false,
+        cull_face: false,
+        blend: Blend::PremultipliedAlpha,
+        ..RenderState::OPAQUE
+    };
+
+    /// Fullscreen triangles which overwrite every pixel
+    pub const FULLSCREEN: RenderState = RenderState {
+        depth_test: false,
+        depth_write: false,
+        cull_face: false,
+        ..RenderState::OPAQUE
+    };
+
+    /// Fullscreen passes into textures that hold data rather than colours
+    pub const DATA: RenderState = RenderState {
+        srgb: false,
+        ..RenderState::FULLSCREEN
+    };
+
+    /// Sets whatever differs from `current`, or everything if it's unknown
+    fn apply(&self, current: Option<RenderState>) {
+        let changed = |f: fn(&RenderState) -> bool| current.is_none_or(|c| f(&c) != f(self));
+        unsafe {
+            if changed(|s| s.depth_test) {
+                set_capability(gl::DEPTH_TEST, self.depth_test);
+            }
+            if changed(|s| s.depth_less_equal) {
+                gl::DepthFunc(if self.depth_less_equal {
+                    gl::LEQUAL
+                } else {
+                    gl::LESS
+                });
+            }
+            if changed(|s| s.depth_write) {
+                gl::DepthMask(if self.depth_write {
+                    gl::TRUE
+                } else {
+                    gl::FALSE
+                });
+            }
+            if changed(|s| s.cull_face) {
+                set_capability(gl::CULL_FACE, self.cull_face);
+            }
+            if changed(|s| s.srgb) {
+                set_capability(gl::FRAMEBUFFER_SRGB, self.srgb);
+            }
+            if current.is_none_or(|c| c.blend != self.blend) {
+                set_capability(gl::BLEND, self.blend != Blend::Off);
+                match self.blend {
+                    Blend::Off => {}
+                    Blend::Add => {
+                        gl::BlendEquation(gl::FUNC_ADD);
+                        gl::BlendFunc(gl::ONE, gl::ONE);
+                    }
+                    Blend::ReverseSubtract => {
+                        gl::BlendEquation(gl::FUNC_REVERSE_SUBTRACT);
+                        gl::BlendFunc(gl::ONE, gl::ONE);
+                    }
+                    Blend::Alpha => {
+                        gl::BlendEquation(gl::FUNC_ADD);
+                        gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+                    }
+                    Blend::PremultipliedAlpha => {
+                        gl::BlendEquation(gl::FUNC_ADD);
+                        gl::BlendFuncSeparate(
+                            gl::ONE,
+                            gl::ONE_MINUS_SRC_ALPHA,
+                            gl::ONE_MINUS_DST_ALPHA,
+                            gl::ONE,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+unsafe fn set_capability(capability: GLenum, enabled: bool) {
+    if enabled {
+        gl::Enable(capability);
+    } else {
+        gl::Disable(capability);
+    }
+}
+
+/// Cached GL state, everything that changes the render state or the target goes through it
+pub struct GlState {
+    state: Option<RenderState>,
+    /// Framebuffer and viewport size
+    target: Option<(GLuint, i32, i32)>,
+    /// Where finished frames go, the window unless rendering offscreen
+    output: (GLuint, i32, i32),
+}
+
+impl GlState {
+    fn new(output: (GLuint, i32, i32)) -> Self {
+        GlState {
+            state: None,
+            target: None,
+            output,
+        }
+    }
+
+    /// Framebuffer and size of the output
+    pub fn output(&self) -> (GLuint, i32, i32) {
+        self.output
+    }
+
+    pub fn set(&mut self, state: RenderState) {
+        if self.state != Some(state) {
+            state.apply(self.state);
+            self.state = Some(state);
+        }
+    }
+
+    /// Binds an offscreen framebuffer and sets the viewport to cover it
+    pub fn bind_target(&mut self, fbo: GLuint, width: i32, height: i32) {
+        if self.target != Some((fbo, width, height)) {
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+                gl::Viewport(0, 0, width, height);
+            }
+            self.target = Some((fbo, width, height));
+        }
+    }
+
+    pub fn bind_output(&mut self) {
+        let (fbo, width, height) = self.output;
+        self.bind_target(fbo, width, height);
+    }
+}
+
+/// A model placed in the world
+pub struct ModelInstance<'a> {
+    pub model: &'a Model,
+    pub transform: Mat4,
+}
+
+/// Draws models in the passes that include them
+pub struct ModelRenderer {
+    shader: Program,
+    shadow_shader: Program,
+    prepass_shader: Program,
+}
+
+impl ModelRenderer {
+    fn new() -> Result<Self> {
+        let shader = Program::new()
+            .vertex_shader(include_str!("shaders/model/model.vert"))?
+            .fragment_shader(&with_includes(
+                include_str!("shaders/model/model.frag"),
+                &[FOG_GLSL, SHADOW_BLOCK_GLSL, SHADOWS_GLSL],
+            ))?
+            .link()?;
+        let shadow_shader = Program::new()
+            .vertex_shader(&with_includes(
+                include_str!("shaders/model/shadow.vert"),
+                &[SHADOW_BLOCK_GLSL],
+            ))?
+            .fragment_shader(include_str!("shaders/editor/terrain/shadow.frag.glsl"))?
+            .link()?;
+        let prepass_shader = Program::new()
+            .vertex_shader(include_str!("shaders/model/model.vert"))?
+            .fragment_shader(include_str!("shaders/model/prepass.frag"))?
+            .link()?;
+        Ok(ModelRenderer {
+            shader,
+            shadow_shader,
+            prepass_shader,
+        })
+    }
+
+    /// Every object casts shadows, blended ones are drawn after the sky from back to front
+    fn submit(&self, objects: &[ModelInstance], camera: &Camera, draw_list: &mut DrawList) {
+        let mut blended = vec![];
+        for (index, object) in objects.iter().enumerate() {
+            draw_list.submit(Pass::Shadow, DrawItem::Object(index));
+            if object.model.has_primitives(false) {
+                draw_list.submit(Pass::Prepass, DrawItem::Object(index));
+                draw_list.submit(Pass::Opaque, DrawItem::Object(index));
+            }
+            if object.model.has_primitives(true) {
+                let bounds = &object.model.bounds;
+                let center = object
+                    .transform
+                    .transform_point3((bounds.min + bounds.max) * 0.5);
+                blended.push((index, center.distance_squared(camera.position)));
+            }
+        }
+        blended.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        for (index, _) in blended {
+            draw_list.submit(Pass::Transparent, DrawItem::Object(index));
+        }
+    }
+
+    /// Depth only, into the bound shadow cascade
+    pub fn draw_shadows(&self, instance: &ModelInstance, cascade: usize) -> Result<()> {
+        self.shadow_shader.set_used();
+        self.shadow_shader.set_i32("cascade", cascade as i32)?;
+        draw_geometry(&self.shadow_shader, instance, |_| Ok(true))
+    }
+
+    /// Only the opaque primitives, blended ones don't occlude
+    pub fn draw_prepass(&self, instance: &ModelInstance) -> Result<()> {
+        self.prepass_shader.set_used();
+        draw_geometry(&self.prepass_shader, instance, |material_index| {
+            Ok(!instance.model.materials[material_index].blend)
+        })
+    }
+
+    /// The primitives with blended materials if `blend`, otherwise the opaque ones.
+    /// Lit and shadowed by the cascades in `shadow_map`.
+    pub fn draw(&self, instance: &ModelInstance, shadow_map: GLuint, blend: bool) -> Result<()> {
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(SHADOW_MAP_UNIT));
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, shadow_map);
+        }
+        self.shader.set_used();
+        draw_geometry(&self.shader, instance, |material_index| {
+            let material = &instance.model.materials[material_index];
+            if material.blend != blend {
+                return Ok(false);
+            }
+            material.bind(&self.shader)?;
+            Ok(true)
+        })
+    }
+}
+
+/// Draws the primitives of the model. `prepare` is called with the material index of each first
+/// and skips the primitive if it returns false.
+fn draw_geometry<F>(shader: &Program, instance: &ModelInstance, mut prepare: F) -> Result<()>
+where
+    F: FnMut(usize) -> Result<bool>,
+{
+    let model = instance.model;
+    unsafe {
+        gl::BindVertexArray(model.vao);
+    }
+    for node in &model.drawable_nodes {
+        shader.set_mat4("model", &(instance.transform * node.transform))?;
+        for primitive in &node.primitives {
+            if !prepare(primitive.material_index)? {
+                continue;
+            }
+            unsafe {
+                gl::DrawElements(
+                    gl::TRIANGLES,
+                    primitive.index_count as i32,
+                    gl::UNSIGNED_INT,
+                    primitive.first_index as *const _,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Colour target for frames that don't go to the window
+struct OffscreenTarget {
+    fbo: GLuint,
+    texture: GLuint,
+    width: i32,
+    height: i32,
+}
+
+impl OffscreenTarget {
+    fn new(width: i32, height: i32) -> Result<Self> {
+        let mut max_size = 0;
+        unsafe {
+            gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut max_size);
+        }
+        if width > max_size || height > max_size {
+            return Err(RenderError::TargetTooLarge {
+                width,
+                height,
+                max_size,
+            }
+            .into());
+        }
+
+        let mut fbo: GLuint = 0;
+        let mut texture: GLuint = 0;
+        unsafe {
+            gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture);
+            // Same encoding as the window
+            gl::TextureStorage2D(texture, 1, gl::SRGB8_ALPHA8, width, height);
+            gl::CreateFramebuffers(1, &mut fbo);
+            gl::NamedFramebufferTexture(fbo, gl::COLOR_ATTACHMENT0, texture, 0);
+            assert_eq!(
+                gl::CheckNamedFramebufferStatus(fbo, gl::FRAMEBUFFER),
+                gl::FRAMEBUFFER_COMPLETE,
+                "Offscreen framebuffer is incomplete",
+            );
+        }
+
+        Ok(OffscreenTarget {
+            fbo,
+            texture,
+            width,
+            height,
+        })
+    }
+
+    fn output(&self) -> (GLuint, i32, i32) {
+        (self.fbo, self.width, self.height)
+    }
+}
+
+impl Drop for OffscreenTarget {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.fbo);
+            gl::DeleteTextures(1, &self.texture);
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("Can't render {width}x{height} pixels, the limit is {max_size} on each side")]
+    TargetTooLarge {
+        width: i32,
+        height: i32,
+        max_size: i32,
+    },
+}
+
+// NOTE: no need to worry about std140 because Mat4's are aligned properly and with no gaps
+#[repr(C)]
+struct CameraTransforms {
+    mvp: Mat4,
+    proj: Mat4,
+    view: Mat4,
+    model: Mat4, // still unsure whether it belongs here
+}
+
+/// Uniform buffer binding of the camera transforms in all shaders
+const TRANSFORMS_BINDING: GLuint = 1;
+
+/// What a frame shows, borrowed from whoever owns the level
+pub struct Scene<'a> {
+    pub camera: &'a Camera,
+    pub terrain: &'a mut Terrain,
+    pub skybox: &'a mut Skybox,
+    pub sky: &'a SkySettings,
+    pub light: &'a DirectionalLight,
+    pub fog: &'a Fog,
+    pub objects: Vec<ModelInstance<'a>>,
+}
+
+/// Owns the frame: the passes, their targets and the GL state
+pub struct Renderer {
+    pub gl: GlState,
+    models: ModelRenderer,
+    pub ssao: Ssao,
+    pub post_process: PostProcess,
+    graphics: GraphicsSettings,
+
+    transforms_ubo: GLuint,
+    light_buffer: LightBuffer,
+    fog_buffer: FogBuffer,
+
+    /// Replaces the window when there isn't one
+    _offscreen: Option<OffscreenTarget>,
+
+    /// The last pass begun this frame
+    pass: Option<Pass>,
+    /// Camera projection of the current frame
+    proj: Mat4,
+}
+
+impl Renderer {
+    /// Draws into the window
+    pub fn new(
+        window_width: i32,
+        window_height: i32,
+        ambient_occlusion: AmbientOcclusion,
+        post_process: PostProcessSettings,
+        graphics: &GraphicsSettings,
+    ) -> Result<Self> {
+        Renderer::with_output(
+            None,
+            (0, window_width, window_height),
+            ambient_occlusion,
+            post_process,
+            graphics,
+        )
+    }
+
+    /// Draws into a texture, works without a window
+    pub fn offscreen(
+        width: i32,
+        height: i32,
+        ambient_occlusion: AmbientOcclusion,
+        post_process: PostProcessSettings,
+        graphics: &GraphicsSettings,
+    ) -> Result<Self> {
+        let target = OffscreenTarget::new(width, height)?;
+        let output = target.output();
+        Renderer::with_output(
+            Some(target),
+            output,
+            ambient_occlusion,
+            post_process,
+            graphics,
+        )
+    }
+
+    fn with_output(
+        offscreen: Option<OffscreenTarget>,
+        output: (GLuint, i32, i32),
+        ambient_occlusion: AmbientOcclusion,
+        post_process: PostProcessSettings,
+        graphics: &GraphicsSettings,
+    ) -> Result<Self> {
+        let (_, width, height) = output;
+        let mut gl = GlState::new(output);
+        gl.set(RenderState::OPAQUE);
+        gl.bind_output();
+
+        let mut transforms_ubo: GLuint = 0;
+        unsafe {
+            gl::CreateBuffers(1, &mut transforms_ubo);
+            gl::NamedBufferStorage(
+                transforms_ubo,
+                size_of::<CameraTransforms>() as isize,
+                std::ptr::null(),
+                gl::DYNAMIC_STORAGE_BIT,
+            );
+            gl::BindBufferBase(gl::UNIFORM_BUFFER, TRANSFORMS_BINDING, transforms_ubo);
+        }
+
+        Ok(Renderer {
+            gl,
+            models: ModelRenderer::new()?,
+            ssao: Ssao::new(ambient_occlusion, width, height)?,
+            post_process: PostProcess::new(post_process, graphics, width, height)?,
+            graphics: *graphics,
+
+            transforms_ubo,
+            light_buffer: LightBuffer::new(),
+            fog_buffer: FogBuffer::new(),
+
+            _offscreen: offscreen,
+
+            pass: None,
+            proj: Mat4::IDENTITY,
+        })
+    }
+
+    pub fn apply_graphics_settings(&mut self, graphics: &GraphicsSettings) {
+        self.graphics = *graphics;
+        self.post_process.apply_graphics_settings(graphics);
+    }
+
+    /// Draws the scene and resolves it into the output, ready for the UI to go on top
+    pub fn draw_scene(&mut self, mut scene: Scene) -> Result<()> {
+        let camera = scene.camera;
+        let light = scene.light;
+        self.begin_frame(camera);
+        self.light_buffer.update(light, scene.sky.bake_ambient);
+        self.fog_buffer.update(scene.fog, camera.position);
+        scene.skybox.bake_ambient(&mut self.gl, scene.sky, light)?;
+        scene.terrain.update_heightmap(&mut self.gl)?;
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(6));
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, scene.skybox.ambient_map);
+        }
+
+        let mut draw_list = DrawList::default();
+        scene.terrain.submit(&mut draw_list);
+        self.models.submit(&scene.objects, camera, &mut draw_list);
+        scene.skybox.submit(&mut draw_list);
+
+        // Objects cast shadows on the terrain and on each other
+        let mut casters = scene.terrain.aabb;
+        for object in &scene.objects {
+            let bounds = object.model.bounds.transformed(&object.transform);
+            casters.extend(bounds.min);
+            casters.extend(bounds.max);
+        }
+
+        self.begin_pass(Pass::Shadow)?;
+        scene.terrain.shadows.update(camera, light, &casters);
+        for cascade in 0..NUM_CASCADES {
+            scene.terrain.shadows.begin_cascade(&mut self.gl, cascade);
+            self.draw_cascade(&mut scene, &draw_list, cascade)?;
+        }
+
+        for pass in [Pass::Prepass, Pass::Opaque, Pass::Sky, Pass::Transparent] {
+            self.begin_pass(pass)?;
+            if pass != Pass::Prepass || self.ssao.settings.enabled {
+                self.draw_submitted(&mut scene, &draw_list, pass)?;
+            }
+        }
+
+        self.begin_pass(Pass::Ui)?;
+
+        Ok(())
+    }
+
+    /// Draws the shadow casters into the bound cascade
+    fn draw_cascade(&self, scene: &mut Scene, draw_list: &DrawList, cascade: usize) -> Result<()> {
+        for item in draw_list.items(Pass::Shadow) {
+            match item {
+                DrawItem::Terrain => scene.terrain.draw_shadows(cascade)?,
+                DrawItem::Object(index) => {
+                    self.models.draw_shadows(&scene.objects[index], cascade)?
+                }
+                DrawItem::Sky => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the items submitted to a pass after the shadow one
+    fn draw_submitted(&self, scene: &mut Scene, draw_list: &DrawList, pass: Pass) -> Result<()> {
+        let shadow_map = scene.terrain.shadows.texture;
+        for item in draw_list.items(pass) {
+            match (item, pass) {
+                (DrawItem::Terrain, Pass::Prepass) => scene.terrain.draw_prepass()?,
+                (DrawItem::Terrain, _) => scene.terrain.draw()?,
+                (DrawItem::Object(index), Pass::Prepass) => {
+                    self.models.draw_prepass(&scene.objects[index])?
+                }
+                (DrawItem::Object(index), _) => self.models.draw(
+                    &scene.objects[index],
+                    shadow_map,
+                    pass == Pass::Transparent,
+                )?,
+                (DrawItem::Sky, _) => scene.skybox.draw(scene.sky)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the scene without the UI at `scale` times the output size and reads it back
+    pub fn capture(&mut self, scene: Scene, scale: i32) -> Result<RgbImage> {
+        let (_, width, height) = self.gl.output();
+        let (width, height) = (width * scale, height * scale);
+
+        let target = OffscreenTarget::new(width, height)?;
+        let ssao = Ssao::new(self.ssao.settings, width, height)?;
+        let post_process =
+            PostProcess::new(self.post_process.settings, &self.graphics, width, height)?;
+        let ssao = mem::replace(&mut self.ssao, ssao);
+        let post_process = mem::replace(&mut self.post_process, post_process);
+        let output = mem::replace(&mut self.gl.output, target.output());
+
+        let result = self.draw_scene(scene).map(|_| self.read_output());
+
+        self.ssao = ssao;
+        self.post_process = post_process;
+        self.gl.output = output;
+        // The framebuffers of the capture are deleted when this returns
+        self.gl.target = None;
+
+        result
+    }
+
+    /// Reads back the last frame in the output
+    pub fn read_output(&mut self) -> RgbImage {
+        let (_, width, height) = self.gl.output();
+        self.gl.bind_output();
+        // The stored sRGB values are only read back as they are without sRGB conversion
+        self.gl.set(RenderState::DATA);
+
+        let mut pixels = vec![0u8; (width * height * 3) as usize];
+        unsafe {
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl::ReadPixels(
+                0,
+                0,
+                width,
+                height,
+                gl::RGB,
+                gl::UNSIGNED_BYTE,
+                pixels.as_mut_ptr() as *mut _,
+            );
+        }
+        let image = RgbImage::from_raw(width as u32, height as u32, pixels)
+            .expect("Pixel buffer fits the image");
+        // GL rows go bottom to top
+        image::imageops::flip_vertical(&image)
+    }
+
+    fn begin_frame(&mut self, camera: &Camera) {
+        self.pass = None;
+        self.proj = camera.get_projection_matrix();
+
+        let view = camera.get_view_matrix();
+        let model = Mat4::IDENTITY;
+        let transforms = CameraTransforms {
+            mvp: self.proj * view * model,
+            proj: self.proj,
+            view,
+            model,
+        };
+        unsafe {
+            gl::NamedBufferSubData(
+                self.transforms_ubo,
+                0,
+                size_of::<CameraTransforms>() as isize,
+                &transforms as *const CameraTransforms as *const _,
+            );
+        }
+    }
+
+    /// Sets the target and the state for the pass. Passes must be begun in order.
+    fn begin_pass(&mut self, pass: Pass) -> Result<()> {
+        debug_assert!(
+            self.pass.is_none_or(|previous| previous < pass),
+            "{:?} pass after {:?}",
+            pass,
+            self.pass,
+        );
+        self.pass = Some(pass);
+
+        match pass {
+            Pass::Shadow => self.gl.set(RenderState::OPAQUE),
+            Pass::Prepass => {
+                self.gl.set(RenderState::OPAQUE);
+                if self.ssao.settings.enabled {
+                    self.ssao.begin_prepass(&mut self.gl);
+                }
+            }
+            Pass::Opaque => {
+                if self.ssao.settings.enabled {
+                    self.ssao.compute(&mut self.gl, &self.proj)?;
+                } else {
+                    self.ssao.clear();
+                }
+                self.gl.set(RenderState::OPAQUE);
+                self.post_process.begin(&mut self.gl);
+            }
+            Pass::Sky => self.gl.set(RenderState::SKY),
+            Pass::Transparent => self.gl.set(RenderState::TRANSPARENT),
+            Pass::Ui => {
+                self.post_process.resolve(&mut self.gl)?;
+                if self.ssao.show {
+                    self.ssao.draw_debug(&mut self.gl);
+                }
+                self.gl.set(RenderState::UI);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.transforms_ubo);
+        }
+    }
+}
@@ -0,0 +1,24 @@
+// Distance and height fog, shared by everything drawn into the scene.
+// Prepended after #version, see FOG_GLSL in fog.rs.
+
+layout(std140, binding = 2) uniform UFog {
+    vec4 color;
+    vec4 camera_position;
+    float density;
+    float start;
+    float height_falloff;
+    float base_height;
+}
+uFog;
+
+// Exponential fog integrated along the view ray through density that falls off with height
+float fog_amount(vec3 frag_pos) {
+    vec3 ray = frag_pos - uFog.camera_position.xyz;
+    float dist = length(ray);
+    float fog_dist = max(dist - uFog.start, 0.0);
+    float camera_density =
+        uFog.density * exp(-uFog.height_falloff * (uFog.camera_position.y - uFog.base_height));
+    float fy = uFog.height_falloff * ray.y * fog_dist / max(dist, 0.0001);
+    float integral = abs(fy) > 0.0001 ? (1.0 - exp(-fy)) / fy : 1.0;
+    return 1.0 - exp(-camera_density * fog_dist * integral);
+}
@@ -0,0 +1,13 @@
+// Cascade matrices and settings, shared by the shaders that cast and receive shadows.
+// Prepended after #version, see SHADOW_BLOCK_GLSL in shadows.rs.
+
+const int NUM_CASCADES = 4;
+
+layout(std140, binding = 3) uniform UShadows {
+    mat4 light_vp[NUM_CASCADES];
+    vec4 splits;  // view distance where each cascade ends
+    vec4 texel_sizes;
+    float blend;
+    int show_cascades;
+}
+uShadows;
@@ -0,0 +1,56 @@
+// Cascaded shadow lookups, needs the block from shadow_block.glsl before it.
+// Prepended after #version, see SHADOWS_GLSL in shadows.rs.
+
+layout(binding = 7) uniform sampler2DArray shadow_map;
+
+float cascade_shadow(int cascade, vec3 frag_pos, vec3 normal) {
+    // Offsetting along the normal scales with the texel size, unlike a depth bias
+    vec3 offset_pos = frag_pos + normal * uShadows.texel_sizes[cascade] * 1.5;
+    vec4 light_space_pos = uShadows.light_vp[cascade] * vec4(offset_pos, 1.0);
+    vec3 proj_coords = light_space_pos.xyz / light_space_pos.w * 0.5 + 0.5;
+    float frag_depth = proj_coords.z;
+    const float bias = 0.0001;
+    float shadow = 0.0;
+    vec2 texel_size = 1.0 / textureSize(shadow_map, 0).xy;
+    for (int x = -1; x <= 1; ++x) {
+        for (int y = -1; y <= 1; ++y) {
+            vec2 uv = proj_coords.xy + vec2(x, y) * texel_size;
+            float pcf_depth = texture(shadow_map, vec3(uv, cascade)).r;
+            shadow += (frag_depth - bias) > pcf_depth ? 1.0 : 0.0;
+        }
+    }
+    return shadow / 9.0;
+}
+
+// Where the cascade ends the next one is faded in, and after the last one shadows fade out
+float calc_shadow(vec3 frag_pos, vec3 normal, float view_depth) {
+    for (int i = 0; i < NUM_CASCADES; ++i) {
+        float end = uShadows.splits[i];
+        if (view_depth < end) {
+            float start = i == 0 ? 0.0 : uShadows.splits[i - 1];
+            float blend_start = end - uShadows.blend * (end - start);
+            float shadow = cascade_shadow(i, frag_pos, normal);
+            if (view_depth > blend_start) {
+                float next = i + 1 < NUM_CASCADES ? cascade_shadow(i + 1, frag_pos, normal) : 0.0;
+                shadow = mix(shadow, next, smoothstep(blend_start, end, view_depth));
+            }
+            return shadow;
+        }
+    }
+    return 0.0;
+}
+
+const vec3 CASCADE_COLORS[NUM_CASCADES] =
+    vec3[](vec3(0.9, 0.2, 0.2), vec3(0.2, 0.9, 0.2), vec3(0.2, 0.4, 0.95), vec3(0.95, 0.85, 0.2));
+
+vec3 tint_cascades(vec3 color, float view_depth) {
+    if (uShadows.show_cascades == 0) {
+        return color;
+    }
+    for (int i = 0; i < NUM_CASCADES; ++i) {
+        if (view_depth < uShadows.splits[i]) {
+            return mix(color, CASCADE_COLORS[i], 0.35);
+        }
+    }
+    return color;
+}
@@ -3,7 +3,6 @@ layout(triangles) in;
 layout(line_strip, max_vertices = 6) out;
 
 in TES_OUT {
-    vec4 frag_pos_sun_space;
     vec3 frag_pos;
     vec3 normal;
     vec2 tile_uv;
@@ -19,7 +18,6 @@ layout(std140, binding = 1) uniform UTransforms {
     mat4 proj;
     mat4 view;
     mat4 model;
-    mat4 sun_vp;
 }
 uTransforms;
 
@@ -0,0 +1,41 @@
+#version 450 core
+
+in VS_OUT { vec2 uv; }
+fs_in;
+
+const int BLEND_ADD = 0;
+const int BLEND_MAX = 1;
+const int BLEND_MIN = 2;
+const int BLEND_REPLACE = 3;
+
+const int MAX_LAYERS = 6;
+
+// Bottom to top
+layout(binding = 0) uniform sampler2D heights[MAX_LAYERS];
+layout(binding = 6) uniform sampler2D masks[MAX_LAYERS];
+uniform int blend_modes[MAX_LAYERS];
+uniform float opacities[MAX_LAYERS];
+uniform int num_layers;
+
+layout(location = 0) out vec4 Height;
+
+void main() {
+    float height = 0.0;
+    for (int i = 0; i < num_layers; ++i) {
+        float value = texture(heights[i], fs_in.uv).r;
+        float blended;
+        if (blend_modes[i] == BLEND_ADD) {
+            blended = height + value;
+        } else if (blend_modes[i] == BLEND_MAX) {
+            blended = max(height, value);
+        } else if (blend_modes[i] == BLEND_MIN) {
+            blended = min(height, value);
+        } else {
+            blended = value;
+        }
+        float alpha = opacities[i] * clamp(texture(masks[i], fs_in.uv).r, 0.0, 1.0);
+        height = mix(height, blended, alpha);
+    }
+
+    Height = vec4(clamp(height, 0.0, 1.0), 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 450 core
+
+layout(std140, binding = 1) uniform UTransforms {
+    mat4 mvp;
+    mat4 proj;
+    mat4 view;
+    mat4 model;
+}
+uTransforms;
+
+in TES_OUT {
+    vec3 frag_pos;
+    vec3 normal;
+    vec2 tile_uv;
+}
+fs_in;
+
+out vec4 Normal;
+
+// View space normal for ambient occlusion
+void main() { Normal = vec4(normalize(mat3(uTransforms.view) * fs_in.normal), 0.0); }
@@ -7,10 +7,11 @@ layout(std140, binding = 1) uniform UTransforms {
     mat4 proj;
     mat4 view;
     mat4 model;
-    mat4 sun_vp;
 }
 uTransforms;
 
+uniform int cascade;
+
 layout(binding = 1) uniform sampler2D heightmap;
 
 uniform float terrain_max_height = 200.0;
@@ -28,5 +29,5 @@ void main() {
     vec4 p = mix(p2, p1, gl_TessCoord.y);
 
     p.y += texture(heightmap, tile_uv).r * terrain_max_height;
-    gl_Position = uTransforms.sun_vp * uTransforms.model * p;
+    gl_Position = uShadows.light_vp[cascade] * uTransforms.model * p;
 }
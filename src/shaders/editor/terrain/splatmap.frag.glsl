@@ -0,0 +1,94 @@
+#version 450 core
+
+in VS_OUT { vec2 uv; }
+fs_in;
+
+struct Rule {
+    float min_height;
+    float max_height;
+    float min_slope;  // degrees
+    float max_slope;  // degrees
+    float blend;
+    float noise;
+};
+
+const int MAX_RULES = 4;
+
+uniform Rule rules[MAX_RULES];
+uniform int num_rules;
+uniform float terrain_size;
+uniform float terrain_max_height;
+
+layout(binding = 1) uniform sampler2D heightmap;
+
+layout(location = 0) out vec4 Weights;
+
+float sample_height(vec2 uv) { return texture(heightmap, uv).r * terrain_max_height; }
+
+// Same as calc_normal in terrain.te.glsl
+vec3 calc_normal(vec2 uv) {
+    vec2 heightmap_size = textureSize(heightmap, 0);
+    vec2 texel_size = 1.0 / heightmap_size;
+    float L = sample_height(uv - vec2(texel_size.x, 0));
+    float R = sample_height(uv + vec2(texel_size.x, 0));
+    float T = sample_height(uv - vec2(0, texel_size.y));
+    float B = sample_height(uv + vec2(0, texel_size.y));
+
+    vec2 texel_size_world = terrain_size / heightmap_size;
+    vec3 horizontal = vec3(2.0 * texel_size_world.x, R - L, 0.0);
+    vec3 vertical = vec3(0.0, B - T, 2.0 * texel_size_world.y);
+
+    return normalize(cross(vertical, horizontal));
+}
+
+float hash(vec2 p) { return fract(sin(dot(p, vec2(127.1, 311.7))) * 43758.5453); }
+
+float value_noise(vec2 p) {
+    vec2 i = floor(p);
+    vec2 f = fract(p);
+    vec2 u = f * f * (3.0 - 2.0 * f);
+    float a = hash(i);
+    float b = hash(i + vec2(1.0, 0.0));
+    float c = hash(i + vec2(0.0, 1.0));
+    float d = hash(i + vec2(1.0, 1.0));
+    return mix(mix(a, b, u.x), mix(c, d, u.x), u.y);
+}
+
+// Roughly in [-1, 1]
+float border_noise(vec2 uv) {
+    vec2 p = uv * terrain_size / 16.0;
+    float n = 0.6 * value_noise(p) + 0.3 * value_noise(p * 2.03) + 0.1 * value_noise(p * 4.01);
+    return n * 2.0 - 1.0;
+}
+
+// 1 inside [lo, hi], fading out over `width` outside of it
+float soft_range(float value, float lo, float hi, float width) {
+    width = max(width, 0.0001);
+    return smoothstep(lo - width, lo, value) * (1.0 - smoothstep(hi, hi + width, value));
+}
+
+void main() {
+    float height = sample_height(fs_in.uv);
+    float slope = degrees(acos(clamp(calc_normal(fs_in.uv).y, -1.0, 1.0)));
+    float noise = border_noise(fs_in.uv);
+
+    // The first rule is the base material which covers everything else
+    float weights[MAX_RULES] = float[](1.0, 0.0, 0.0, 0.0);
+    for (int i = 1; i < num_rules; ++i) {
+        Rule rule = rules[i];
+        float height_width = rule.blend * terrain_max_height;
+        float slope_width = rule.blend * 90.0;
+        float mask = soft_range(height + noise * rule.noise * height_width, rule.min_height,
+                                rule.max_height, height_width) *
+                     soft_range(slope + noise * rule.noise * slope_width, rule.min_slope,
+                                rule.max_slope, slope_width);
+
+        // Paint over the previous rules
+        for (int j = 0; j < i; ++j) {
+            weights[j] *= 1.0 - mask;
+        }
+        weights[i] = mask;
+    }
+
+    Weights = vec4(weights[0], weights[1], weights[2], weights[3]);
+}
@@ -1,7 +1,14 @@
 #version 450 core
 
+layout(std140, binding = 1) uniform UTransforms {
+    mat4 mvp;
+    mat4 proj;
+    mat4 view;
+    mat4 model;
+}
+uTransforms;
+
 in TES_OUT {
-    vec4 frag_pos_sun_space;
     vec3 frag_pos;
     vec3 normal;
     vec2 tile_uv;
@@ -13,32 +20,151 @@ out vec4 Color;
 uniform vec2 cursor;
 uniform float brush_size;
 
+layout(std140, binding = 4) uniform ULight {
+    vec4 direction;  // towards the light
+    vec4 color;
+    float ambient;
+    int use_sky_ambient;
+}
+uLight;
+
+uniform float terrain_max_height;
+uniform float terrain_size;
+uniform int num_patches;
+
+// Analysis overlays
+const int OVERLAY_NONE = 0;
+const int OVERLAY_SLOPE = 1;
+const int OVERLAY_CURVATURE = 2;
+const int OVERLAY_HEIGHT_BANDS = 3;
+const int OVERLAY_CONTOURS = 4;
+
+uniform int overlay_mode;
+uniform vec2 slope_thresholds;  // degrees: gentle below x, steep above y
+uniform float curvature_scale;
+uniform float height_band;       // metres
+uniform float contour_interval;  // metres
+
+// Sculpting symmetry
+const int SYMMETRY_NONE = 0;
+const int SYMMETRY_X = 1;
+const int SYMMETRY_Z = 2;
+const int SYMMETRY_XZ = 3;
+const int SYMMETRY_RADIAL = 4;
+
+uniform int symmetry_mode;
+uniform int symmetry_folds;
+uniform vec2 terrain_center;
+
 layout(binding = 0) uniform sampler2D terrain_texture;
+layout(binding = 1) uniform sampler2D heightmap;
 layout(binding = 2) uniform sampler2D brush_texture;
-layout(binding = 3) uniform sampler2D shadow_map;
-
-float calc_shadow(vec4 frag_pos) {
-    vec3 proj_coords = frag_pos.xyz / frag_pos.w;
-    proj_coords = proj_coords * 0.5 + 0.5;
-    float frag_depth = proj_coords.z;
-    // TODO: adjust bias based on the angle
-    float bias = 0.003;
-    float shadow = 0.0;
-    vec2 texel_size = 1.0 / textureSize(shadow_map, 0);
-    for (int x = -1; x <= 1; ++x) {
-        for (int y = -1; y <= 1; ++y) {
-            float pcf_depth = texture(shadow_map, proj_coords.xy + vec2(x, y) * texel_size).r;
-            shadow += (frag_depth - bias) > pcf_depth ? 1.0 : 0.0;
+layout(binding = 4) uniform sampler2D splat_map;
+layout(binding = 5) uniform sampler2D sculpt_mask;
+layout(binding = 6) uniform samplerCube sky_ambient;
+layout(binding = 8) uniform sampler2D ao_map;
+
+uniform bool show_sculpt_mask;
+
+const int MAX_MATERIALS = 4;
+uniform vec3 material_colors[MAX_MATERIALS];
+uniform int num_materials;
+
+const float ENABLE_SHADOWS = 1.0;
+
+// Green -> yellow -> red
+vec3 heat(float t) {
+    return t < 0.5 ? mix(vec3(0.1, 0.8, 0.1), vec3(0.95, 0.9, 0.1), t * 2.0)
+                   : mix(vec3(0.95, 0.9, 0.1), vec3(0.9, 0.1, 0.1), t * 2.0 - 1.0);
+}
+
+float sample_height(vec2 uv) { return texture(heightmap, uv).r * terrain_max_height; }
+
+vec3 apply_overlay(vec3 color, vec3 normal) {
+    if (overlay_mode == OVERLAY_SLOPE) {
+        float slope = degrees(acos(clamp(normal.y, -1.0, 1.0)));
+        float t = smoothstep(slope_thresholds.x, slope_thresholds.y, slope);
+        return mix(color, heat(t), 0.6);
+    }
+    if (overlay_mode == OVERLAY_CURVATURE) {
+        // Laplacian of the height field: positive is concave (valleys), negative is convex (ridges)
+        vec2 texel_size = 1.0 / textureSize(heightmap, 0);
+        float texel_size_world = terrain_size * texel_size.x;
+        float C = sample_height(fs_in.tile_uv);
+        float L = sample_height(fs_in.tile_uv - vec2(texel_size.x, 0));
+        float R = sample_height(fs_in.tile_uv + vec2(texel_size.x, 0));
+        float T = sample_height(fs_in.tile_uv - vec2(0, texel_size.y));
+        float B = sample_height(fs_in.tile_uv + vec2(0, texel_size.y));
+        float laplacian = (L + R + T + B - 4.0 * C) / (texel_size_world * texel_size_world);
+        float k = clamp(laplacian * curvature_scale, -1.0, 1.0);
+        vec3 tint = k > 0.0 ? vec3(0.1, 0.3, 0.95) : vec3(0.95, 0.2, 0.1);
+        return mix(color, tint, abs(k) * 0.8);
+    }
+    if (overlay_mode == OVERLAY_HEIGHT_BANDS) {
+        float band = floor(fs_in.frag_pos.y / height_band);
+        float num_bands = max(ceil(terrain_max_height / height_band), 1.0);
+        vec3 tint = heat(clamp(band / num_bands, 0.0, 1.0));
+        return mix(color, tint, 0.6);
+    }
+    if (overlay_mode == OVERLAY_CONTOURS) {
+        float h = fs_in.frag_pos.y / contour_interval;
+        float dist = min(fract(h), 1.0 - fract(h));
+        float contour = 1.0 - smoothstep(0.0, fwidth(h) * 1.5, dist);
+        return mix(color, vec3(0.05, 0.05, 0.05), contour);
+    }
+    return color;
+}
+
+// 1 on a line `dist` away, with a roughly constant width on screen
+float line(float dist, float width) { return 1.0 - smoothstep(0.0, width, abs(dist)); }
+
+vec3 draw_symmetry_axes(vec3 color) {
+    if (symmetry_mode == SYMMETRY_NONE) {
+        return color;
+    }
+    const vec3 axis_color = vec3(0.2, 0.85, 0.95);
+    vec2 p = fs_in.frag_pos.xz - terrain_center;
+    float width = 1.5 * fwidth(fs_in.frag_pos.x);
+    float t = 0.0;
+    if (symmetry_mode == SYMMETRY_X || symmetry_mode == SYMMETRY_XZ) {
+        t = max(t, line(p.x, width));
+    }
+    if (symmetry_mode == SYMMETRY_Z || symmetry_mode == SYMMETRY_XZ) {
+        t = max(t, line(p.y, width));
+    }
+    if (symmetry_mode == SYMMETRY_RADIAL) {
+        // Spokes at the boundaries between the copies
+        for (int i = 0; i < symmetry_folds; ++i) {
+            float angle = 6.28318530718 * float(i) / float(symmetry_folds);
+            vec2 dir = vec2(cos(angle), sin(angle));
+            if (dot(p, dir) > 0.0) {
+                t = max(t, line(p.x * dir.y - p.y * dir.x, width));
+            }
         }
     }
-    return shadow / 9.0;
+    return mix(color, axis_color, t);
 }
 
-const float ENABLE_SHADOWS = 1.0;
+vec3 tint_protected(vec3 color) {
+    if (!show_sculpt_mask) {
+        return color;
+    }
+    const vec3 protected_color = vec3(0.95, 0.3, 0.25);
+    float protection = texture(sculpt_mask, fs_in.tile_uv).r;
+    return mix(color, protected_color, protection * 0.45);
+}
 
 void main() {
-    vec2 patch_uv = fs_in.tile_uv * 64.0;
+    vec2 patch_uv = fs_in.tile_uv * float(num_patches);
     vec4 terrain_color = texture(terrain_texture, patch_uv);
+    if (num_materials > 0) {
+        vec4 weights = texture(splat_map, fs_in.tile_uv);
+        vec3 material_color = vec3(0.0);
+        for (int i = 0; i < num_materials; ++i) {
+            material_color += weights[i] * material_colors[i];
+        }
+        terrain_color = vec4(material_color, 1.0);
+    }
     vec2 brush_uv = vec2(0.5, 0.5) + (fs_in.frag_pos.xz - cursor) / brush_size;
     const vec4 brush_color = vec4(0.75, 0.45, 0.92, 1.0);
     const vec3 brush_border_color = vec3(0.69, 0.67, 0.91);
@@ -48,16 +174,24 @@ void main() {
 
     base_color = mix(base_color, brush_border_color, t);
 
-    vec3 ambient = 0.35 * base_color;
     vec3 normal = normalize(fs_in.normal);
-    vec3 light_color = vec3(1.0);
-    vec3 light_dir = normalize(vec3(0.0, 200.0, 500.0));  // @hardcoded
-    float diff = max(dot(light_dir, normal), 0.0);
-    vec3 diffuse = diff * light_color;
+    // A low mip of the sky is roughly what a surface facing that way receives
+    vec3 sky_light = uLight.use_sky_ambient != 0 ? textureLod(sky_ambient, normal, 3.0).rgb : vec3(1.0);
+    // Screen-space ambient occlusion
+    float occlusion = texelFetch(ao_map, ivec2(gl_FragCoord.xy), 0).r;
+    vec3 ambient = uLight.ambient * sky_light * occlusion;
+    float diff = max(dot(uLight.direction.xyz, normal), 0.0);
+    vec3 diffuse = diff * uLight.color.rgb;
 
-    float shadow = calc_shadow(fs_in.frag_pos_sun_space);
+    float view_depth = -(uTransforms.view * vec4(fs_in.frag_pos, 1.0)).z;
+    float shadow = calc_shadow(fs_in.frag_pos, normal, view_depth);
 
     vec3 lighting = (ambient + (1.0 - shadow * ENABLE_SHADOWS) * diffuse) * base_color;
+    lighting = mix(lighting, uFog.color.rgb, fog_amount(fs_in.frag_pos));
+    lighting = apply_overlay(lighting, normal);
+    lighting = tint_protected(lighting);
+    lighting = tint_cascades(lighting, view_depth);
+    lighting = draw_symmetry_axes(lighting);
 
     Color = vec4(lighting, 1.0);
 }
@@ -7,7 +7,6 @@ layout(std140, binding = 1) uniform UTransforms {
     mat4 proj;
     mat4 view;
     mat4 model;
-    mat4 sun_vp;
 }
 uTransforms;
 
@@ -20,7 +19,6 @@ in TCS_OUT { vec2 tile_uv; }
 tes_in[];
 
 out TES_OUT {
-    vec4 frag_pos_sun_space;
     vec3 frag_pos;
     vec3 normal;
     vec2 tile_uv;
@@ -60,7 +58,6 @@ void main() {
     gl_Position = uTransforms.mvp * p;
     tes_out.tile_uv = tile_uv;
     tes_out.frag_pos = p.xyz;
-    tes_out.frag_pos_sun_space = uTransforms.sun_vp * vec4(tes_out.frag_pos, 1.0);
 
     // Note: we're assuming the model matrix is identity here
     tes_out.normal = calc_normal(tile_uv);
@@ -0,0 +1,115 @@
+#version 450 core
+
+// glTF metallic-roughness
+layout(binding = 0) uniform sampler2D base_color_texture;
+layout(binding = 1) uniform sampler2D metallic_roughness_texture;
+layout(binding = 2) uniform sampler2D normal_texture;
+layout(binding = 3) uniform sampler2D occlusion_texture;
+layout(binding = 4) uniform sampler2D emissive_texture;
+layout(binding = 6) uniform samplerCube sky_ambient;
+layout(binding = 8) uniform sampler2D ao_map;
+
+uniform vec4 base_color_factor;
+uniform float metallic_factor;
+uniform float roughness_factor;
+uniform float normal_scale;
+uniform float occlusion_strength;
+uniform vec3 emissive_factor;
+uniform bool alpha_blend;
+
+layout(std140, binding = 1) uniform UTransforms {
+    mat4 mvp;
+    mat4 proj;
+    mat4 view;
+    mat4 model;
+}
+uTransforms;
+
+layout(std140, binding = 4) uniform ULight {
+    vec4 direction;  // towards the light
+    vec4 color;
+    float ambient;
+    int use_sky_ambient;
+}
+uLight;
+
+layout(location = 0) in vec2 inUV;
+layout(location = 1) in vec3 inWorldPos;
+layout(location = 2) in vec3 inNormal;
+layout(location = 3) in vec4 inTangent;
+
+layout(location = 0) out vec4 outColor;
+
+const float PI = 3.14159265359;
+
+// GGX / Trowbridge-Reitz
+float distribution(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith with Schlick-GGX for both directions
+float geometry(float n_dot_v, float n_dot_l, float roughness) {
+    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    float g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    float g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+// Tangent space normal from the normal map, or the vertex normal if there are no tangents
+vec3 surface_normal() {
+    vec3 n = normalize(inNormal);
+    if (dot(inTangent.xyz, inTangent.xyz) == 0.0) {
+        return n;
+    }
+    vec3 t = normalize(inTangent.xyz - n * dot(n, inTangent.xyz));
+    vec3 b = cross(n, t) * inTangent.w;
+    vec3 tangent_normal = texture(normal_texture, inUV).rgb * 2.0 - 1.0;
+    tangent_normal.xy *= normal_scale;
+    return normalize(mat3(t, b, n) * tangent_normal);
+}
+
+vec3 fresnel(float cos_theta, vec3 f0) { return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0); }
+
+void main() {
+    vec4 base_color = texture(base_color_texture, inUV) * base_color_factor;
+    vec4 metallic_roughness = texture(metallic_roughness_texture, inUV);
+    float metallic = metallic_roughness.b * metallic_factor;
+    float roughness = clamp(metallic_roughness.g * roughness_factor, 0.04, 1.0);
+    float occlusion = mix(1.0, texture(occlusion_texture, inUV).r, occlusion_strength) *
+                      texelFetch(ao_map, ivec2(gl_FragCoord.xy), 0).r;
+    vec3 emissive = texture(emissive_texture, inUV).rgb * emissive_factor;
+
+    vec3 n = surface_normal();
+    vec3 v = normalize(uFog.camera_position.xyz - inWorldPos);
+    vec3 l = normalize(uLight.direction.xyz);
+    vec3 h = normalize(v + l);
+    float n_dot_l = max(dot(n, l), 0.0);
+    float n_dot_v = max(dot(n, v), 0.0001);
+    float n_dot_h = max(dot(n, h), 0.0);
+
+    // Dielectrics reflect 4% head on, metals tint the reflection with their colour
+    vec3 f0 = mix(vec3(0.04), base_color.rgb, metallic);
+    vec3 f = fresnel(max(dot(h, v), 0.0), f0);
+    vec3 specular =
+        distribution(n_dot_h, roughness) * geometry(n_dot_v, n_dot_l, roughness) * f /
+        (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+    vec3 diffuse = (1.0 - f) * (1.0 - metallic) * base_color.rgb / PI;
+    // The light colour is its irradiance, PI matches the brightness of the Lambertian terrain
+    vec3 direct = (diffuse + specular) * uLight.color.rgb * n_dot_l * PI;
+
+    // The vertex normal, a normal map would make the offset noisy
+    float view_depth = -(uTransforms.view * vec4(inWorldPos, 1.0)).z;
+    float shadow = calc_shadow(inWorldPos, normalize(inNormal), view_depth);
+    direct *= 1.0 - shadow;
+
+    vec3 sky_light =
+        uLight.use_sky_ambient != 0 ? textureLod(sky_ambient, n, 3.0).rgb : vec3(1.0);
+    vec3 ambient = uLight.ambient * sky_light * base_color.rgb * occlusion;
+
+    vec3 color = direct + ambient + emissive;
+    color = mix(color, uFog.color.rgb, fog_amount(inWorldPos));
+    outColor = vec4(tint_cascades(color, view_depth), alpha_blend ? base_color.a : 1.0);
+}
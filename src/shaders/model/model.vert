@@ -0,0 +1,30 @@
+#version 450 core
+
+layout(std140, binding = 1) uniform UTransforms {
+    mat4 mvp;
+    mat4 proj;
+    mat4 view;
+    mat4 model;
+}
+uTransforms;
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inNormal;
+layout(location = 2) in vec2 inUV;
+layout(location = 3) in vec4 inTangent;
+
+layout(location = 0) out vec2 outUV;
+layout(location = 1) out vec3 outWorldPos;
+layout(location = 2) out vec3 outNormal;
+layout(location = 3) out vec4 outTangent;
+
+uniform mat4 model;
+
+void main() {
+    vec4 world_pos = model * vec4(inPosition, 1.0);
+    gl_Position = uTransforms.mvp * world_pos;
+    outUV = inUV;
+    outWorldPos = world_pos.xyz;
+    outNormal = mat3(transpose(inverse(model))) * inNormal;
+    outTangent = vec4(mat3(model) * inTangent.xyz, inTangent.w);
+}
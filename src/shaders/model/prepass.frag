@@ -0,0 +1,16 @@
+#version 450 core
+
+layout(std140, binding = 1) uniform UTransforms {
+    mat4 mvp;
+    mat4 proj;
+    mat4 view;
+    mat4 model;
+}
+uTransforms;
+
+layout(location = 2) in vec3 inNormal;
+
+out vec4 Normal;
+
+// View space normal for ambient occlusion
+void main() { Normal = vec4(normalize(mat3(uTransforms.view) * inNormal), 0.0); }
@@ -0,0 +1,8 @@
+#version 450 core
+
+layout(location = 0) in vec3 inPosition;
+
+uniform mat4 model;
+uniform int cascade;
+
+void main() { gl_Position = uShadows.light_vp[cascade] * model * vec4(inPosition, 1.0); }
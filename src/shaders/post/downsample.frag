@@ -0,0 +1,45 @@
+#version 450 core
+
+layout(binding = 0) uniform sampler2D source;
+
+// On the first pass only colours above the threshold are kept
+uniform bool prefilter;
+uniform float threshold;
+
+in vec2 uv;
+
+out vec4 Color;
+
+// 13 bilinear taps in overlapping boxes, smooth enough that bright pixels don't flicker
+vec3 downsample(vec2 uv) {
+    vec2 t = 1.0 / textureSize(source, 0);
+    vec3 a = texture(source, uv + t * vec2(-2.0, 2.0)).rgb;
+    vec3 b = texture(source, uv + t * vec2(0.0, 2.0)).rgb;
+    vec3 c = texture(source, uv + t * vec2(2.0, 2.0)).rgb;
+    vec3 d = texture(source, uv + t * vec2(-2.0, 0.0)).rgb;
+    vec3 e = texture(source, uv).rgb;
+    vec3 f = texture(source, uv + t * vec2(2.0, 0.0)).rgb;
+    vec3 g = texture(source, uv + t * vec2(-2.0, -2.0)).rgb;
+    vec3 h = texture(source, uv + t * vec2(0.0, -2.0)).rgb;
+    vec3 i = texture(source, uv + t * vec2(2.0, -2.0)).rgb;
+    vec3 j = texture(source, uv + t * vec2(-1.0, 1.0)).rgb;
+    vec3 k = texture(source, uv + t * vec2(1.0, 1.0)).rgb;
+    vec3 l = texture(source, uv + t * vec2(-1.0, -1.0)).rgb;
+    vec3 m = texture(source, uv + t * vec2(1.0, -1.0)).rgb;
+    return e * 0.125 + (a + c + g + i) * 0.03125 + (b + d + f + h) * 0.0625 +
+           (j + k + l + m) * 0.125;
+}
+
+void main() {
+    vec3 color = downsample(uv);
+    if (prefilter) {
+        // Soft cut-off keeps the bloom from popping in
+        float brightness = max(color.r, max(color.g, color.b));
+        float knee = threshold * 0.5;
+        float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+        soft = soft * soft / (4.0 * knee + 0.0001);
+        float contribution = max(soft, brightness - threshold) / max(brightness, 0.0001);
+        color *= contribution;
+    }
+    Color = vec4(color, 1.0);
+}
@@ -0,0 +1,42 @@
+#version 450 core
+
+// Tone mapped, sRGB decoded to linear when sampled
+layout(binding = 0) uniform sampler2D image;
+
+in vec2 uv;
+
+out vec4 Color;
+
+const float REDUCE_MIN = 1.0 / 128.0;
+const float REDUCE_MUL = 1.0 / 8.0;
+const float SPAN_MAX = 8.0;
+
+// Perceptual, edges are found where the eye sees them
+float luma(vec3 color) { return dot(sqrt(color), vec3(0.299, 0.587, 0.114)); }
+
+// FXAA: blurs along the edges found from the luma of the neighbours
+void main() {
+    vec2 t = 1.0 / textureSize(image, 0);
+    vec3 rgb_m = texture(image, uv).rgb;
+    float luma_nw = luma(texture(image, uv + vec2(-1.0, 1.0) * t).rgb);
+    float luma_ne = luma(texture(image, uv + vec2(1.0, 1.0) * t).rgb);
+    float luma_sw = luma(texture(image, uv + vec2(-1.0, -1.0) * t).rgb);
+    float luma_se = luma(texture(image, uv + vec2(1.0, -1.0) * t).rgb);
+    float luma_m = luma(rgb_m);
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Perpendicular to the luma gradient
+    vec2 dir = vec2(-((luma_nw + luma_ne) - (luma_sw + luma_se)), (luma_nw + luma_sw) - (luma_ne + luma_se));
+    float dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    float inverse_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * inverse_dir_min, -SPAN_MAX, SPAN_MAX) * t;
+
+    vec3 rgb_a = 0.5 * (texture(image, uv + dir * (1.0 / 3.0 - 0.5)).rgb +
+                        texture(image, uv + dir * (2.0 / 3.0 - 0.5)).rgb);
+    vec3 rgb_b = rgb_a * 0.5 + 0.25 * (texture(image, uv - dir * 0.5).rgb +
+                                       texture(image, uv + dir * 0.5).rgb);
+    // The wider blur went past the edge
+    float luma_b = luma(rgb_b);
+    Color = vec4(luma_b < luma_min || luma_b > luma_max ? rgb_a : rgb_b, 1.0);
+}
@@ -0,0 +1,73 @@
+#version 450 core
+
+layout(binding = 0) uniform sampler2D scene;
+layout(binding = 1) uniform sampler2D bloom;
+
+const int TONE_MAPPER_REINHARD = 0;
+const int TONE_MAPPER_ACES = 1;
+const int TONE_MAPPER_AGX = 2;
+
+uniform float exposure;  // linear multiplier
+uniform int tone_mapper;
+uniform float bloom_intensity;
+
+in vec2 uv;
+
+// Linear, the framebuffer converts to sRGB
+out vec4 Color;
+
+vec3 reinhard(vec3 color) { return color / (1.0 + color); }
+
+// Stephen Hill's fit of the ACES reference rendering and output transforms
+vec3 aces(vec3 color) {
+    const mat3 input_matrix = mat3(0.59719, 0.07600, 0.02840, 0.35458, 0.90834, 0.13383, 0.04823,
+                                   0.01566, 0.83777);
+    const mat3 output_matrix = mat3(1.60475, -0.10208, -0.00327, -0.53108, 1.10813, -0.07276,
+                                    -0.07367, -0.00605, 1.07602);
+    color = input_matrix * color;
+    vec3 a = color * (color + 0.0245786) - 0.000090537;
+    vec3 b = color * (0.983729 * color + 0.4329510) + 0.238081;
+    return clamp(output_matrix * (a / b), 0.0, 1.0);
+}
+
+// Polynomial fit of the AgX base curve by Benjamin Wrensch
+vec3 agx_curve(vec3 x) {
+    vec3 x2 = x * x;
+    vec3 x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 +
+           0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 color) {
+    const mat3 inset = mat3(0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+                            0.0784335999999992, 0.878468636469772, 0.0784336, 0.0792237451477643,
+                            0.0791661274605434, 0.879142973793104);
+    const mat3 outset = mat3(1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+                             -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+                             -0.0990297440797205, -0.0989611768448433, 1.15107367264116);
+    const float min_ev = -12.47393;
+    const float max_ev = 4.026069;
+    color = inset * color;
+    color = clamp(log2(max(color, 1e-10)), min_ev, max_ev);
+    color = (color - min_ev) / (max_ev - min_ev);
+    color = outset * agx_curve(color);
+    // The curve produces display values
+    return pow(clamp(color, 0.0, 1.0), vec3(2.2));
+}
+
+void main() {
+    vec3 color = texture(scene, uv).rgb;
+    if (bloom_intensity > 0.0) {
+        color += texture(bloom, uv).rgb * bloom_intensity;
+    }
+    color *= exposure;
+
+    if (tone_mapper == TONE_MAPPER_REINHARD) {
+        color = reinhard(color);
+    } else if (tone_mapper == TONE_MAPPER_ACES) {
+        color = aces(color);
+    } else {
+        color = agx(color);
+    }
+    Color = vec4(color, 1.0);
+}
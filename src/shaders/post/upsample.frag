@@ -0,0 +1,19 @@
+#version 450 core
+
+layout(binding = 0) uniform sampler2D source;
+
+in vec2 uv;
+
+out vec4 Color;
+
+// 3x3 tent filter over the smaller level
+void main() {
+    vec2 t = 1.0 / textureSize(source, 0);
+    vec3 color = texture(source, uv).rgb * 4.0;
+    color += (texture(source, uv + vec2(-t.x, 0.0)).rgb + texture(source, uv + vec2(t.x, 0.0)).rgb +
+              texture(source, uv + vec2(0.0, -t.y)).rgb + texture(source, uv + vec2(0.0, t.y)).rgb) *
+             2.0;
+    color += texture(source, uv + vec2(-t.x, -t.y)).rgb + texture(source, uv + vec2(t.x, -t.y)).rgb +
+             texture(source, uv + vec2(-t.x, t.y)).rgb + texture(source, uv + vec2(t.x, t.y)).rgb;
+    Color = vec4(color / 16.0, 1.0);
+}
@@ -0,0 +1,19 @@
+#version 450 core
+
+// Fullscreen quad covering one face of a cube map, drawn as a triangle fan
+const vec2 VERTICES[] = vec2[](vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0));
+
+uniform int face;
+
+out vec3 TexCoords;
+
+void main() {
+    vec2 p = VERTICES[gl_VertexID];
+    float s = p.x;
+    float t = p.y;
+    // Directions through the face in the order of GL_TEXTURE_CUBE_MAP_POSITIVE_X + face
+    vec3 directions[6] = vec3[](vec3(1.0, -t, -s), vec3(-1.0, -t, s), vec3(s, 1.0, t),
+                                vec3(s, -1.0, -t), vec3(s, -t, 1.0), vec3(-s, -t, -1.0));
+    TexCoords = directions[face];
+    gl_Position = vec4(p, 0.0, 1.0);
+}
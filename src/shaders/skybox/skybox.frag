@@ -5,6 +5,116 @@ in vec3 TexCoords;
 
 layout(binding = 0) uniform samplerCube skybox;
 
+layout(std140, binding = 4) uniform ULight {
+    vec4 direction;  // towards the light
+    vec4 color;
+    float ambient;
+    int use_sky_ambient;
+}
+uLight;
+
+uniform bool procedural;
+uniform float haze;
+uniform bool apply_fog;
+
+// How far away the sky is as far as fog is concerned
+const float SKY_DISTANCE = 2000.0;
+
+// Single scattering through an Earth-like atmosphere, all distances in metres
+const float PI = 3.14159265359;
+const float PLANET_RADIUS = 6371e3;
+const float ATMOSPHERE_RADIUS = 6471e3;
+const vec3 RAYLEIGH_COEFFICIENT = vec3(5.5e-6, 13.0e-6, 22.4e-6);
+const float MIE_COEFFICIENT = 21e-6;
+const float RAYLEIGH_SCALE_HEIGHT = 8e3;
+const float MIE_SCALE_HEIGHT = 1.2e3;
+const float MIE_ANISOTROPY = 0.758;
+const float SUN_INTENSITY = 22.0;
+const float SUN_ANGULAR_RADIUS = 0.01;
+const int PRIMARY_STEPS = 16;
+const int LIGHT_STEPS = 8;
+
+// Distances to where the ray enters and leaves a sphere around the planet centre
+vec2 ray_sphere(vec3 origin, vec3 dir, float radius) {
+    float b = dot(origin, dir);
+    float c = dot(origin, origin) - radius * radius;
+    float d = b * b - c;
+    if (d < 0.0) {
+        return vec2(1e5, -1e5);
+    }
+    d = sqrt(d);
+    return vec2(-b - d, -b + d);
+}
+
+vec3 atmosphere(vec3 dir, vec3 sun_dir) {
+    vec3 origin = vec3(0.0, PLANET_RADIUS + 1.0, 0.0);
+    vec2 hit = ray_sphere(origin, dir, ATMOSPHERE_RADIUS);
+    float step_size = hit.y / float(PRIMARY_STEPS);
+    float mie_coefficient = MIE_COEFFICIENT * haze;
+
+    float mu = dot(dir, sun_dir);
+    float g = MIE_ANISOTROPY;
+    float phase_rayleigh = 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+    float phase_mie = 3.0 / (8.0 * PI) * ((1.0 - g * g) * (mu * mu + 1.0)) /
+                      (pow(1.0 + g * g - 2.0 * mu * g, 1.5) * (2.0 + g * g));
+
+    vec3 total_rayleigh = vec3(0.0);
+    vec3 total_mie = vec3(0.0);
+    float depth_rayleigh = 0.0;
+    float depth_mie = 0.0;
+    for (int i = 0; i < PRIMARY_STEPS; ++i) {
+        vec3 pos = origin + dir * (float(i) + 0.5) * step_size;
+        float height = length(pos) - PLANET_RADIUS;
+        float step_rayleigh = exp(-height / RAYLEIGH_SCALE_HEIGHT) * step_size;
+        float step_mie = exp(-height / MIE_SCALE_HEIGHT) * step_size;
+        depth_rayleigh += step_rayleigh;
+        depth_mie += step_mie;
+
+        // Optical depth towards the sun
+        float light_step_size = ray_sphere(pos, sun_dir, ATMOSPHERE_RADIUS).y / float(LIGHT_STEPS);
+        float light_depth_rayleigh = 0.0;
+        float light_depth_mie = 0.0;
+        for (int j = 0; j < LIGHT_STEPS; ++j) {
+            vec3 light_pos = pos + sun_dir * (float(j) + 0.5) * light_step_size;
+            float light_height = length(light_pos) - PLANET_RADIUS;
+            light_depth_rayleigh += exp(-light_height / RAYLEIGH_SCALE_HEIGHT) * light_step_size;
+            light_depth_mie += exp(-light_height / MIE_SCALE_HEIGHT) * light_step_size;
+        }
+
+        vec3 attenuation =
+            exp(-(mie_coefficient * (depth_mie + light_depth_mie) +
+                  RAYLEIGH_COEFFICIENT * (depth_rayleigh + light_depth_rayleigh)));
+        total_rayleigh += step_rayleigh * attenuation;
+        total_mie += step_mie * attenuation;
+    }
+
+    vec3 color = SUN_INTENSITY * (phase_rayleigh * RAYLEIGH_COEFFICIENT * total_rayleigh +
+                                  phase_mie * mie_coefficient * total_mie);
+
+    // Sun disc, dimmed by the air in front of it
+    vec3 transmittance = exp(-(mie_coefficient * depth_mie + RAYLEIGH_COEFFICIENT * depth_rayleigh));
+    float disc = smoothstep(cos(SUN_ANGULAR_RADIUS), cos(SUN_ANGULAR_RADIUS * 0.8), mu);
+    color += disc * SUN_INTENSITY * transmittance;
+
+    return color;
+}
+
 void main() {
-    FragColor = texture(skybox, TexCoords);  // * vec4(1.0, 0.7, 0.7, 1.0);
+    vec3 color;
+    if (procedural) {
+        // There's no ground to hit, the horizon colour continues downwards
+        vec3 dir = normalize(TexCoords);
+        dir = normalize(vec3(dir.x, max(dir.y, 0.0), dir.z));
+        color = atmosphere(dir, normalize(uLight.direction.xyz));
+    } else {
+        color = texture(skybox, TexCoords).rgb;
+    }
+
+    if (apply_fog) {
+        // Only height fog reaches the sky, so it fades into the horizon rather than covering it all
+        vec3 sky_pos = uFog.camera_position.xyz + normalize(TexCoords) * SKY_DISTANCE;
+        float fog = uFog.height_falloff > 0.0 ? fog_amount(sky_pos) : 0.0;
+        color = mix(color, uFog.color.rgb, fog);
+    }
+    FragColor = vec4(color, 1.0);
 }
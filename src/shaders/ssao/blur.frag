@@ -0,0 +1,19 @@
+#version 450 core
+
+layout(binding = 0) uniform sampler2D ao_map;
+
+in vec2 uv;
+
+out float Occlusion;
+
+// Box blur over the size of the noise tile
+void main() {
+    vec2 texel_size = 1.0 / textureSize(ao_map, 0);
+    float sum = 0.0;
+    for (int x = -2; x < 2; ++x) {
+        for (int y = -2; y < 2; ++y) {
+            sum += texture(ao_map, uv + vec2(x, y) * texel_size).r;
+        }
+    }
+    Occlusion = sum / 16.0;
+}
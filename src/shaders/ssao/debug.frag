@@ -0,0 +1,9 @@
+#version 450 core
+
+layout(binding = 0) uniform sampler2D ao_map;
+
+in vec2 uv;
+
+out vec4 Color;
+
+void main() { Color = vec4(vec3(texture(ao_map, uv).r), 1.0); }
@@ -0,0 +1,54 @@
+#version 450 core
+
+layout(binding = 0) uniform sampler2D depth_map;
+layout(binding = 1) uniform sampler2D normal_map;  // view space
+layout(binding = 2) uniform sampler2D noise_map;
+
+const int KERNEL_SIZE = 16;
+uniform vec3 kernel[KERNEL_SIZE];
+
+uniform mat4 proj;
+uniform mat4 inverse_proj;
+uniform float radius;
+uniform float intensity;
+
+in vec2 uv;
+
+out float Occlusion;
+
+vec3 view_pos(vec2 uv) {
+    float depth = texture(depth_map, uv).r;
+    vec4 pos = inverse_proj * vec4(vec3(uv, depth) * 2.0 - 1.0, 1.0);
+    return pos.xyz / pos.w;
+}
+
+void main() {
+    if (texture(depth_map, uv).r == 1.0) {
+        // Sky
+        Occlusion = 1.0;
+        return;
+    }
+    vec3 pos = view_pos(uv);
+    vec3 normal = normalize(texture(normal_map, uv).xyz);
+
+    // Rotate the kernel around the normal, the blur hides the noise pattern
+    vec2 noise_scale = textureSize(depth_map, 0) / textureSize(noise_map, 0);
+    vec3 random = vec3(texture(noise_map, uv * noise_scale).xy, 0.0);
+    vec3 tangent = normalize(random - normal * dot(random, normal));
+    vec3 bitangent = cross(normal, tangent);
+    mat3 tbn = mat3(tangent, bitangent, normal);
+
+    // Bias grows with distance to avoid self-occlusion from depth precision
+    float bias = 0.02 + 0.001 * -pos.z;
+    float occlusion = 0.0;
+    for (int i = 0; i < KERNEL_SIZE; ++i) {
+        vec3 sample_pos = pos + tbn * kernel[i] * radius;
+        vec4 offset = proj * vec4(sample_pos, 1.0);
+        vec2 sample_uv = offset.xy / offset.w * 0.5 + 0.5;
+        float sample_depth = view_pos(sample_uv).z;
+        // Geometry far in front of the point doesn't occlude it
+        float range = smoothstep(0.0, 1.0, radius / abs(pos.z - sample_depth));
+        occlusion += (sample_depth >= sample_pos.z + bias ? 1.0 : 0.0) * range;
+    }
+    Occlusion = pow(1.0 - occlusion / KERNEL_SIZE, intensity);
+}
@@ -0,0 +1,254 @@
+use gl::types::*;
+use glam::{Mat4, Vec3, Vec4};
+
+use crate::camera::{Camera, NEAR_PLANE};
+use crate::light::DirectionalLight;
+use crate::ray::AABB;
+use crate::renderer::GlState;
+
+/// Must match NUM_CASCADES in shadow_block.glsl
+pub const NUM_CASCADES: usize = 4;
+
+/// Uniform buffer binding shared by all shaders that cast or receive shadows
+const SHADOWS_BINDING: GLuint = 3;
+
+/// Texture unit the cascades are sampled from
+pub const SHADOW_MAP_UNIT: i32 = 7;
+
+/// NUM_CASCADES and UShadows, for `with_includes`
+pub const SHADOW_BLOCK_GLSL: &str = include_str!("shaders/common/shadow_block.glsl");
+
+/// calc_shadow() and tint_cascades(), for `with_includes` after SHADOW_BLOCK_GLSL
+pub const SHADOWS_GLSL: &str = include_str!("shaders/common/shadows.glsl");
+
+/// Cascade sizes to choose from. Four 4096² float depth layers already take 256 MiB.
+pub const SHADOW_MAP_SIZES: [i32; 3] = [1024, 2048, 4096];
+
+/// 0 splits the view distance evenly, 1 logarithmically
+const SPLIT_LAMBDA: f32 = 0.75;
+
+/// Must match UShadows in shadow_block.glsl
+#[repr(C)]
+struct ShadowUniforms {
+    light_vp: [Mat4; NUM_CASCADES],
+    /// View distance where each cascade ends
+    splits: Vec4,
+    /// World size of a shadow map texel in each cascade
+    texel_sizes: Vec4,
+    blend: f32,
+    show_cascades: i32,
+    _padding: [f32; 2],
+}
+
+/// Sun shadow maps, each covering a further slice of the view frustum
+pub struct ShadowCascades {
+    /// Depth texture array with a layer per cascade
+    pub texture: GLuint,
+    fbos: [GLuint; NUM_CASCADES],
+    pub size: i32,
+    ubo: GLuint,
+
+    /// How far from the camera shadows are drawn
+    pub distance: f32,
+    /// Fraction of each cascade over which it fades into the next one
+    pub blend: f32,
+    /// Tints every cascade with its own colour
+    pub show_cascades: bool,
+}
+
+impl ShadowCascades {
+    pub fn new(size: i32) -> Self {
+        let size = ShadowCascades::supported_size(size);
+        let (texture, fbos) = ShadowCascades::create_maps(size);
+        let mut ubo: GLuint = 0;
+        unsafe {
+            gl::CreateBuffers(1, &mut ubo);
+            gl::NamedBufferStorage(
+                ubo,
+                std::mem::size_of::<ShadowUniforms>() as isize,
+                std::ptr::null(),
+                gl::DYNAMIC_STORAGE_BIT,
+            );
+            gl::BindBufferBase(gl::UNIFORM_BUFFER, SHADOWS_BINDING, ubo);
+        }
+
+        ShadowCascades {
+            texture,
+            fbos,
+            size,
+            ubo,
+
+            distance: 600.0,
+            blend: 0.1,
+            show_cascades: false,
+        }
+    }
+
+    /// Clamps sizes from older settings files to what's offered and what the GPU supports
+    fn supported_size(size: i32) -> i32 {
+        let mut max_size = 0;
+        unsafe {
+            gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut max_size);
+        }
+        let max_size = max_size.min(SHADOW_MAP_SIZES[SHADOW_MAP_SIZES.len() - 1]);
+        size.clamp(SHADOW_MAP_SIZES[0].min(max_size), max_size)
+    }
+
+    /// Returns the depth texture array and a framebuffer for each of its layers
+    fn create_maps(size: i32) -> (GLuint, [GLuint; NUM_CASCADES]) {
+        let mut texture: GLuint = 0;
+        let mut fbos = [0; NUM_CASCADES];
+        unsafe {
+            gl::CreateTextures(gl::TEXTURE_2D_ARRAY, 1, &mut texture);
+            gl::TextureParameteri(texture, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TextureParameteri(texture, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::TextureParameteri(texture, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TextureParameteri(texture, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::TextureStorage3D(
+                texture,
+                1,
+                gl::DEPTH_COMPONENT32F,
+                size,
+                size,
+                NUM_CASCADES as i32,
+            );
+
+            gl::CreateFramebuffers(NUM_CASCADES as i32, fbos.as_mut_ptr());
+            for (layer, &fbo) in fbos.iter().enumerate() {
+                gl::NamedFramebufferTextureLayer(
+                    fbo,
+                    gl::DEPTH_ATTACHMENT,
+                    texture,
+                    0,
+                    layer as i32,
+                );
+                gl::NamedFramebufferDrawBuffer(fbo, gl::NONE);
+                gl::NamedFramebufferReadBuffer(fbo, gl::NONE);
+
+                assert_eq!(
+                    gl::CheckNamedFramebufferStatus(fbo, gl::FRAMEBUFFER),
+                    gl::FRAMEBUFFER_COMPLETE,
+                    "Shadow map framebuffer is incomplete",
+                );
+            }
+        }
+
+        (texture, fbos)
+    }
+
+    fn delete_maps(&self) {
+        unsafe {
+            gl::DeleteFramebuffers(NUM_CASCADES as i32, self.fbos.as_ptr());
+            gl::DeleteTextures(1, &self.texture);
+        }
+    }
+
+    pub fn resize(&mut self, size: i32) {
+        let size = ShadowCascades::supported_size(size);
+        if size == self.size {
+            return;
+        }
+        self.delete_maps();
+        let (texture, fbos) = ShadowCascades::create_maps(size);
+        self.texture = texture;
+        self.fbos = fbos;
+        self.size = size;
+    }
+
+    /// View distances where the cascades start and end
+    fn split_distances(&self) -> [f32; NUM_CASCADES + 1] {
+        let (near, far) = (NEAR_PLANE, self.distance.max(NEAR_PLANE * 2.0));
+        let mut splits = [near; NUM_CASCADES + 1];
+        for (i, split) in splits.iter_mut().enumerate().skip(1) {
+            let t = i as f32 / NUM_CASCADES as f32;
+            let logarithmic = near * (far / near).powf(t);
+            let linear = near + (far - near) * t;
+            *split = SPLIT_LAMBDA * logarithmic + (1.0 - SPLIT_LAMBDA) * linear;
+        }
+        splits
+    }
+
+    /// Fits the cascades to the camera frustum. `casters` bounds everything that casts shadows.
+    pub fn update(&self, camera: &Camera, light: &DirectionalLight, casters: &AABB) {
+        let splits = self.split_distances();
+        let mut light_vp = [Mat4::IDENTITY; NUM_CASCADES];
+        let mut texel_sizes = Vec4::ZERO;
+        for i in 0..NUM_CASCADES {
+            // Start a bit earlier so that the blend region is covered by both cascades
+            let start = if i == 0 {
+                splits[0]
+            } else {
+                splits[i] - self.blend * (splits[i] - splits[i - 1])
+            };
+            let corners = camera.frustum_corners(start, splits[i + 1]);
+            let (vp, texel_size) = self.fit_cascade(&corners, light.direction, casters);
+            light_vp[i] = vp;
+            texel_sizes[i] = texel_size;
+        }
+
+        let uniforms = ShadowUniforms {
+            light_vp,
+            splits: Vec4::new(splits[1], splits[2], splits[3], splits[4]),
+            texel_sizes,
+            blend: self.blend,
+            show_cascades: self.show_cascades as i32,
+            _padding: [0.0; 2],
+        };
+        unsafe {
+            gl::NamedBufferSubData(
+                self.ubo,
+                0,
+                std::mem::size_of::<ShadowUniforms>() as isize,
+                &uniforms as *const ShadowUniforms as *const _,
+            );
+        }
+    }
+
+    /// Returns the light view projection and the world size of a texel
+    fn fit_cascade(&self, corners: &[Vec3; 8], direction: Vec3, casters: &AABB) -> (Mat4, f32) {
+        // A bounding sphere keeps the cascade size the same while the camera turns
+        let center = corners.iter().fold(Vec3::ZERO, |sum, &corner| sum + corner) / 8.0;
+        let radius = corners
+            .iter()
+            .map(|corner| corner.distance(center))
+            .fold(0.0, f32::max);
+        let radius = (radius * 16.0).ceil() / 16.0;
+
+        let up = if direction.y.abs() > 0.99 {
+            Vec3::Z
+        } else {
+            Vec3::Y
+        };
+        let view = Mat4::look_at_rh(center - direction * radius, center, up);
+
+        // Casters between the sun and the slice have to be in the map too
+        let near = (-casters.transformed(&view).max.z).min(0.0);
+        let mut proj =
+            Mat4::orthographic_rh_gl(-radius, radius, -radius, radius, near, radius * 2.0);
+
+        // Only move in whole texels so that shadow edges don't shimmer when the camera moves
+        let half_size = self.size as f32 / 2.0;
+        let origin = (proj * view).w_axis * half_size;
+        proj.w_axis.x += (origin.x.round() - origin.x) / half_size;
+        proj.w_axis.y += (origin.y.round() - origin.y) / half_size;
+
+        (proj * view, 2.0 * radius / self.size as f32)
+    }
+
+    /// Binds the framebuffer of the cascade and clears it
+    pub fn begin_cascade(&self, gl: &mut GlState, cascade: usize) {
+        gl.bind_target(self.fbos[cascade], self.size, self.size);
+        unsafe {
+            gl::Clear(gl::DEPTH_BUFFER_BIT);
+        }
+    }
+}
+
+impl Drop for ShadowCascades {
+    fn drop(&mut self) {
+        self.delete_maps();
+        unsafe {
+            gl::DeleteBuffers(1, &self.ubo);
+        }
+    }
+}
@@ -1,15 +1,85 @@
+use std::f32::consts::PI;
+use std::fs;
 use std::mem::size_of;
+use std::path::{Path, PathBuf};
 
 use gl::types::*;
+use glam::Vec3;
+use image::Rgb32FImage;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::opengl::shader::{Program, ShaderError};
+use crate::fog::FOG_GLSL;
+use crate::light::DirectionalLight;
+use crate::opengl::shader::{with_includes, Program, ShaderError};
+use crate::renderer::{DrawItem, DrawList, GlState, Pass, RenderState};
+use crate::texture::{calculate_mip_levels, unit_to_gl_const};
 use crate::utils::size_of_slice;
 
+/// Faces of the cube map lighting is baked into
+const AMBIENT_MAP_SIZE: i32 = 32;
+
+/// Where the skybox sets live
+pub const SKYBOX_DIR: &str = "textures/skybox";
+
+/// Cube map faces in the order of GL_TEXTURE_CUBE_MAP_POSITIVE_X + i
+const FACE_NAMES: [&str; 6] = ["right", "left", "top", "bottom", "front", "back"];
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "tga", "hdr"];
+
 #[derive(Debug, Error)]
 pub enum SkyboxError {
     #[error("Skybox shader error: {0}")]
     Shader(#[from] ShaderError),
+    #[error("Couldn't load skybox image {path:?}: {source}")]
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    #[error("No {face} image in {dir:?}")]
+    MissingFace { dir: PathBuf, face: &'static str },
+    #[error("Skybox faces in {0:?} must be square and all the same size")]
+    FaceSize(PathBuf),
+    #[error("{0:?} must be twice as wide as it is high to be used as a panorama")]
+    NotEquirectangular(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkyMode {
+    /// Loaded from disk, see `Skybox::load`
+    Cubemap,
+    /// Scattering computed for the current sun direction
+    Atmosphere,
+}
+
+impl SkyMode {
+    pub const ALL: [SkyMode; 2] = [SkyMode::Cubemap, SkyMode::Atmosphere];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkyMode::Cubemap => "Cube map",
+            SkyMode::Atmosphere => "Atmosphere",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct SkySettings {
+    pub mode: SkyMode,
+    /// Scales the scattering by larger particles which brightens the sky around the sun
+    pub haze: f32,
+    /// Light the terrain with the colour of the sky rather than a flat ambient term
+    pub bake_ambient: bool,
+}
+
+impl Default for SkySettings {
+    fn default() -> Self {
+        SkySettings {
+            mode: SkyMode::Cubemap,
+            haze: 1.0,
+            bake_ambient: false,
+        }
+    }
 }
 
 pub struct Skybox {
@@ -17,11 +87,52 @@ pub struct Skybox {
     shader: Program,
     vao: GLuint,
     vbo: GLuint,
+
+    bake_shader: Program,
+    bake_fbo: GLuint,
+    /// The sky rendered into a small cube map, blurred by sampling its lower mips
+    pub ambient_map: GLuint,
+    /// What the ambient map was baked for
+    baked: Option<(Vec3, SkySettings)>,
 }
 
 impl Skybox {
-    /// right, left, top, bottom, front, back
-    pub fn from(paths: [&str; 6]) -> Result<Self, SkyboxError> {
+    /// Loads a directory with right, left, top, bottom, front and back images,
+    /// or a single equirectangular image which is converted to a cube map
+    pub fn load(path: &str) -> Result<Self, SkyboxError> {
+        let path = Path::new(path);
+        let (faces, hdr) = if path.is_dir() {
+            let paths = FACE_NAMES
+                .iter()
+                .map(|&face| find_face(path, face))
+                .collect::<Result<Vec<_>, _>>()?;
+            let hdr = paths.iter().any(|path| is_hdr(path));
+            let faces = paths
+                .iter()
+                .map(|path| open_image(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            let size = faces[0].width();
+            if faces
+                .iter()
+                .any(|face| face.width() != size || face.height() != size)
+            {
+                return Err(SkyboxError::FaceSize(path.to_owned()));
+            }
+            (faces, hdr)
+        } else {
+            let panorama = open_image(path)?;
+            if panorama.width() != panorama.height() * 2 {
+                return Err(SkyboxError::NotEquirectangular(path.to_owned()));
+            }
+            (equirect_to_faces(&panorama), is_hdr(path))
+        };
+
+        Skybox::from_faces(&faces, hdr)
+    }
+
+    /// Faces in the order right, left, top, bottom, front, back.
+    /// Values are linear if `hdr`, sRGB in [0:1] otherwise.
+    fn from_faces(faces: &[Rgb32FImage], hdr: bool) -> Result<Self, SkyboxError> {
         // Generate texture
         let mut id: GLuint = 0;
         unsafe {
@@ -55,32 +166,51 @@ impl Skybox {
             );
         }
 
-        // Load images
-        for (i, path) in paths.iter().enumerate() {
-            let img = image::open(path)
-                .expect("Can't load skybox image")
-                .into_rgb8();
-            let (width, height) = img.dimensions();
+        // Send faces to GPU
+        for (i, face) in faces.iter().enumerate() {
+            let (width, height) = face.dimensions();
+            let target = gl::TEXTURE_CUBE_MAP_POSITIVE_X + i as u32;
             unsafe {
-                // Send to GPU
-                gl::TexImage2D(
-                    gl::TEXTURE_CUBE_MAP_POSITIVE_X + i as u32,
-                    0,
-                    gl::SRGB8 as GLint,
-                    width as GLint,
-                    height as GLint,
-                    0,
-                    gl::RGB,
-                    gl::UNSIGNED_BYTE,
-                    img.as_raw().as_ptr() as *const std::ffi::c_void,
-                );
+                if hdr {
+                    gl::TexImage2D(
+                        target,
+                        0,
+                        gl::RGB16F as GLint,
+                        width as GLint,
+                        height as GLint,
+                        0,
+                        gl::RGB,
+                        gl::FLOAT,
+                        face.as_raw().as_ptr() as *const std::ffi::c_void,
+                    );
+                } else {
+                    let pixels: Vec<u8> = face
+                        .as_raw()
+                        .iter()
+                        .map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8)
+                        .collect();
+                    gl::TexImage2D(
+                        target,
+                        0,
+                        gl::SRGB8 as GLint,
+                        width as GLint,
+                        height as GLint,
+                        0,
+                        gl::RGB,
+                        gl::UNSIGNED_BYTE,
+                        pixels.as_ptr() as *const std::ffi::c_void,
+                    );
+                }
             }
         }
 
         // Create shader
         let shader = Program::new()
             .vertex_shader(include_str!("shaders/skybox/skybox.vert"))?
-            .fragment_shader(include_str!("shaders/skybox/skybox.frag"))?
+            .fragment_shader(&with_includes(
+                include_str!("shaders/skybox/skybox.frag"),
+                &[FOG_GLSL],
+            ))?
             .link()?;
         shader.set_used();
 
@@ -151,26 +281,112 @@ impl Skybox {
             gl::EnableVertexArrayAttrib(vao, 0);
         }
 
+        let bake_shader = Program::new()
+            .vertex_shader(include_str!("shaders/skybox/sky_bake.vert"))?
+            .fragment_shader(&with_includes(
+                include_str!("shaders/skybox/skybox.frag"),
+                &[FOG_GLSL],
+            ))?
+            .link()?;
+
+        let mut bake_fbo: GLuint = 0;
+        let mut ambient_map: GLuint = 0;
+        unsafe {
+            gl::CreateTextures(gl::TEXTURE_CUBE_MAP, 1, &mut ambient_map);
+            gl::TextureParameteri(
+                ambient_map,
+                gl::TEXTURE_MIN_FILTER,
+                gl::LINEAR_MIPMAP_LINEAR as GLint,
+            );
+            gl::TextureParameteri(ambient_map, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            gl::TextureStorage2D(
+                ambient_map,
+                calculate_mip_levels(AMBIENT_MAP_SIZE as usize, AMBIENT_MAP_SIZE as usize),
+                gl::RGBA16F,
+                AMBIENT_MAP_SIZE,
+                AMBIENT_MAP_SIZE,
+            );
+            gl::CreateFramebuffers(1, &mut bake_fbo);
+        }
+
         Ok(Skybox {
             id,
             shader,
             vao,
             vbo,
+
+            bake_shader,
+            bake_fbo,
+            ambient_map,
+            baked: None,
         })
     }
 
-    pub fn draw(&self) {
+    /// Re-renders the ambient map if the sun or the sky has changed since the last time
+    pub fn bake_ambient(
+        &mut self,
+        gl: &mut GlState,
+        sky: &SkySettings,
+        light: &DirectionalLight,
+    ) -> Result<(), SkyboxError> {
+        if !sky.bake_ambient || self.baked == Some((light.direction, *sky)) {
+            return Ok(());
+        }
+        self.baked = Some((light.direction, *sky));
+
+        self.bake_shader.set_used();
+        self.bake_shader
+            .set_i32("procedural", (sky.mode == SkyMode::Atmosphere) as i32)?;
+        self.bake_shader.set_f32("haze", sky.haze)?;
+        self.bake_shader.set_i32("apply_fog", 0)?;
+        gl.set(RenderState::FULLSCREEN);
+        gl.bind_target(self.bake_fbo, AMBIENT_MAP_SIZE, AMBIENT_MAP_SIZE);
+        unsafe {
+            gl::BindVertexArray(self.vao);
+            gl::ActiveTexture(unit_to_gl_const(0));
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, self.id);
+        }
+        for face in 0..6 {
+            self.bake_shader.set_i32("face", face)?;
+            unsafe {
+                gl::NamedFramebufferTextureLayer(
+                    self.bake_fbo,
+                    gl::COLOR_ATTACHMENT0,
+                    self.ambient_map,
+                    0,
+                    face,
+                );
+                gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+            }
+        }
         unsafe {
-            gl::DepthFunc(gl::LEQUAL);
+            gl::GenerateTextureMipmap(self.ambient_map);
         }
+
+        Ok(())
+    }
+
+    /// Fills in where nothing else was drawn
+    pub fn submit(&self, draw_list: &mut DrawList) {
+        draw_list.submit(Pass::Sky, DrawItem::Sky);
+    }
+
+    /// Expects the depth test to pass at the far plane
+    pub fn draw(&self, sky: &SkySettings) -> Result<(), SkyboxError> {
         self.shader.set_used();
+        self.shader
+            .set_i32("procedural", (sky.mode == SkyMode::Atmosphere) as i32)?;
+        self.shader.set_f32("haze", sky.haze)?;
+        self.shader.set_i32("apply_fog", 1)?;
 
         unsafe {
             gl::BindVertexArray(self.vao);
+            gl::ActiveTexture(unit_to_gl_const(0));
             gl::BindTexture(gl::TEXTURE_CUBE_MAP, self.id);
             gl::DrawArrays(gl::TRIANGLES, 0, 36);
-            gl::DepthFunc(gl::LESS);
         }
+
+        Ok(())
     }
 }
 
@@ -179,6 +395,101 @@ impl Drop for Skybox {
         unsafe {
             gl::DeleteBuffers(1, &self.vbo as *const _);
             gl::DeleteVertexArrays(1, &self.vao as *const _);
+            gl::DeleteTextures(1, &self.id);
+            gl::DeleteFramebuffers(1, &self.bake_fbo);
+            gl::DeleteTextures(1, &self.ambient_map);
         }
     }
 }
+
+/// Skybox sets in SKYBOX_DIR, as paths for `Skybox::load`
+pub fn list_skyboxes() -> Vec<String> {
+    let mut skyboxes: Vec<String> = match fs::read_dir(SKYBOX_DIR) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir() || has_image_extension(path))
+            .map(|path| path.to_string_lossy().replace('\\', "/"))
+            .collect(),
+        Err(_) => vec![],
+    };
+    skyboxes.sort();
+    skyboxes
+}
+
+fn has_image_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+fn is_hdr(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("hdr"))
+}
+
+fn find_face(dir: &Path, face: &'static str) -> Result<PathBuf, SkyboxError> {
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", face, ext)))
+        .find(|path| path.exists())
+        .ok_or_else(|| SkyboxError::MissingFace {
+            dir: dir.to_owned(),
+            face,
+        })
+}
+
+fn open_image(path: &Path) -> Result<Rgb32FImage, SkyboxError> {
+    let img = image::open(path).map_err(|source| SkyboxError::Image {
+        path: path.to_owned(),
+        source,
+    })?;
+    Ok(img.into_rgb32f())
+}
+
+/// Same as in sky_bake.vert, `s` and `t` are [-1:1] across the face
+fn face_direction(face: usize, s: f32, t: f32) -> Vec3 {
+    match face {
+        0 => Vec3::new(1.0, -t, -s),
+        1 => Vec3::new(-1.0, -t, s),
+        2 => Vec3::new(s, 1.0, t),
+        3 => Vec3::new(s, -1.0, -t),
+        4 => Vec3::new(s, -t, 1.0),
+        _ => Vec3::new(-s, -t, -1.0),
+    }
+}
+
+/// Resamples a panorama with -Z in the middle into six cube map faces
+fn equirect_to_faces(panorama: &Rgb32FImage) -> Vec<Rgb32FImage> {
+    let size = panorama.height() / 2;
+    (0..6)
+        .map(|face| {
+            Rgb32FImage::from_fn(size, size, |x, y| {
+                let s = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let t = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let dir = face_direction(face, s, t).normalize();
+                let u = 0.5 + dir.x.atan2(-dir.z) / (2.0 * PI);
+                let v = 0.5 - dir.y.clamp(-1.0, 1.0).asin() / PI;
+                image::Rgb(sample_panorama(panorama, u, v))
+            })
+        })
+        .collect()
+}
+
+/// Bilinear filtering, wrapping around horizontally
+fn sample_panorama(panorama: &Rgb32FImage, u: f32, v: f32) -> [f32; 3] {
+    let (width, height) = (panorama.width() as i64, panorama.height() as i64);
+    let x = u * width as f32 - 0.5;
+    let y = v * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let pixel = |x: i64, y: i64| {
+        let x = x.rem_euclid(width) as u32;
+        let y = y.clamp(0, height - 1) as u32;
+        Vec3::from(panorama.get_pixel(x, y).0)
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = pixel(x0, y0).lerp(pixel(x0 + 1, y0), tx);
+    let bottom = pixel(x0, y0 + 1).lerp(pixel(x0 + 1, y0 + 1), tx);
+    top.lerp(bottom, ty).to_array()
+}
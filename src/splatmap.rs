@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::Path;
+
+use gl::types::*;
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+use crate::opengl::shader::Program;
+use crate::renderer::{GlState, RenderState};
+use crate::texture::unit_to_gl_const;
+use crate::Result;
+
+/// One splat map channel per rule
+pub const MAX_MATERIAL_RULES: usize = 4;
+
+/// Decides where a terrain material goes based on height and slope.
+/// Rules are applied in order, so later rules are painted over the earlier ones.
+/// The first rule is the base material and its ranges are ignored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaterialRule {
+    pub name: String,
+    pub color: Vec3,
+    pub min_height: f32,
+    pub max_height: f32,
+    /// Degrees
+    pub min_slope: f32,
+    /// Degrees
+    pub max_slope: f32,
+    /// Width of the transition at the rule borders, as a fraction of the ranges
+    pub blend: f32,
+    /// How much the borders are broken up by noise
+    pub noise: f32,
+}
+
+impl MaterialRule {
+    pub fn new(name: &str, color: Vec3) -> Self {
+        MaterialRule {
+            name: name.to_owned(),
+            color,
+            min_height: 0.0,
+            max_height: 10_000.0,
+            min_slope: 0.0,
+            max_slope: 90.0,
+            blend: 0.05,
+            noise: 0.5,
+        }
+    }
+}
+
+pub fn default_material_rules() -> Vec<MaterialRule> {
+    vec![
+        MaterialRule::new("Grass", Vec3::new(0.22, 0.4, 0.12)),
+        MaterialRule {
+            min_slope: 35.0,
+            ..MaterialRule::new("Rock", Vec3::new(0.4, 0.37, 0.33))
+        },
+        MaterialRule {
+            min_height: 160.0,
+            max_slope: 45.0,
+            ..MaterialRule::new("Snow", Vec3::new(0.9, 0.92, 0.95))
+        },
+    ]
+}
+
+const RULES_FILE_NAME: &str = "materials.json";
+
+/// The rules saved with a terrain by `save_material_rules`, or the defaults if there are none
+pub fn load_material_rules(dir: &Path) -> Result<Vec<MaterialRule>> {
+    let path = dir.join(RULES_FILE_NAME);
+    if !path.exists() {
+        return Ok(default_material_rules());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+pub fn save_material_rules(dir: &Path, rules: &[MaterialRule]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(
+        dir.join(RULES_FILE_NAME),
+        serde_json::to_string_pretty(rules)?,
+    )?;
+    Ok(())
+}
+
+/// Material weights for the terrain, one RGBA channel per rule
+pub struct SplatMap {
+    pub texture: GLuint,
+    texture_size: usize,
+
+    fbo: GLuint,
+    shader: Program,
+}
+
+impl SplatMap {
+    pub fn new(texture_size: usize) -> Result<Self> {
+        let mut texture: GLuint = 0;
+        let mut fbo: GLuint = 0;
+        unsafe {
+            gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture);
+            gl::TextureParameteri(texture, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+            gl::TextureParameteri(texture, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+            gl::TextureParameteri(texture, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+            gl::TextureParameteri(texture, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            gl::TextureStorage2D(
+                texture,
+                1,
+                gl::RGBA8,
+                texture_size as i32,
+                texture_size as i32,
+            );
+
+            gl::CreateFramebuffers(1, &mut fbo);
+            gl::NamedFramebufferTexture(fbo, gl::COLOR_ATTACHMENT0, texture, 0);
+            let draw_buffers = [gl::COLOR_ATTACHMENT0];
+            gl::NamedFramebufferDrawBuffers(fbo, 1, draw_buffers.as_ptr() as *const _);
+            assert_eq!(
+                gl::CheckNamedFramebufferStatus(fbo, gl::FRAMEBUFFER),
+                gl::FRAMEBUFFER_COMPLETE,
+                "Splat map framebuffer is incomplete",
+            );
+        }
+
+        let shader = Program::new()
+            .vertex_shader(include_str!("shaders/editor/terrain/heightmap.vert"))?
+            .fragment_shader(include_str!("shaders/editor/terrain/splatmap.frag.glsl"))?
+            .link()?;
+
+        Ok(SplatMap {
+            texture,
+            texture_size,
+
+            fbo,
+            shader,
+        })
+    }
+
+    /// Evaluates the rules against the heightmap and overwrites all weights
+    pub fn generate(
+        &self,
+        gl: &mut GlState,
+        rules: &[MaterialRule],
+        heightmap: GLuint,
+        terrain_size: f32,
+        max_height: f32,
+    ) -> Result<()> {
+        let num_rules = rules.len().min(MAX_MATERIAL_RULES);
+
+        self.shader.set_used();
+        self.shader.set_i32("num_rules", num_rules as i32)?;
+        self.shader.set_f32("terrain_size", terrain_size)?;
+        self.shader.set_f32("terrain_max_height", max_height)?;
+        for (i, rule) in rules.iter().enumerate().take(num_rules).skip(1) {
+            let uniform = |field: &str| format!("rules[{}].{}", i, field);
+            self.shader
+                .set_f32(&uniform("min_height"), rule.min_height)?;
+            self.shader
+                .set_f32(&uniform("max_height"), rule.max_height)?;
+            self.shader.set_f32(&uniform("min_slope"), rule.min_slope)?;
+            self.shader.set_f32(&uniform("max_slope"), rule.max_slope)?;
+            self.shader.set_f32(&uniform("blend"), rule.blend)?;
+            self.shader.set_f32(&uniform("noise"), rule.noise)?;
+        }
+
+        gl.set(RenderState::DATA);
+        gl.bind_target(self.fbo, self.texture_size as i32, self.texture_size as i32);
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(1));
+            gl::BindTexture(gl::TEXTURE_2D, heightmap);
+
+            gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for SplatMap {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.fbo);
+            gl::DeleteTextures(1, &self.texture);
+        }
+    }
+}
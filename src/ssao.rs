@@ -0,0 +1,305 @@
+use std::f32::consts::PI;
+
+use gl::types::*;
+use glam::{Mat4, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::opengl::shader::Program;
+use crate::renderer::{GlState, RenderState};
+use crate::texture::unit_to_gl_const;
+use crate::Result;
+
+/// Must match KERNEL_SIZE in ssao.frag
+const KERNEL_SIZE: usize = 16;
+
+/// The noise texture tiles the screen, the blur averages over one tile
+const NOISE_SIZE: i32 = 4;
+
+/// Texture unit the lit shaders read the occlusion from
+pub const AO_UNIT: i32 = 8;
+
+/// Screen-space ambient occlusion, darkens the ambient light in creases and corners
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct AmbientOcclusion {
+    pub enabled: bool,
+    /// Metres around a point that can occlude it
+    pub radius: f32,
+    /// Exponent applied to the occlusion
+    pub intensity: f32,
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> Self {
+        AmbientOcclusion {
+            enabled: true,
+            radius: 1.5,
+            intensity: 1.5,
+        }
+    }
+}
+
+pub struct Ssao {
+    pub settings: AmbientOcclusion,
+    /// Shows the occlusion instead of the scene
+    pub show: bool,
+
+    width: i32,
+    height: i32,
+
+    /// Depth and view space normals of the scene
+    prepass_fbo: GLuint,
+    depth_texture: GLuint,
+    normal_texture: GLuint,
+
+    /// Raw occlusion, then blurred into the texture the scene reads
+    ao_fbo: GLuint,
+    ao_texture: GLuint,
+    blur_fbo: GLuint,
+    blurred_texture: GLuint,
+    noise_texture: GLuint,
+
+    /// Fullscreen triangles are generated from gl_VertexID but a VAO must still be bound
+    vao: GLuint,
+    ssao_shader: Program,
+    blur_shader: Program,
+    debug_shader: Program,
+}
+
+impl Ssao {
+    pub fn new(settings: AmbientOcclusion, width: i32, height: i32) -> Result<Self> {
+        let ssao_shader = Program::new()
+            .vertex_shader(include_str!("shaders/fullscreen.vert"))?
+            .fragment_shader(include_str!("shaders/ssao/ssao.frag"))?
+            .link()?;
+        ssao_shader.set_used();
+        for (i, sample) in Ssao::kernel().iter().enumerate() {
+            ssao_shader.set_vec3(&format!("kernel[{}]", i), sample)?;
+        }
+        let blur_shader = Program::new()
+            .vertex_shader(include_str!("shaders/fullscreen.vert"))?
+            .fragment_shader(include_str!("shaders/ssao/blur.frag"))?
+            .link()?;
+        let debug_shader = Program::new()
+            .vertex_shader(include_str!("shaders/fullscreen.vert"))?
+            .fragment_shader(include_str!("shaders/ssao/debug.frag"))?
+            .link()?;
+
+        let depth_texture = create_target(gl::DEPTH_COMPONENT32F, width, height);
+        let normal_texture = create_target(gl::RGBA16F, width, height);
+        let ao_texture = create_target(gl::R8, width, height);
+        let blurred_texture = create_target(gl::R8, width, height);
+        let noise_texture = Ssao::create_noise_texture();
+
+        let mut prepass_fbo: GLuint = 0;
+        let mut ao_fbo: GLuint = 0;
+        let mut blur_fbo: GLuint = 0;
+        let mut vao: GLuint = 0;
+        unsafe {
+            gl::CreateFramebuffers(1, &mut prepass_fbo);
+            gl::NamedFramebufferTexture(prepass_fbo, gl::DEPTH_ATTACHMENT, depth_texture, 0);
+            gl::NamedFramebufferTexture(prepass_fbo, gl::COLOR_ATTACHMENT0, normal_texture, 0);
+
+            gl::CreateFramebuffers(1, &mut ao_fbo);
+            gl::NamedFramebufferTexture(ao_fbo, gl::COLOR_ATTACHMENT0, ao_texture, 0);
+
+            gl::CreateFramebuffers(1, &mut blur_fbo);
+            gl::NamedFramebufferTexture(blur_fbo, gl::COLOR_ATTACHMENT0, blurred_texture, 0);
+
+            for &fbo in [prepass_fbo, ao_fbo, blur_fbo].iter() {
+                assert_eq!(
+                    gl::CheckNamedFramebufferStatus(fbo, gl::FRAMEBUFFER),
+                    gl::FRAMEBUFFER_COMPLETE,
+                    "SSAO framebuffer is incomplete",
+                );
+            }
+
+            gl::CreateVertexArrays(1, &mut vao);
+        }
+
+        Ok(Ssao {
+            settings,
+            show: false,
+
+            width,
+            height,
+
+            prepass_fbo,
+            depth_texture,
+            normal_texture,
+
+            ao_fbo,
+            ao_texture,
+            blur_fbo,
+            blurred_texture,
+            noise_texture,
+
+            vao,
+            ssao_shader,
+            blur_shader,
+            debug_shader,
+        })
+    }
+
+    /// Points in a unit hemisphere around +Z, denser near the center
+    fn kernel() -> [Vec3; KERNEL_SIZE] {
+        let mut kernel = [Vec3::ZERO; KERNEL_SIZE];
+        // Golden angle spiral so that the samples don't line up
+        let golden_angle = PI * (3.0 - 5.0f32.sqrt());
+        for (i, sample) in kernel.iter_mut().enumerate() {
+            let t = (i as f32 + 0.5) / KERNEL_SIZE as f32;
+            let z = 1.0 - t;
+            let r = (1.0 - z * z).sqrt();
+            let angle = golden_angle * i as f32;
+            let direction = Vec3::new(r * angle.cos(), r * angle.sin(), z);
+            let scale = 0.1 + 0.9 * t * t;
+            *sample = direction * scale;
+        }
+        kernel
+    }
+
+    /// Rotations of the kernel around the normal, different for each pixel of a tile
+    fn create_noise_texture() -> GLuint {
+        let count = (NOISE_SIZE * NOISE_SIZE) as usize;
+        let mut noise = Vec::with_capacity(count * 2);
+        for i in 0..count {
+            // Neighbouring pixels get angles far apart
+            let angle = ((i * 7) % count) as f32 / count as f32 * 2.0 * PI;
+            noise.push(angle.cos());
+            noise.push(angle.sin());
+        }
+
+        let mut texture: GLuint = 0;
+        unsafe {
+            gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture);
+            gl::TextureParameteri(texture, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+            gl::TextureParameteri(texture, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+            gl::TextureParameteri(texture, gl::TEXTURE_WRAP_S, gl::REPEAT as GLint);
+            gl::TextureParameteri(texture, gl::TEXTURE_WRAP_T, gl::REPEAT as GLint);
+            gl::TextureStorage2D(texture, 1, gl::RG16F, NOISE_SIZE, NOISE_SIZE);
+            gl::TextureSubImage2D(
+                texture,
+                0,
+                0,
+                0,
+                NOISE_SIZE,
+                NOISE_SIZE,
+                gl::RG,
+                gl::FLOAT,
+                noise.as_ptr() as *const _,
+            );
+        }
+        texture
+    }
+
+    /// Binds and clears the framebuffer for the depth and normal prepass
+    pub fn begin_prepass(&self, gl: &mut GlState) {
+        gl.bind_target(self.prepass_fbo, self.width, self.height);
+        unsafe {
+            gl::ClearNamedFramebufferfv(self.prepass_fbo, gl::COLOR, 0, [0.0f32; 4].as_ptr());
+            gl::Clear(gl::DEPTH_BUFFER_BIT);
+        }
+    }
+
+    /// Turns the prepass into blurred occlusion and binds it for the lit shaders
+    pub fn compute(&self, gl: &mut GlState, proj: &Mat4) -> Result<()> {
+        gl.set(RenderState::DATA);
+        gl.bind_target(self.ao_fbo, self.width, self.height);
+        unsafe {
+            gl::BindVertexArray(self.vao);
+            gl::ActiveTexture(unit_to_gl_const(0));
+            gl::BindTexture(gl::TEXTURE_2D, self.depth_texture);
+            gl::ActiveTexture(unit_to_gl_const(1));
+            gl::BindTexture(gl::TEXTURE_2D, self.normal_texture);
+            gl::ActiveTexture(unit_to_gl_const(2));
+            gl::BindTexture(gl::TEXTURE_2D, self.noise_texture);
+        }
+        self.ssao_shader.set_used();
+        self.ssao_shader.set_mat4("proj", proj)?;
+        self.ssao_shader.set_mat4("inverse_proj", &proj.inverse())?;
+        self.ssao_shader.set_f32("radius", self.settings.radius)?;
+        self.ssao_shader
+            .set_f32("intensity", self.settings.intensity)?;
+        unsafe {
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+        }
+
+        gl.bind_target(self.blur_fbo, self.width, self.height);
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(0));
+            gl::BindTexture(gl::TEXTURE_2D, self.ao_texture);
+        }
+        self.blur_shader.set_used();
+        unsafe {
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+        }
+        self.bind();
+        Ok(())
+    }
+
+    /// No occlusion anywhere
+    pub fn clear(&self) {
+        let one: f32 = 1.0;
+        unsafe {
+            gl::ClearTexImage(
+                self.blurred_texture,
+                0,
+                gl::RED,
+                gl::FLOAT,
+                &one as *const f32 as *const _,
+            );
+        }
+        self.bind();
+    }
+
+    fn bind(&self) {
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(AO_UNIT));
+            gl::BindTexture(gl::TEXTURE_2D, self.blurred_texture);
+        }
+    }
+
+    /// Draws the occlusion over the whole screen
+    pub fn draw_debug(&self, gl: &mut GlState) {
+        gl.set(RenderState::FULLSCREEN);
+        self.debug_shader.set_used();
+        unsafe {
+            gl::BindVertexArray(self.vao);
+            gl::ActiveTexture(unit_to_gl_const(0));
+            gl::BindTexture(gl::TEXTURE_2D, self.blurred_texture);
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+        }
+    }
+}
+
+impl Drop for Ssao {
+    fn drop(&mut self) {
+        let fbos = [self.prepass_fbo, self.ao_fbo, self.blur_fbo];
+        let textures = [
+            self.depth_texture,
+            self.normal_texture,
+            self.ao_texture,
+            self.blurred_texture,
+            self.noise_texture,
+        ];
+        unsafe {
+            gl::DeleteFramebuffers(fbos.len() as i32, fbos.as_ptr());
+            gl::DeleteTextures(textures.len() as i32, textures.as_ptr());
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
+
+/// Screen sized texture read with texelFetch
+fn create_target(format: GLenum, width: i32, height: i32) -> GLuint {
+    let mut texture: GLuint = 0;
+    unsafe {
+        gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture);
+        gl::TextureParameteri(texture, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+        gl::TextureParameteri(texture, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+        gl::TextureParameteri(texture, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+        gl::TextureParameteri(texture, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+        gl::TextureStorage2D(texture, 1, format, width, height);
+    }
+    texture
+}
@@ -1,61 +1,125 @@
 use std::ffi::c_void;
+use std::fs;
+use std::path::Path;
 
 use gl::types::*;
 use glam::Vec3Swizzles;
-use glam::{Vec2, Vec3};
+use glam::{Mat2, Vec2, Vec3};
 use image::GenericImageView;
-
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::fog::FOG_GLSL;
+use crate::heightfield::Heightfield;
+use crate::layers::{layers_dir, read_floats, write_floats, LayerInfo, LayerStack};
+use crate::player::Ground;
+use crate::shadows::{ShadowCascades, SHADOWS_GLSL, SHADOW_BLOCK_GLSL, SHADOW_MAP_UNIT};
+use crate::splatmap::{MaterialRule, SplatMap, MAX_MATERIAL_RULES};
 use crate::texture::{calculate_mip_levels, get_max_anisotropy, unit_to_gl_const};
 use crate::{
-    opengl::shader::Program,
+    opengl::shader::{with_includes, Program},
     ray::{Ray, AABB},
+    renderer::{Blend, DrawItem, DrawList, GlState, Pass, RenderState},
     utils::vec2_infinity,
     Result,
 };
-use crate::{WINDOW_HEIGHT, WINDOW_WIDTH};
 
-struct Heightmap {
-    texture: GLuint,
-    texture_size: usize,
+#[derive(Debug, Error)]
+pub enum HeightmapError {
+    #[error("{path:?} is {width}x{height}, only square heightmaps are supported")]
+    NotSquare {
+        path: String,
+        width: u32,
+        height: u32,
+    },
+    #[error("{path:?} is {size}x{size}, only sizes 1024, 2048 and 4096 are supported")]
+    UnsupportedSize { path: String, size: u32 },
+}
+
+pub(crate) struct Heightmap {
+    pub texture: GLuint,
+    pub texture_size: usize,
 
     // For drawing on heightmap
     fbo: GLuint,
-    shader: Program,
 }
 
 impl Heightmap {
-    pub fn flat(texture_size: usize) -> Result<Self> {
-        Heightmap::new(None, Some(texture_size))
+    pub fn flat(texture_size: usize) -> Self {
+        Heightmap::from_heights(texture_size, &vec![0.0; texture_size * texture_size])
     }
 
     pub fn from_image(path: &str) -> Result<Self> {
-        Heightmap::new(Some(path), None)
+        let img = image::open(path)?;
+        let (width, height) = img.dimensions();
+        if width != height {
+            return Err(HeightmapError::NotSquare {
+                path: path.to_owned(),
+                width,
+                height,
+            }
+            .into());
+        }
+        if !(width == 1024 || width == 2048 || width == 4096) {
+            return Err(HeightmapError::UnsupportedSize {
+                path: path.to_owned(),
+                size: width,
+            }
+            .into());
+        }
+        let pixels = img.into_luma16().into_raw();
+        let texture_size = width as usize;
+
+        // 16 bit values get normalised to [0, 1]
+        let texture = Heightmap::create_texture(texture_size);
+        unsafe {
+            gl::TextureSubImage2D(
+                texture,
+                0,
+                0,
+                0,
+                texture_size as i32,
+                texture_size as i32,
+                gl::RED,
+                gl::UNSIGNED_SHORT,
+                pixels.as_ptr() as *const _,
+            );
+        }
+
+        Ok(Heightmap::with_texture(texture, texture_size))
     }
 
-    fn new(path: Option<&str>, texture_size: Option<usize>) -> Result<Self> {
-        debug_assert!(!(path.is_some() && texture_size.is_some()));
-        debug_assert!(!(path.is_none() && texture_size.is_none()));
+    /// Heights are normalised [0:1], but sculpt layers can go outside that range
+    pub fn from_heights(texture_size: usize, heights: &[f32]) -> Self {
+        let texture = Heightmap::create_texture(texture_size);
+        let heightmap = Heightmap::with_texture(texture, texture_size);
+        heightmap.write_heights(heights);
+        heightmap
+    }
 
-        let (pixels, texture_size) = if let Some(path) = path {
-            let img = image::open(path)?;
-            let (width, height) = img.dimensions();
-            assert_eq!(width, height, "Only square heightmaps are supported");
-            assert!(
-                width == 1024 || width == 2048 || width == 4096,
-                "Only heightmaps with sizes 1024, 2048 and 4096 are supported"
-            );
+    fn with_texture(texture: GLuint, texture_size: usize) -> Self {
+        // Framebuffer object for rendering to heightmap
+        let mut fbo: GLuint = 0;
+        unsafe {
+            gl::CreateFramebuffers(1, &mut fbo);
+            let draw_buffers = [gl::COLOR_ATTACHMENT0];
+            gl::NamedFramebufferDrawBuffers(fbo, 1, draw_buffers.as_ptr() as *const _);
+        }
+        Heightmap::attach_to_framebuffer(fbo, texture);
 
-            (img.into_luma16().into_raw(), width as usize)
-        } else {
-            let size = texture_size.unwrap();
+        Heightmap {
+            texture,
+            texture_size,
 
-            (vec![0u16; size * size], size)
-        };
+            fbo,
+        }
+    }
 
+    /// Floats, so that small brush strokes accumulate without quantisation
+    fn create_texture(texture_size: usize) -> GLuint {
         let mut texture: GLuint = 0;
         unsafe {
             gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture);
-            gl::BindTexture(gl::TEXTURE_2D, texture);
             gl::TextureParameteri(texture, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
             gl::TextureParameteri(texture, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
             gl::TextureParameteri(texture, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
@@ -63,96 +127,119 @@ impl Heightmap {
             gl::TextureStorage2D(
                 texture,
                 1,
-                gl::R16,
-                texture_size as i32,
-                texture_size as i32,
-            );
-
-            gl::TextureSubImage2D(
-                texture,
-                0,
-                0,
-                0,
+                gl::R32F,
                 texture_size as i32,
                 texture_size as i32,
-                gl::RED,
-                gl::UNSIGNED_SHORT,
-                pixels.as_ptr() as *const _,
             );
         }
+        texture
+    }
 
-        // Framebuffer object for rendering to heightmap
-        let mut fbo: GLuint = 0;
+    fn attach_to_framebuffer(fbo: GLuint, texture: GLuint) {
         unsafe {
-            gl::CreateFramebuffers(1, &mut fbo);
             gl::NamedFramebufferTexture(fbo, gl::COLOR_ATTACHMENT0, texture, 0);
-            let draw_buffers = [gl::COLOR_ATTACHMENT0];
-            gl::NamedFramebufferDrawBuffers(fbo, 1, draw_buffers.as_ptr() as *const _);
             assert_eq!(
                 gl::CheckNamedFramebufferStatus(fbo, gl::FRAMEBUFFER),
                 gl::FRAMEBUFFER_COMPLETE,
                 "Heightmap texture framebuffer is incomplete",
             );
         }
+    }
 
-        let shader = Program::new()
-            .vertex_shader(include_str!("shaders/editor/terrain/heightmap.vert"))?
-            .fragment_shader(include_str!("shaders/editor/terrain/heightmap.frag"))?
-            .link()?;
+    pub fn fbo(&self) -> GLuint {
+        self.fbo
+    }
 
-        Ok(Heightmap {
-            texture,
-            texture_size,
+    pub fn read_heights(&self) -> Vec<f32> {
+        let pixel_count = self.texture_size * self.texture_size;
+        let mut heights = vec![0f32; pixel_count];
+        unsafe {
+            gl::GetTextureImage(
+                self.texture,
+                0,
+                gl::RED,
+                gl::FLOAT,
+                (pixel_count * std::mem::size_of::<f32>()) as i32,
+                heights.as_mut_ptr() as *mut c_void,
+            );
+        }
+        heights
+    }
 
-            fbo,
-            shader,
-        })
+    pub fn write_heights(&self, heights: &[f32]) {
+        assert_eq!(heights.len(), self.texture_size * self.texture_size);
+        unsafe {
+            gl::TextureSubImage2D(
+                self.texture,
+                0,
+                0,
+                0,
+                self.texture_size as i32,
+                self.texture_size as i32,
+                gl::RED,
+                gl::FLOAT,
+                heights.as_ptr() as *const _,
+            );
+        }
+    }
+
+    pub fn fill(&self, value: f32) {
+        unsafe {
+            gl::ClearTexImage(
+                self.texture,
+                0,
+                gl::RED,
+                gl::FLOAT,
+                &value as *const f32 as *const _,
+            );
+        }
     }
 
-    fn draw_on_heightmap(
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_on_heightmap(
         &self,
+        gl: &mut GlState,
+        shader: &Program,
         cursor: Vec2,
+        brush_transform: &Mat2,
         brush: &Brush,
         terrain_size: f32,
         delta_time: f32,
         raise: bool,
     ) {
-        self.shader.set_used();
+        shader.set_used();
         debug_assert!(cursor.x <= 1.0 && cursor.x >= 0.0);
         debug_assert!(cursor.y <= 1.0 && cursor.y >= 0.0);
-        self.shader.set_vec2("cursor", &cursor).unwrap();
+        shader.set_vec2("cursor", &cursor).unwrap();
+        shader.set_mat2("brush_transform", brush_transform).unwrap();
         let brush_size = brush.size as f32 / terrain_size;
-        self.shader.set_f32("brush_size", brush_size).unwrap();
-        self.shader.set_f32("delta_time", delta_time).unwrap();
+        shader.set_f32("brush_size", brush_size).unwrap();
+        shader.set_f32("delta_time", delta_time).unwrap();
 
+        gl.set(RenderState {
+            blend: if raise {
+                Blend::Add
+            } else {
+                Blend::ReverseSubtract
+            },
+            ..RenderState::DATA
+        });
+        gl.bind_target(self.fbo, self.texture_size as i32, self.texture_size as i32);
         unsafe {
-            gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo);
-            gl::Disable(gl::FRAMEBUFFER_SRGB);
-            gl::Viewport(0, 0, self.texture_size as i32, self.texture_size as i32);
-
             gl::ActiveTexture(unit_to_gl_const(0));
             gl::BindTexture(gl::TEXTURE_2D, brush.texture);
 
-            gl::Enable(gl::BLEND);
-            gl::Disable(gl::DEPTH_TEST);
-
-            gl::BlendFunc(gl::ONE, gl::ONE);
-            gl::BlendEquation(if raise {
-                gl::FUNC_ADD
-            } else {
-                gl::FUNC_REVERSE_SUBTRACT
-            });
-
             gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
             gl::MemoryBarrier(gl::FRAMEBUFFER_BARRIER_BIT); // not critical
+        }
+    }
+}
 
-            // Reset everything back
-            gl::Disable(gl::BLEND);
-            gl::Enable(gl::DEPTH_TEST);
-            gl::BlendEquation(gl::FUNC_ADD);
-            gl::Enable(gl::FRAMEBUFFER_SRGB);
-            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
-            gl::Viewport(0, 0, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32);
+impl Drop for Heightmap {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.fbo);
+            gl::DeleteTextures(1, &self.texture);
         }
     }
 }
@@ -212,6 +299,252 @@ impl Brush {
     }
 }
 
+/// What to draw over the terrain to help judge its shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayMode {
+    None,
+    Slope,
+    Curvature,
+    HeightBands,
+    Contours,
+}
+
+impl OverlayMode {
+    pub const ALL: [OverlayMode; 5] = [
+        OverlayMode::None,
+        OverlayMode::Slope,
+        OverlayMode::Curvature,
+        OverlayMode::HeightBands,
+        OverlayMode::Contours,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverlayMode::None => "None",
+            OverlayMode::Slope => "Slope",
+            OverlayMode::Curvature => "Curvature",
+            OverlayMode::HeightBands => "Height bands",
+            OverlayMode::Contours => "Contour lines",
+        }
+    }
+
+    /// Must match the OVERLAY_* constants in terrain.frag.glsl
+    fn to_gl(self) -> i32 {
+        match self {
+            OverlayMode::None => 0,
+            OverlayMode::Slope => 1,
+            OverlayMode::Curvature => 2,
+            OverlayMode::HeightBands => 3,
+            OverlayMode::Contours => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Overlay {
+    pub mode: OverlayMode,
+    /// Slopes below this angle (degrees) are drawn green
+    pub slope_gentle: f32,
+    /// Slopes above this angle (degrees) are drawn red
+    pub slope_steep: f32,
+    pub curvature_scale: f32,
+    pub height_band: f32,
+    pub contour_interval: f32,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Overlay {
+            mode: OverlayMode::None,
+            slope_gentle: 15.0,
+            slope_steep: 35.0,
+            curvature_scale: 20.0,
+            height_band: 20.0,
+            contour_interval: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymmetryMode {
+    None,
+    /// Mirror across the plane x = 0
+    X,
+    /// Mirror across the plane z = 0
+    Z,
+    /// Mirror across both planes
+    XZ,
+    /// Repeat around the terrain center
+    Radial,
+}
+
+impl SymmetryMode {
+    pub const ALL: [SymmetryMode; 5] = [
+        SymmetryMode::None,
+        SymmetryMode::X,
+        SymmetryMode::Z,
+        SymmetryMode::XZ,
+        SymmetryMode::Radial,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SymmetryMode::None => "None",
+            SymmetryMode::X => "Mirror X",
+            SymmetryMode::Z => "Mirror Z",
+            SymmetryMode::XZ => "Mirror X and Z",
+            SymmetryMode::Radial => "Radial",
+        }
+    }
+
+    /// Must match the SYMMETRY_* constants in terrain.frag.glsl
+    fn to_gl(self) -> i32 {
+        match self {
+            SymmetryMode::None => 0,
+            SymmetryMode::X => 1,
+            SymmetryMode::Z => 2,
+            SymmetryMode::XZ => 3,
+            SymmetryMode::Radial => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symmetry {
+    pub mode: SymmetryMode,
+    /// Number of copies around the center in radial mode
+    pub radial_folds: u32,
+}
+
+impl Default for Symmetry {
+    fn default() -> Self {
+        Symmetry {
+            mode: SymmetryMode::None,
+            radial_folds: 4,
+        }
+    }
+}
+
+impl Symmetry {
+    /// Transforms around the terrain center which produce all symmetric copies
+    /// of a point, the identity included
+    pub fn transforms(&self) -> Vec<Mat2> {
+        let flip_x = Mat2::from_diagonal(Vec2::new(-1.0, 1.0));
+        let flip_z = Mat2::from_diagonal(Vec2::new(1.0, -1.0));
+        match self.mode {
+            SymmetryMode::None => vec![Mat2::IDENTITY],
+            SymmetryMode::X => vec![Mat2::IDENTITY, flip_x],
+            SymmetryMode::Z => vec![Mat2::IDENTITY, flip_z],
+            SymmetryMode::XZ => vec![Mat2::IDENTITY, flip_x, flip_z, flip_x * flip_z],
+            SymmetryMode::Radial => {
+                let folds = self.radial_folds.max(1);
+                (0..folds)
+                    .map(|i| Mat2::from_angle(std::f32::consts::TAU * i as f32 / folds as f32))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Protects regions of the terrain from the heightmap tools.
+/// The brush effect is scaled by one minus the mask value.
+pub struct SculptMask {
+    /// 1 where the terrain is fully protected
+    map: Heightmap,
+    /// Brushes paint the mask instead of the terrain
+    pub painting: bool,
+    /// Tint protected areas
+    pub show: bool,
+}
+
+impl SculptMask {
+    const FILE_NAME: &'static str = "sculpt_mask.r32";
+
+    /// Nothing protected
+    fn new(texture_size: usize) -> Self {
+        SculptMask {
+            map: Heightmap::flat(texture_size),
+            painting: false,
+            show: true,
+        }
+    }
+
+    /// Loads the mask saved in `dir` if there is one
+    fn load_or_new(dir: &Path, texture_size: usize) -> Result<Self> {
+        let path = dir.join(SculptMask::FILE_NAME);
+        let mask = SculptMask::new(texture_size);
+        if path.exists() {
+            let values = read_floats(&path)?;
+            if values.len() != texture_size * texture_size {
+                return Err(
+                    format!("Sculpt mask {:?} doesn't match the terrain size", path).into(),
+                );
+            }
+            mask.map.write_heights(&values);
+        }
+        Ok(mask)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        write_floats(&dir.join(SculptMask::FILE_NAME), &self.map.read_heights())
+    }
+
+    pub fn invert(&self) {
+        let values: Vec<f32> = self.map.read_heights().iter().map(|v| 1.0 - v).collect();
+        self.map.write_heights(&values);
+    }
+
+    /// Unprotect everything
+    pub fn clear(&self) {
+        self.map.fill(0.0);
+    }
+
+    /// Protect everything
+    pub fn fill(&self) {
+        self.map.fill(1.0);
+    }
+}
+
+/// A single brush application on the heightmap
+struct Dab {
+    /// Normalised [0:1]
+    position: Vec2,
+    brush_transform: Mat2,
+}
+
+/// Distribution of heights and slopes over the whole heightmap
+pub struct Histogram {
+    /// Bins over [0, max_height]
+    pub heights: Vec<u32>,
+    /// Bins over [0, 90] degrees
+    pub slopes: Vec<u32>,
+    pub max_height: f32,
+}
+
+/// Terrain dimensions and quality, adjustable at runtime
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct TerrainSettings {
+    pub max_height: f32,
+    pub num_patches: i32,
+    pub patch_size: f32,
+    pub tess_level: f32,
+    pub shadow_map_size: i32,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        TerrainSettings {
+            max_height: 200.0,
+            num_patches: 64,
+            patch_size: 16.0,
+            tess_level: 11.0,
+            shadow_map_size: 2048,
+        }
+    }
+}
+
 pub struct Terrain {
     pub aabb: AABB,
 
@@ -220,20 +553,33 @@ pub struct Terrain {
     pub tess_level: f32,
 
     texture: GLuint,
+    /// Composited from the layers
     heightmap: Heightmap,
+    pub layers: LayerStack,
+
+    pub material_rules: Vec<MaterialRule>,
+    splat_map: SplatMap,
+    /// The heights changed since the material weights were generated
+    materials_stale: bool,
+    /// Material weights are only regenerated once the brush is released
+    stroke_in_progress: bool,
 
     pub cursor: Vec2,
     pub brush: Brush,
+    brush_shader: Program,
 
-    shadow_map_fbo: GLuint,
-    shadow_map: GLuint,
-    shadow_map_size: i32,
+    pub overlay: Overlay,
+    pub symmetry: Symmetry,
+    pub sculpt_mask: SculptMask,
+
+    pub shadows: ShadowCascades,
     shadow_map_shader: Program,
+    /// Depth and normals for ambient occlusion
+    prepass_shader: Program,
 
     debug: TerrainDebug,
 
     // Main parameters
-    center: Vec2,
     max_height: f32,
     num_patches: i32,
     patch_size: f32,
@@ -241,26 +587,32 @@ pub struct Terrain {
 
 struct TerrainDebug {
     aabb_shader: Program,
-    normal_shader: Program,
 }
 
 impl Terrain {
-    pub fn new(center: Vec2, start_flat: bool, heightmap_path: &str) -> Result<Self> {
+    pub fn new(
+        gl: &mut GlState,
+        center: Vec2,
+        settings: &TerrainSettings,
+        start_flat: bool,
+        heightmap_path: &str,
+        material_rules: Vec<MaterialRule>,
+        layer_infos: &[LayerInfo],
+    ) -> Result<Self> {
         // TODO: support centers other than 0, 0
         // (currently hard-coded in terrain.vert.glsl)
         assert_eq!(center, Vec2::new(0.0, 0.0));
 
-        let max_height = 200.0;
-        let num_patches = 64;
-        let patch_size = 16.0;
+        let TerrainSettings {
+            max_height,
+            num_patches,
+            patch_size,
+            tess_level,
+            shadow_map_size,
+        } = *settings;
 
         let terrain_size = patch_size * num_patches as f32;
-        let aabb = {
-            let half_size = terrain_size / 2.0;
-            let min = Vec3::new(-half_size, 0.0, -half_size);
-            let max = Vec3::new(half_size, max_height, half_size);
-            AABB::new(min, max)
-        };
+        let aabb = Terrain::calculate_aabb(terrain_size, max_height);
 
         let mut vao: GLuint = 0;
         unsafe {
@@ -313,165 +665,250 @@ impl Terrain {
         };
 
         let cursor = vec2_infinity();
-        let heightmap = if start_flat {
-            Heightmap::flat(1024)?
+        let mut layers = if start_flat {
+            LayerStack::from_base(Heightmap::flat(1024))?
+        } else if layer_infos.is_empty() {
+            let base = Heightmap::from_image(heightmap_path)?;
+            LayerStack::from_base(base)?
+        } else {
+            LayerStack::load(&layers_dir(heightmap_path), layer_infos)?
+        };
+        // The composite is clamped to [0:1] even where the layers add up to more
+        let heightmap = Heightmap::flat(layers.texture_size());
+        layers.composite(gl, &heightmap)?;
+        let sculpt_mask = if start_flat {
+            SculptMask::new(layers.texture_size())
         } else {
-            Heightmap::from_image(heightmap_path)?
+            SculptMask::load_or_new(&layers_dir(heightmap_path), layers.texture_size())?
         };
+
         let brush = Brush::new("textures/brushes/mountain05.tga", 100.0);
+        let brush_shader = Program::new()
+            .vertex_shader(include_str!("shaders/editor/terrain/heightmap.vert"))?
+            .fragment_shader(include_str!("shaders/editor/terrain/heightmap.frag"))?
+            .link()?;
+        let splat_map = SplatMap::new(heightmap.texture_size)?;
+        splat_map.generate(
+            gl,
+            &material_rules,
+            heightmap.texture,
+            terrain_size,
+            max_height,
+        )?;
 
         let shader = Program::new()
             .vertex_shader(include_str!("shaders/editor/terrain/terrain.vert.glsl"))?
             .tess_control_shader(include_str!("shaders/editor/terrain/terrain.tc.glsl"))?
             .tess_evaluation_shader(include_str!("shaders/editor/terrain/terrain.te.glsl"))?
-            .fragment_shader(include_str!("shaders/editor/terrain/terrain.frag.glsl"))?
+            .fragment_shader(&with_includes(
+                include_str!("shaders/editor/terrain/terrain.frag.glsl"),
+                &[FOG_GLSL, SHADOW_BLOCK_GLSL, SHADOWS_GLSL],
+            ))?
             .link()?;
         shader.set_used();
         shader.set_vec2("terrain_center", &center)?;
-        shader.set_f32("terrain_max_height", max_height)?;
-        shader.set_f32("terrain_size", terrain_size)?;
-        shader.set_i32("num_patches", num_patches)?;
-        shader.set_f32("patch_size", patch_size)?;
-
-        // Shadow map
-        let mut shadow_map_fbo: GLuint = 0;
-        let mut shadow_map: GLuint = 0;
-        let shadow_map_size = 2048;
-        unsafe {
-            gl::CreateFramebuffers(1, &mut shadow_map_fbo);
-            gl::CreateTextures(gl::TEXTURE_2D, 1, &mut shadow_map);
-            gl::TextureParameteri(shadow_map, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
-            gl::TextureParameteri(shadow_map, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
-            gl::TextureParameteri(shadow_map, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
-            gl::TextureParameteri(shadow_map, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
-            gl::TextureStorage2D(
-                shadow_map,
-                1,
-                gl::DEPTH_COMPONENT16,
-                shadow_map_size,
-                shadow_map_size,
-            );
-            gl::NamedFramebufferTexture(shadow_map_fbo, gl::DEPTH_ATTACHMENT, shadow_map, 0);
-            gl::NamedFramebufferDrawBuffer(shadow_map_fbo, gl::NONE);
-            gl::NamedFramebufferReadBuffer(shadow_map_fbo, gl::NONE);
 
-            assert_eq!(
-                gl::CheckNamedFramebufferStatus(shadow_map_fbo, gl::FRAMEBUFFER),
-                gl::FRAMEBUFFER_COMPLETE,
-                "Shadow map framebuffer is incomplete",
-            );
-        }
+        let shadows = ShadowCascades::new(shadow_map_size);
         let shadow_map_shader = Program::new()
             .vertex_shader(include_str!("shaders/editor/terrain/terrain.vert.glsl"))?
             .tess_control_shader(include_str!("shaders/editor/terrain/terrain.tc.glsl"))?
-            .tess_evaluation_shader(include_str!("shaders/editor/terrain/shadow.te.glsl"))?
+            .tess_evaluation_shader(&with_includes(
+                include_str!("shaders/editor/terrain/shadow.te.glsl"),
+                &[SHADOW_BLOCK_GLSL],
+            ))?
             .fragment_shader(include_str!("shaders/editor/terrain/shadow.frag.glsl"))?
             .link()?;
         shadow_map_shader.set_used();
         shadow_map_shader.set_vec2("terrain_center", &center)?;
-        shadow_map_shader.set_f32("terrain_max_height", max_height)?;
-        shadow_map_shader.set_i32("num_patches", num_patches)?;
-        shadow_map_shader.set_f32("patch_size", patch_size)?;
+
+        let prepass_shader = Program::new()
+            .vertex_shader(include_str!("shaders/editor/terrain/terrain.vert.glsl"))?
+            .tess_control_shader(include_str!("shaders/editor/terrain/terrain.tc.glsl"))?
+            .tess_evaluation_shader(include_str!("shaders/editor/terrain/terrain.te.glsl"))?
+            .fragment_shader(include_str!("shaders/editor/terrain/prepass.frag.glsl"))?
+            .link()?;
+        prepass_shader.set_used();
+        prepass_shader.set_vec2("terrain_center", &center)?;
 
         let debug = {
             let aabb_shader = Program::new()
                 .vertex_shader(include_str!("shaders/debug/aabb.vert"))?
                 .fragment_shader(include_str!("shaders/debug/aabb.frag"))?
                 .link()?;
-            aabb_shader.set_used();
-            aabb_shader.set_vec3("aabb_min", &aabb.min)?;
-            aabb_shader.set_vec3("aabb_max", &aabb.max)?;
-
-            let normal_shader = Program::new()
-                .vertex_shader(include_str!("shaders/editor/terrain/terrain.vert.glsl"))?
-                .tess_control_shader(include_str!("shaders/editor/terrain/terrain.tc.glsl"))?
-                .tess_evaluation_shader(include_str!("shaders/editor/terrain/terrain.te.glsl"))?
-                .geometry_shader(include_str!("shaders/debug/terrain/normals.geometry.glsl"))?
-                .fragment_shader(include_str!("shaders/debug/terrain/normals.frag.glsl"))?
-                .link()?;
-            normal_shader.set_used();
 
-            TerrainDebug {
-                aabb_shader,
-                normal_shader,
-            }
+            TerrainDebug { aabb_shader }
         };
 
-        Ok(Terrain {
+        let terrain = Terrain {
             aabb,
 
             vao,
             shader,
-            tess_level: 11.0,
+            tess_level,
 
             texture,
             heightmap,
+            layers,
+
+            material_rules,
+            splat_map,
+            materials_stale: false,
+            stroke_in_progress: false,
 
             cursor,
             brush,
+            brush_shader,
 
-            shadow_map_fbo,
-            shadow_map,
-            shadow_map_size,
+            overlay: Overlay::default(),
+            symmetry: Symmetry::default(),
+            sculpt_mask,
+
+            shadows,
             shadow_map_shader,
+            prepass_shader,
 
             debug,
 
-            center,
             max_height,
             num_patches,
             patch_size,
-        })
+        };
+        terrain.set_dimension_uniforms()?;
+
+        Ok(terrain)
     }
 
-    // TODO: use a renderer
-    pub fn draw(&mut self, time: f32) -> Result<()> {
-        // Set common stuff for shadow pass / render pass
-        unsafe {
-            gl::PatchParameteri(gl::PATCH_VERTICES, 4);
-            gl::BindVertexArray(self.vao);
+    fn calculate_aabb(terrain_size: f32, max_height: f32) -> AABB {
+        let half_size = terrain_size / 2.0;
+        let min = Vec3::new(-half_size, 0.0, -half_size);
+        let max = Vec3::new(half_size, max_height, half_size);
+        AABB::new(min, max)
+    }
 
-            // Default texture
-            gl::ActiveTexture(unit_to_gl_const(0));
-            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+    /// Sends everything that depends on the terrain dimensions to the shaders
+    fn set_dimension_uniforms(&self) -> Result<()> {
+        let terrain_size = self.size();
 
-            // Heightmap
-            gl::ActiveTexture(unit_to_gl_const(1));
-            gl::BindTexture(gl::TEXTURE_2D, self.heightmap.texture);
+        self.shader.set_used();
+        self.shader.set_f32("terrain_max_height", self.max_height)?;
+        self.shader.set_f32("terrain_size", terrain_size)?;
+        self.shader.set_i32("num_patches", self.num_patches)?;
+        self.shader.set_f32("patch_size", self.patch_size)?;
 
-            // Brush
-            gl::ActiveTexture(unit_to_gl_const(2));
-            gl::BindTexture(gl::TEXTURE_2D, self.brush.texture);
+        self.shadow_map_shader.set_used();
+        self.shadow_map_shader
+            .set_f32("terrain_max_height", self.max_height)?;
+        self.shadow_map_shader
+            .set_i32("num_patches", self.num_patches)?;
+        self.shadow_map_shader
+            .set_f32("patch_size", self.patch_size)?;
+
+        self.prepass_shader.set_used();
+        self.prepass_shader
+            .set_f32("terrain_max_height", self.max_height)?;
+        self.prepass_shader.set_f32("terrain_size", terrain_size)?;
+        self.prepass_shader
+            .set_i32("num_patches", self.num_patches)?;
+        self.prepass_shader.set_f32("patch_size", self.patch_size)?;
+
+        self.debug.aabb_shader.set_used();
+        self.debug
+            .aabb_shader
+            .set_vec3("aabb_min", &self.aabb.min)?;
+        self.debug
+            .aabb_shader
+            .set_vec3("aabb_max", &self.aabb.max)?;
+
+        Ok(())
+    }
+
+    pub fn settings(&self) -> TerrainSettings {
+        TerrainSettings {
+            max_height: self.max_height,
+            num_patches: self.num_patches,
+            patch_size: self.patch_size,
+            tess_level: self.tess_level,
+            shadow_map_size: self.shadows.size,
+        }
+    }
+
+    /// Rebuilds whatever is affected by the changed settings
+    pub fn apply_settings(&mut self, gl: &mut GlState, settings: &TerrainSettings) -> Result<()> {
+        if settings.shadow_map_size != self.shadows.size {
+            self.shadows.resize(settings.shadow_map_size);
+        }
 
-            // Shadow map
-            gl::ActiveTexture(unit_to_gl_const(3));
-            gl::BindTexture(gl::TEXTURE_2D, self.shadow_map);
+        self.tess_level = settings.tess_level;
+
+        let dimensions_changed = settings.max_height != self.max_height
+            || settings.num_patches != self.num_patches
+            || settings.patch_size != self.patch_size;
+        if dimensions_changed {
+            self.max_height = settings.max_height;
+            self.num_patches = settings.num_patches;
+            self.patch_size = settings.patch_size;
+            self.aabb =
+                Terrain::calculate_aabb(self.patch_size * self.num_patches as f32, self.max_height);
+            self.hide_cursor();
+            self.set_dimension_uniforms()?;
+            self.update_splat_map(gl)?;
         }
 
-        // Draw into shadow map
+        Ok(())
+    }
+
+    /// Casts shadows and is drawn opaque
+    pub fn submit(&self, draw_list: &mut DrawList) {
+        for pass in [Pass::Shadow, Pass::Prepass, Pass::Opaque] {
+            draw_list.submit(pass, DrawItem::Terrain);
+        }
+    }
+
+    /// Draws depth from the sun into the bound shadow cascade
+    pub fn draw_shadows(&self, cascade: usize) -> Result<()> {
+        self.bind_for_drawing();
         self.shadow_map_shader.set_used();
         self.shadow_map_shader
             .set_f32("tess_level", self.tess_level)?;
+        self.shadow_map_shader.set_i32("cascade", cascade as i32)?;
         unsafe {
-            gl::BindFramebuffer(gl::FRAMEBUFFER, self.shadow_map_fbo);
-            gl::Viewport(0, 0, self.shadow_map_size, self.shadow_map_size);
-            gl::Clear(gl::DEPTH_BUFFER_BIT);
-
-            gl::DrawArraysInstanced(gl::PATCHES, 0, 4, 64 * 64);
+            gl::DrawArraysInstanced(gl::PATCHES, 0, 4, self.num_patches * self.num_patches);
+        }
+        Ok(())
+    }
 
-            gl::Viewport(0, 0, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32);
-            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+    /// Draws depth and view space normals into the bound framebuffer
+    pub fn draw_prepass(&self) -> Result<()> {
+        self.bind_for_drawing();
+        self.prepass_shader.set_used();
+        self.prepass_shader.set_f32("tess_level", self.tess_level)?;
+        unsafe {
+            gl::DrawArraysInstanced(gl::PATCHES, 0, 4, self.num_patches * self.num_patches);
         }
+        Ok(())
+    }
+
+    /// Draws the terrain lit by the shadow cascades
+    pub fn draw(&mut self) -> Result<()> {
+        self.bind_for_drawing();
 
         // Draw the scene
         self.shader.set_used();
         self.shader.set_vec2("cursor", &self.cursor)?;
         self.shader.set_f32("brush_size", self.brush.size)?;
         self.shader.set_f32("tess_level", self.tess_level)?;
+        self.set_overlay_uniforms()?;
+        self.shader
+            .set_i32("symmetry_mode", self.symmetry.mode.to_gl())?;
+        self.shader
+            .set_i32("symmetry_folds", self.symmetry.radial_folds as i32)?;
+        self.shader
+            .set_i32("show_sculpt_mask", self.sculpt_mask.show as i32)?;
+        self.set_material_uniforms()?;
 
         unsafe {
             // gl::PolygonMode(gl::FRONT_AND_BACK, gl::LINE);
-            gl::DrawArraysInstanced(gl::PATCHES, 0, 4, 64 * 64);
+            gl::DrawArraysInstanced(gl::PATCHES, 0, 4, self.num_patches * self.num_patches);
             // gl::PolygonMode(gl::FRONT_AND_BACK, gl::FILL);
         }
 
@@ -480,22 +917,122 @@ impl Terrain {
         //     // Draw AABB
         //     let debug = &mut self.debug;
         //     debug.aabb_shader.set_used();
-        //     debug.aabb_shader.set_f32("time", time)?;
         //     unsafe {
         //         gl::DrawArrays(gl::LINE_STRIP, 0, 16);
         //     }
-
-        //     // Draw normals
-        //     debug.normal_shader.set_used();
-        //     debug.normal_shader.set_f32("tess_level", self.tess_level)?;
-        //     unsafe {
-        //         gl::DrawArraysInstanced(gl::PATCHES, 0, 4, 64 * 64);
-        //     }
         // }
 
         Ok(())
     }
 
+    /// Binds the vertex array and textures used by both the shadow and the render pass
+    fn bind_for_drawing(&self) {
+        unsafe {
+            gl::PatchParameteri(gl::PATCH_VERTICES, 4);
+            gl::BindVertexArray(self.vao);
+
+            // Default texture
+            gl::ActiveTexture(unit_to_gl_const(0));
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+
+            // Heightmap
+            gl::ActiveTexture(unit_to_gl_const(1));
+            gl::BindTexture(gl::TEXTURE_2D, self.heightmap.texture);
+
+            // Brush
+            gl::ActiveTexture(unit_to_gl_const(2));
+            gl::BindTexture(gl::TEXTURE_2D, self.brush.texture);
+
+            // Shadow cascades
+            gl::ActiveTexture(unit_to_gl_const(SHADOW_MAP_UNIT));
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.shadows.texture);
+
+            // Material weights
+            gl::ActiveTexture(unit_to_gl_const(4));
+            gl::BindTexture(gl::TEXTURE_2D, self.splat_map.texture);
+
+            // Protected areas
+            gl::ActiveTexture(unit_to_gl_const(5));
+            gl::BindTexture(gl::TEXTURE_2D, self.sculpt_mask.map.texture);
+        }
+    }
+
+    fn set_overlay_uniforms(&self) -> Result<()> {
+        let overlay = &self.overlay;
+        self.shader.set_i32("overlay_mode", overlay.mode.to_gl())?;
+        if overlay.mode == OverlayMode::None {
+            return Ok(());
+        }
+        self.shader.set_vec2(
+            "slope_thresholds",
+            &Vec2::new(overlay.slope_gentle, overlay.slope_steep),
+        )?;
+        self.shader
+            .set_f32("curvature_scale", overlay.curvature_scale)?;
+        self.shader.set_f32("height_band", overlay.height_band)?;
+        self.shader
+            .set_f32("contour_interval", overlay.contour_interval)?;
+        Ok(())
+    }
+
+    fn set_material_uniforms(&self) -> Result<()> {
+        let num_materials = self.material_rules.len().min(MAX_MATERIAL_RULES);
+        self.shader.set_i32("num_materials", num_materials as i32)?;
+        for (i, rule) in self.material_rules.iter().take(num_materials).enumerate() {
+            self.shader
+                .set_vec3(&format!("material_colors[{}]", i), &rule.color)?;
+        }
+        Ok(())
+    }
+
+    /// Composites the changed layers, the material weights follow when no stroke is in progress.
+    /// Called before the frame is drawn.
+    pub fn update_heightmap(&mut self, gl: &mut GlState) -> Result<()> {
+        if self.layers.composite(gl, &self.heightmap)? {
+            self.materials_stale = true;
+        }
+        if self.materials_stale && !self.stroke_in_progress {
+            self.update_splat_map(gl)?;
+            self.materials_stale = false;
+        }
+        Ok(())
+    }
+
+    /// Re-evaluates the material rules, e.g. after they've been edited or the terrain reshaped
+    pub fn update_splat_map(&self, gl: &mut GlState) -> Result<()> {
+        self.splat_map.generate(
+            gl,
+            &self.material_rules,
+            self.heightmap.texture,
+            self.size(),
+            self.max_height,
+        )
+    }
+
+    /// Reads the heightmap back from the GPU and bins its heights and slopes
+    pub fn compute_histogram(&self, num_bins: usize) -> Histogram {
+        let heightfield =
+            Heightfield::new(self.heightmap.texture_size, self.heightmap.read_heights());
+
+        let mut histogram = Histogram {
+            heights: vec![0; num_bins],
+            slopes: vec![0; num_bins],
+            max_height: self.max_height,
+        };
+        let bin = |t: f32| ((t * num_bins as f32) as usize).min(num_bins - 1);
+
+        for y in 0..heightfield.size {
+            for x in 0..heightfield.size {
+                let height = heightfield.at(x as isize, y as isize);
+                histogram.heights[bin(height)] += 1;
+                let slope = heightfield.slope(x, y, self.size(), self.max_height);
+                histogram.slopes[bin(slope / 90.0)] += 1;
+            }
+        }
+
+        histogram
+    }
+
     pub fn get_heightmap_pixels(&self) -> (Vec<u8>, usize) {
         let buffer_size = self.heightmap.texture_size * self.heightmap.texture_size * 2;
         let mut pixels = Vec::<u8>::with_capacity(buffer_size);
@@ -513,15 +1050,95 @@ impl Terrain {
         (pixels, self.heightmap.texture_size)
     }
 
+    /// CPU copy of the current heights, for walking on
+    pub fn ground(&self) -> Ground {
+        let heightfield =
+            Heightfield::new(self.heightmap.texture_size, self.heightmap.read_heights());
+        Ground::new(
+            heightfield,
+            self.aabb.min.xz(),
+            self.size(),
+            self.max_height,
+        )
+    }
+
     pub fn size(&self) -> f32 {
         self.aabb.max.x - self.aabb.min.x
     }
 
-    pub fn shape_terrain(&mut self, delta_time: f32, raise: bool) {
+    /// Where the brush should be applied for the current cursor, including symmetric copies
+    fn brush_dabs(&self) -> Vec<Dab> {
+        let terrain_size = self.size();
+        let center = self.aabb.min.xz() + Vec2::splat(terrain_size / 2.0);
+        let offset = self.cursor - center;
+
+        let mut dabs: Vec<Dab> = vec![];
+        for transform in self.symmetry.transforms() {
+            let position = center + transform * offset;
+            let position = (position - self.aabb.min.xz()) / terrain_size;
+            if !(0.0..=1.0).contains(&position.x) || !(0.0..=1.0).contains(&position.y) {
+                continue;
+            }
+
+            // Don't apply the brush twice when the cursor is on a mirror axis
+            let min_distance = 0.01 * self.brush.size / terrain_size;
+            if dabs
+                .iter()
+                .any(|dab| dab.position.distance(position) < min_distance)
+            {
+                continue;
+            }
+
+            dabs.push(Dab {
+                position,
+                // Orthogonal, so the transpose maps the copy back onto the brush footprint
+                brush_transform: transform.transpose(),
+            });
+        }
+        dabs
+    }
+
+    /// When painting the sculpt mask, raising protects and lowering unprotects
+    pub fn shape_terrain(&mut self, gl: &mut GlState, delta_time: f32, raise: bool) {
         let terrain_size = self.size();
-        let cursor = (self.cursor - self.aabb.min.xz()) / terrain_size;
-        self.heightmap
-            .draw_on_heightmap(cursor, &self.brush, terrain_size, delta_time, raise);
+        let painting_mask = self.sculpt_mask.painting;
+        let target = if painting_mask {
+            &self.sculpt_mask.map
+        } else {
+            self.layers.active_target()
+        };
+
+        // The mask doesn't protect itself
+        self.brush_shader.set_used();
+        self.brush_shader
+            .set_i32("use_sculpt_mask", !painting_mask as i32)
+            .unwrap();
+        unsafe {
+            gl::ActiveTexture(unit_to_gl_const(1));
+            gl::BindTexture(gl::TEXTURE_2D, self.sculpt_mask.map.texture);
+        }
+
+        for dab in self.brush_dabs() {
+            target.draw_on_heightmap(
+                gl,
+                &self.brush_shader,
+                dab.position,
+                &dab.brush_transform,
+                &self.brush,
+                terrain_size,
+                delta_time,
+                raise,
+            );
+        }
+        if !painting_mask {
+            self.layers.mark_dirty();
+            self.stroke_in_progress = true;
+        }
+    }
+
+    /// Called while the brush isn't pressed
+    pub fn end_stroke(&mut self) {
+        self.stroke_in_progress = false;
     }
 
     /// Currently only intersects with the bottom plane of the AABB